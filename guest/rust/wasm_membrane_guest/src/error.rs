@@ -38,4 +38,13 @@ impl From<FromUtf8Error> for Error {
             error: format!("{:?}", e)
         }
     }
+}
+
+impl From<serde_json::Error> for Error {
+
+    fn from(e:serde_json::Error) -> Self {
+        Error {
+            error: format!("{:?}", e)
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+#[cfg(not(target_os = "wasi"))]
+use wasm_bindgen::prelude::*;
+
+use crate::error::Error;
+use crate::membrane::{membrane_consume_response, membrane_write_buffer, membrane_write_str};
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+lazy_static! {
+  static ref TASKS: Mutex<HashMap<usize, Task>> = Mutex::new(HashMap::new());
+  static ref READY: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+  static ref TASK_INDEX: AtomicUsize = AtomicUsize::new(0);
+  static ref RUNNING: AtomicBool = AtomicBool::new(false);
+  /// results of host calls that completed, waiting to be picked up by their future
+  static ref COMPLETIONS: Mutex<HashMap<i32, Result<Vec<u8>, Error>>> = Mutex::new(HashMap::new());
+  /// futures waiting on a host call
+  static ref WAITING: Mutex<HashMap<i32, Waker>> = Mutex::new(HashMap::new());
+}
+
+extern "C"
+{
+    pub fn membrane_host_call_async(name_buffer: i32, payload_buffer: i32) -> i32;
+}
+
+/// called by the host's WasmMembrane::complete with a status byte (0 result, 1 error message)
+/// followed by the result or message. wakes the future waiting on handle and runs ready tasks
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_complete(handle: i32, result_buffer: i32)
+{
+    COMPLETIONS.lock().unwrap().insert(handle, membrane_consume_response(result_buffer));
+
+    let waker = WAITING.lock().unwrap().remove(&handle);
+    if let Some(waker) = waker
+    {
+        waker.wake();
+    }
+    membrane_run_ready();
+}
+
+/// run every task that is ready, returns how many tasks are still waiting
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_poll() -> i32
+{
+    membrane_run_ready();
+    TASKS.lock().unwrap().len() as i32
+}
+
+/// run future to completion in the background. it makes progress whenever the host
+/// completes a call it waits on or calls membrane_guest_poll
+pub fn membrane_spawn<F>(future: F)
+    where F: Future<Output = ()> + Send + 'static
+{
+    let id = TASK_INDEX.fetch_add(1, Ordering::Relaxed);
+    TASKS.lock().unwrap().insert(id, Box::pin(future));
+    READY.lock().unwrap().push_back(id);
+    membrane_run_ready();
+}
+
+/// call a function the host registered under name without blocking the guest. the host
+/// answers the call after the current export returned, so many calls can be in flight at once
+pub fn membrane_call_host_async(name: &str, payload: Vec<u8>) -> HostCall
+{
+    let name_buffer = membrane_write_str(name);
+    let payload_buffer = membrane_write_buffer(payload);
+    HostCall {
+        handle: unsafe { membrane_host_call_async(name_buffer, payload_buffer) },
+    }
+}
+
+/// the result of a membrane_call_host_async
+pub struct HostCall {
+    handle: i32,
+}
+
+impl Future for HostCall {
+    type Output = Result<Vec<u8>, Error>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output>
+    {
+        if self.handle < 0
+        {
+            return Poll::Ready(Err("the host refused the call".into()));
+        }
+
+        match COMPLETIONS.lock().unwrap().remove(&self.handle)
+        {
+            Some(result) => Poll::Ready(result),
+            None => {
+                WAITING.lock().unwrap().insert(self.handle, context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// poll ready tasks until none are left. tasks are taken out of TASKS while they are polled
+/// so that they may spawn other tasks
+pub(crate) fn membrane_run_ready()
+{
+    // a task that spawns or a completion during a poll only queues, the outer loop picks it up
+    if RUNNING.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+
+    loop {
+        let id = match READY.lock().unwrap().pop_front()
+        {
+            Some(id) => id,
+            None => break
+        };
+        let task = TASKS.lock().unwrap().remove(&id);
+        if let Some(mut task) = task
+        {
+            let waker = task_waker(id);
+            let mut context = Context::from_waker(&waker);
+            if task.as_mut().poll(&mut context).is_pending()
+            {
+                TASKS.lock().unwrap().insert(id, task);
+            }
+        }
+    }
+
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+// the waker of a task is just its id, waking queues the id on READY
+
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(task_waker_clone, task_waker_wake, task_waker_wake, task_waker_drop);
+
+fn task_waker(id: usize) -> Waker
+{
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &TASK_WAKER_VTABLE)) }
+}
+
+unsafe fn task_waker_clone(data: *const ()) -> RawWaker
+{
+    RawWaker::new(data, &TASK_WAKER_VTABLE)
+}
+
+unsafe fn task_waker_wake(data: *const ())
+{
+    READY.lock().unwrap().push_back(data as usize);
+}
+
+unsafe fn task_waker_drop(_data: *const ())
+{
+}
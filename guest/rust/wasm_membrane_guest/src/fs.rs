@@ -0,0 +1,187 @@
+use std::io;
+
+use crate::error::Error;
+use crate::membrane::{membrane_consume_response, membrane_write_buffer, membrane_write_str, HostResource};
+
+extern "C"
+{
+    pub fn membrane_host_fs_open(path_buffer: i32, flags: i32) -> i32;
+    pub fn membrane_host_fs_read(handle: i32, len: i32) -> i32;
+    pub fn membrane_host_fs_write(handle: i32, data_buffer: i32) -> i32;
+    pub fn membrane_host_fs_list(path_buffer: i32) -> i32;
+    pub fn membrane_host_fs_stat(path_buffer: i32) -> i32;
+    pub fn membrane_host_fs_remove(path_buffer: i32) -> i32;
+}
+
+static OPEN_READ: i32 = 1;
+static OPEN_WRITE: i32 = 2;
+static OPEN_CREATE: i32 = 4;
+static OPEN_TRUNCATE: i32 = 8;
+static OPEN_APPEND: i32 = 16;
+
+/// a file of the filesystem the host granted this membrane. paths are relative to its root.
+/// every call fails when the host did not grant the filesystem capability
+pub struct File {
+    resource: HostResource,
+}
+
+impl File {
+    pub fn open(path: &str) -> Result<File, Error>
+    {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// open for writing, creating the file or truncating it
+    pub fn create(path: &str) -> Result<File, Error>
+    {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+}
+
+impl io::Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = membrane_consume_response(unsafe { membrane_host_fs_read(self.resource.handle() as i32, buf.len() as i32) }).map_err(io_error)?;
+        buf[..data.len()].copy_from_slice(data.as_slice());
+        Ok(data.len())
+    }
+}
+
+impl io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data_buffer = membrane_write_buffer(buf.to_vec());
+        let written = membrane_consume_response(unsafe { membrane_host_fs_write(self.resource.handle() as i32, data_buffer) }).map_err(io_error)?;
+        if written.len() != 4
+        {
+            return Err(io_error("malformed response to write".into()));
+        }
+        let mut count = [0u8; 4];
+        count.copy_from_slice(written.as_slice());
+        Ok(u32::from_le_bytes(count) as usize)
+    }
+
+    /// writes reach the host right away
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenOptions {
+    flags: i32,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self
+    {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self
+    {
+        self.set(OPEN_READ, read)
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self
+    {
+        self.set(OPEN_WRITE, write)
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self
+    {
+        self.set(OPEN_CREATE, create)
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self
+    {
+        self.set(OPEN_TRUNCATE, truncate)
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self
+    {
+        self.set(OPEN_APPEND, append)
+    }
+
+    pub fn open(&self, path: &str) -> Result<File, Error>
+    {
+        let path_buffer = membrane_write_str(path);
+        let handle = membrane_consume_response(unsafe { membrane_host_fs_open(path_buffer, self.flags) })?;
+        if handle.len() != 4
+        {
+            return Err("malformed response to open".into());
+        }
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(handle.as_slice());
+        Ok(File {
+            resource: HostResource::from_handle(u32::from_le_bytes(bytes)),
+        })
+    }
+
+    fn set(&mut self, flag: i32, on: bool) -> &mut Self
+    {
+        if on
+        {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, Error>
+{
+    let path_buffer = membrane_write_str(path);
+    let metadata: serde_json::Value = serde_json::from_slice(membrane_consume_response(unsafe { membrane_host_fs_stat(path_buffer) })?.as_slice())?;
+    Ok(Metadata {
+        is_dir: metadata["is_dir"].as_bool().unwrap_or(false),
+        len: metadata["len"].as_u64().unwrap_or(0),
+    })
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error>
+{
+    let path_buffer = membrane_write_str(path);
+    let entries: serde_json::Value = serde_json::from_slice(membrane_consume_response(unsafe { membrane_host_fs_list(path_buffer) })?.as_slice())?;
+    let entries = entries.as_array().ok_or("malformed response to list")?;
+    Ok(entries.iter().map(|entry| DirEntry {
+        name: entry["name"].as_str().unwrap_or("").to_string(),
+        is_dir: entry["is_dir"].as_bool().unwrap_or(false),
+    }).collect())
+}
+
+/// remove a file or an empty directory
+pub fn remove(path: &str) -> Result<(), Error>
+{
+    let path_buffer = membrane_write_str(path);
+    membrane_consume_response(unsafe { membrane_host_fs_remove(path_buffer) })?;
+    Ok(())
+}
+
+pub fn read_to_string(path: &str) -> Result<String, Error>
+{
+    let mut contents = String::new();
+    io::Read::read_to_string(&mut File::open(path)?, &mut contents).map_err(|e| Error::from(e.to_string()))?;
+    Ok(contents)
+}
+
+pub fn write(path: &str, data: &[u8]) -> Result<(), Error>
+{
+    io::Write::write_all(&mut File::create(path)?, data).map_err(|e| Error::from(e.to_string()))
+}
+
+fn io_error(error: Error) -> io::Error
+{
+    io::Error::new(io::ErrorKind::Other, error.error)
+}
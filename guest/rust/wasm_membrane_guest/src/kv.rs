@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::membrane::{membrane_consume_response, membrane_write_buffer};
+
+extern "C"
+{
+    pub fn membrane_host_kv_get(key_buffer: i32) -> i32;
+    pub fn membrane_host_kv_put(key_buffer: i32, value_buffer: i32) -> i32;
+    pub fn membrane_host_kv_delete(key_buffer: i32) -> i32;
+    pub fn membrane_host_kv_list(prefix_buffer: i32) -> i32;
+    pub fn membrane_host_kv_cas(key_buffer: i32, expected_buffer: i32, new_buffer: i32, flags: i32) -> i32;
+}
+
+static CAS_EXPECTED: i32 = 1;
+static CAS_NEW: i32 = 2;
+
+// keys and values live in the namespace the host granted this membrane. every call fails
+// when the host did not grant the key-value capability
+
+pub fn get(key: &[u8]) -> Result<Option<Vec<u8>>, Error>
+{
+    let key_buffer = membrane_write_buffer(key.to_vec());
+    let mut response = membrane_consume_response(unsafe { membrane_host_kv_get(key_buffer) })?;
+    match response.first()
+    {
+        Some(1) => Ok(Some(response.split_off(1))),
+        Some(_) => Ok(None),
+        None => Err("malformed response to get".into())
+    }
+}
+
+/// fails when the value would take the namespace over its quota
+pub fn put(key: &[u8], value: &[u8]) -> Result<(), Error>
+{
+    let key_buffer = membrane_write_buffer(key.to_vec());
+    let value_buffer = membrane_write_buffer(value.to_vec());
+    membrane_consume_response(unsafe { membrane_host_kv_put(key_buffer, value_buffer) })?;
+    Ok(())
+}
+
+/// returns whether the key existed
+pub fn delete(key: &[u8]) -> Result<bool, Error>
+{
+    let key_buffer = membrane_write_buffer(key.to_vec());
+    flag(membrane_consume_response(unsafe { membrane_host_kv_delete(key_buffer) })?)
+}
+
+/// keys starting with prefix, in ascending order
+pub fn list_prefix(prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>
+{
+    let prefix_buffer = membrane_write_buffer(prefix.to_vec());
+    let data = membrane_consume_response(unsafe { membrane_host_kv_list(prefix_buffer) })?;
+    let mut keys = vec![];
+    let mut offset = 0;
+    while offset < data.len()
+    {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(data.get(offset..offset + 4).ok_or("malformed response to list")?);
+        offset += 4;
+        let len = u32::from_le_bytes(len) as usize;
+        keys.push(data.get(offset..offset + len).ok_or("malformed response to list")?.to_vec());
+        offset += len;
+    }
+    Ok(keys)
+}
+
+/// set key to new (deleting it for None) only if its current value is expected (absent for None).
+/// returns whether the swap happened
+pub fn compare_and_swap(key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, Error>
+{
+    let mut flags = 0;
+    if expected.is_some()
+    {
+        flags |= CAS_EXPECTED;
+    }
+    if new.is_some()
+    {
+        flags |= CAS_NEW;
+    }
+    let key_buffer = membrane_write_buffer(key.to_vec());
+    let expected_buffer = membrane_write_buffer(expected.unwrap_or(&[]).to_vec());
+    let new_buffer = membrane_write_buffer(new.unwrap_or(&[]).to_vec());
+    flag(membrane_consume_response(unsafe { membrane_host_kv_cas(key_buffer, expected_buffer, new_buffer, flags) })?)
+}
+
+fn flag(response: Vec<u8>) -> Result<bool, Error>
+{
+    match response.as_slice()
+    {
+        [flag] => Ok(*flag != 0),
+        _ => Err("malformed response from the host".into())
+    }
+}
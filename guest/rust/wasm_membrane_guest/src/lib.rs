@@ -1,3 +1,6 @@
+// built for wasm32-wasi the exports are plain #[no_mangle] functions, so the module runs
+// without the wasm-bindgen cli in a host that uses WasmMembrane::new_with_wasi
+#[cfg(not(target_os = "wasi"))]
 #[macro_use]
 extern crate wasm_bindgen;
 
@@ -5,4 +8,8 @@ extern crate wasm_bindgen;
 extern crate lazy_static;
 
 pub mod membrane;
-mod error;
\ No newline at end of file
+pub mod error;
+pub mod executor;
+pub mod fs;
+pub mod kv;
+pub mod time;
\ No newline at end of file
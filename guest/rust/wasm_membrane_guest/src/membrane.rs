@@ -1,7 +1,8 @@
 use std::sync::atomic::{Ordering,AtomicI32};
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 
+#[cfg(not(target_os = "wasi"))]
 use wasm_bindgen::prelude::*;
 
 use crate::error::Error;
@@ -9,23 +10,34 @@ use crate::error::Error;
 lazy_static! {
   pub static ref BUFFERS: RwLock<HashMap<i32,Vec<u8>>> = RwLock::new(HashMap::new());
   pub static ref BUFFER_INDEX: AtomicI32 = AtomicI32::new(0);
+  pub static ref CONFIG: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+  pub static ref EVENT_HANDLERS: RwLock<HashMap<String,Vec<EventHandler>>> = RwLock::new(HashMap::new());
 }
 
 pub static VERSION: i32 = 1;
 
+pub type EventHandler = Arc<dyn Fn(&[u8]) -> Result<(), Error> + Send + Sync>;
+
 extern "C"
 {
     pub fn membrane_host_log(buffer: i32);
     pub fn membrane_host_panic(buffer: i32);
+    pub fn membrane_host_send(target_buffer: i32, payload_buffer: i32) -> i32;
+    pub fn membrane_host_request(target_buffer: i32, payload_buffer: i32) -> i32;
+    pub fn membrane_host_reply(correlation_id: i64, payload_buffer: i32) -> i32;
+    pub fn membrane_host_call(name_buffer: i32, payload_buffer: i32) -> i32;
+    pub fn membrane_host_release_resource(handle: i32) -> i32;
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_version() -> i32
 {
   VERSION
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_alloc_buffer(len: i32) -> i32
 {
     let buffer_id = BUFFER_INDEX.fetch_add(1, Ordering::Relaxed);
@@ -38,20 +50,57 @@ pub fn membrane_guest_alloc_buffer(len: i32) -> i32
     buffer_id
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_dealloc_buffer(id: i32)
 {
     let mut buffers = BUFFERS.write().unwrap();
     buffers.remove(&id);
 }
 
-#[wasm_bindgen]
+/// called by the host's emit(topic, payload). runs every handler subscribed to the topic
+/// and returns -1 or the id of a buffer holding the first handler error
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_on_event(topic_buffer: i32, payload_buffer: i32) -> i32
+{
+    let topic = match membrane_consume_string(topic_buffer)
+    {
+        Ok(topic) => topic,
+        Err(error) => return membrane_write_string(format!("could not read event topic: {}", error.error))
+    };
+    let payload = match membrane_consume_buffer(payload_buffer)
+    {
+        Ok(payload) => payload,
+        Err(error) => return membrane_write_string(format!("could not read event payload: {}", error.error))
+    };
+
+    // cloned so handlers may subscribe or unsubscribe while they run
+    let handlers = match EVENT_HANDLERS.read().unwrap().get(&topic)
+    {
+        Some(handlers) => handlers.clone(),
+        None => return -1
+    };
+
+    for handler in handlers
+    {
+        if let Err(error) = handler(payload.as_slice())
+        {
+            return membrane_write_string(error.error);
+        }
+    }
+    -1
+}
+
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_test(test_buffer_message: i32)
 {
     log(membrane_consume_string(test_buffer_message).unwrap().as_str());
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_get_buffer_ptr(id: i32) ->*const u8
 {
     let buffer_info = BUFFERS.read();
@@ -60,7 +109,8 @@ pub fn membrane_guest_get_buffer_ptr(id: i32) ->*const u8
     return buffer.as_ptr()
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_get_buffer_len(id: i32) ->i32
 {
     let buffer_info = BUFFERS.read();
@@ -69,7 +119,8 @@ pub fn membrane_guest_get_buffer_len(id: i32) ->i32
     buffer.len() as _
 }
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_test_log(log_message_buffer: i32)
 {
     let log_message = membrane_consume_string(log_message_buffer).unwrap();
@@ -151,3 +202,265 @@ pub fn membrane_write_string(mut string: String) -> i32 {
 }
 
 
+//////////////////////////////////////////////
+// Init config
+//////////////////////////////////////////////
+
+/// call this from your membrane_guest_init_with_config export:
+///
+/// #[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+/// #[cfg_attr(target_os = "wasi", no_mangle)]
+/// pub fn membrane_guest_init_with_config(config_buffer: i32) -> i32
+/// {
+///     membrane_init_with_config(config_buffer, || {
+///         let settings = membrane_config_map()?;
+///         Ok(())
+///     })
+/// }
+///
+/// the config sent by the host is stored for membrane_config() before init runs.
+/// returning an Err reports the message to the host which then fails its init
+pub fn membrane_init_with_config<F>(config_buffer: i32, init: F) -> i32
+    where F: FnOnce() -> Result<(), Error>
+{
+    if config_buffer >= 0
+    {
+        match membrane_consume_buffer(config_buffer)
+        {
+            Ok(config) => *CONFIG.write().unwrap() = Some(config),
+            Err(error) => return membrane_write_string(format!("could not read init config: {}", error.error))
+        }
+    }
+
+    match init()
+    {
+        Ok(_) => -1,
+        Err(error) => membrane_write_string(error.error)
+    }
+}
+
+/// the raw config the host passed to init, if any
+pub fn membrane_config() -> Option<Vec<u8>>
+{
+    CONFIG.read().unwrap().clone()
+}
+
+/// the config parsed as json, hosts send json when using init_with_json
+pub fn membrane_config_json() -> Result<Option<serde_json::Value>, Error>
+{
+    match membrane_config()
+    {
+        Some(config) => Ok(Some(serde_json::from_slice(config.as_slice())?)),
+        None => Ok(None)
+    }
+}
+
+/// the config as a flat key/value map. non string values are returned as json text
+pub fn membrane_config_map() -> Result<HashMap<String,String>, Error>
+{
+    let mut map = HashMap::new();
+    match membrane_config_json()?
+    {
+        Some(serde_json::Value::Object(object)) => {
+            for (key, value) in object
+            {
+                let value = match value
+                {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string()
+                };
+                map.insert(key, value);
+            }
+        }
+        Some(_) => return Err("init config is not a json object".into()),
+        None => {}
+    }
+    Ok(map)
+}
+
+
+//////////////////////////////////////////////
+// Host functions
+//////////////////////////////////////////////
+
+/// call a function the host registered under name and return its result. the guest is
+/// blocked until the host function finished, including async host functions
+pub fn membrane_call_host(name: &str, payload: Vec<u8>) -> Result<Vec<u8>, Error>
+{
+    let name_buffer = membrane_write_str(name);
+    let payload_buffer = membrane_write_buffer(payload);
+    membrane_consume_response(unsafe { membrane_host_call(name_buffer, payload_buffer) })
+}
+
+/// read a response written by the host: a status byte (0 result, 1 error message)
+/// followed by the result or the message
+pub fn membrane_consume_response(buffer: i32) -> Result<Vec<u8>, Error>
+{
+    if buffer < 0
+    {
+        return Err("the host could not answer".into());
+    }
+    let response = membrane_consume_buffer(buffer)?;
+    match response.split_first()
+    {
+        Some((0, result)) => Ok(result.to_vec()),
+        Some((_, message)) => Err(String::from_utf8(message.to_vec())?.into()),
+        None => Err("empty response from host".into())
+    }
+}
+
+
+//////////////////////////////////////////////
+// Host resources
+//////////////////////////////////////////////
+
+/// a handle to a host object such as an open file, usually returned by a host function.
+/// the host object is released when this is dropped
+pub struct HostResource {
+    handle: u32,
+}
+
+impl HostResource {
+    /// take ownership of a handle the host handed out
+    pub fn from_handle(handle: u32) -> Self
+    {
+        HostResource { handle: handle }
+    }
+
+    pub fn handle(&self) -> u32
+    {
+        self.handle
+    }
+
+    /// release the host object now and report whether the host knew the handle
+    pub fn release(self) -> Result<(), Error>
+    {
+        let handle = self.handle;
+        std::mem::forget(self);
+        membrane_release_resource(handle)
+    }
+}
+
+impl Drop for HostResource {
+    fn drop(&mut self) {
+        membrane_release_resource(self.handle).unwrap_or(());
+    }
+}
+
+pub fn membrane_release_resource(handle: u32) -> Result<(), Error>
+{
+    let result_buffer = unsafe { membrane_host_release_resource(handle as i32) };
+    if result_buffer >= 0
+    {
+        return Err(membrane_consume_string(result_buffer)?.into());
+    }
+    Ok(())
+}
+
+
+//////////////////////////////////////////////
+// Events
+//////////////////////////////////////////////
+
+/// run handler for every event the host emits on topic. a topic may have several
+/// handlers, they run in the order they subscribed
+pub fn membrane_subscribe<F>(topic: &str, handler: F)
+    where F: Fn(&[u8]) -> Result<(), Error> + Send + Sync + 'static
+{
+    EVENT_HANDLERS.write().unwrap().entry(topic.to_string()).or_insert_with(Vec::new).push(Arc::new(handler));
+}
+
+/// remove every handler of topic
+pub fn membrane_unsubscribe(topic: &str)
+{
+    EVENT_HANDLERS.write().unwrap().remove(topic);
+}
+
+
+//////////////////////////////////////////////
+// Messaging between membranes
+//////////////////////////////////////////////
+
+/// topics the host's MessageBus delivers messages and requests on
+pub static MESSAGE_TOPIC: &str = "membrane.message";
+pub static REQUEST_TOPIC: &str = "membrane.request";
+
+/// a message or request as delivered by the bus: [u32 sender len][sender][u64 correlation id][payload]
+pub struct Envelope {
+    pub sender: String,
+    pub correlation_id: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn decode(data: &[u8]) -> Result<Self, Error>
+    {
+        if data.len() < 4
+        {
+            return Err("envelope is truncated".into());
+        }
+        let mut sender_len = [0u8; 4];
+        sender_len.copy_from_slice(&data[0..4]);
+        let sender_len = u32::from_le_bytes(sender_len) as usize;
+        if data.len() < 12 + sender_len
+        {
+            return Err("envelope is truncated".into());
+        }
+        let sender = String::from_utf8(data[4..4 + sender_len].to_vec())?;
+        let mut correlation_id = [0u8; 8];
+        correlation_id.copy_from_slice(&data[4 + sender_len..12 + sender_len]);
+        Ok(Envelope {
+            sender: sender,
+            correlation_id: u64::from_le_bytes(correlation_id),
+            payload: data[12 + sender_len..].to_vec(),
+        })
+    }
+}
+
+fn membrane_check_result(result_buffer: i32) -> Result<(), Error>
+{
+    if result_buffer >= 0
+    {
+        return Err(membrane_consume_string(result_buffer)?.into());
+    }
+    Ok(())
+}
+
+/// send payload to the membrane registered on the bus as target
+pub fn membrane_send(target: &str, payload: Vec<u8>) -> Result<(), Error>
+{
+    let target_buffer = membrane_write_str(target);
+    let payload_buffer = membrane_write_buffer(payload);
+    membrane_check_result(unsafe { membrane_host_send(target_buffer, payload_buffer) })
+}
+
+/// send payload to target and wait for its reply. requests back into a membrane that is
+/// already part of the current call chain fail instead of deadlocking
+pub fn membrane_request(target: &str, payload: Vec<u8>) -> Result<Vec<u8>, Error>
+{
+    let target_buffer = membrane_write_str(target);
+    let payload_buffer = membrane_write_buffer(payload);
+    membrane_consume_response(unsafe { membrane_host_request(target_buffer, payload_buffer) })
+}
+
+/// handle messages sent with membrane_send, the handler receives the sender's name and the payload
+pub fn membrane_on_message<F>(handler: F)
+    where F: Fn(&str, &[u8]) -> Result<(), Error> + Send + Sync + 'static
+{
+    membrane_subscribe(MESSAGE_TOPIC, move |data| {
+        let envelope = Envelope::decode(data)?;
+        handler(envelope.sender.as_str(), envelope.payload.as_slice())
+    });
+}
+
+/// answer requests sent with membrane_request, whatever the handler returns is the reply
+pub fn membrane_on_request<F>(handler: F)
+    where F: Fn(&str, &[u8]) -> Result<Vec<u8>, Error> + Send + Sync + 'static
+{
+    membrane_subscribe(REQUEST_TOPIC, move |data| {
+        let envelope = Envelope::decode(data)?;
+        let reply = handler(envelope.sender.as_str(), envelope.payload.as_slice())?;
+        let reply_buffer = membrane_write_buffer(reply);
+        membrane_check_result(unsafe { membrane_host_reply(envelope.correlation_id as i64, reply_buffer) })
+    });
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::{Add, Sub};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(target_os = "wasi"))]
+use wasm_bindgen::prelude::*;
+
+use crate::error::Error;
+use crate::executor::membrane_run_ready;
+use crate::membrane::membrane_consume_response;
+
+extern "C"
+{
+    pub fn membrane_host_time_monotonic() -> i64;
+    pub fn membrane_host_time_wall() -> i64;
+    pub fn membrane_host_sleep(duration: i64);
+    pub fn membrane_host_random(len: i32) -> i32;
+    pub fn membrane_host_timer_start(delay: i64, interval: i64) -> i32;
+    pub fn membrane_host_timer_cancel(id: i32) -> i32;
+}
+
+enum TimerCallback {
+    Once(Box<dyn FnOnce() + Send>),
+    Repeat(Box<dyn FnMut() + Send>),
+}
+
+lazy_static! {
+  static ref TIMERS: Mutex<HashMap<i32, TimerCallback>> = Mutex::new(HashMap::new());
+  /// repeating timers cancelled from their own callback
+  static ref CANCELLED: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+/// called by the host's WasmMembrane::run_timers when the timer with id is due
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_on_timer(id: i32)
+{
+    // taken out while it runs so the callback may start or cancel timers
+    let callback = TIMERS.lock().unwrap().remove(&id);
+    match callback
+    {
+        Some(TimerCallback::Once(callback)) => callback(),
+        Some(TimerCallback::Repeat(mut callback)) => {
+            callback();
+            let mut timers = TIMERS.lock().unwrap();
+            // a callback that cancelled its own timer left it out of TIMERS for good
+            if !CANCELLED.lock().unwrap().remove(&id)
+            {
+                timers.insert(id, TimerCallback::Repeat(callback));
+            }
+        }
+        None => {}
+    }
+    membrane_run_ready();
+}
+
+/// a point on the host's monotonic clock, like std::time::Instant which has no clock to read in a membrane
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant
+    {
+        Instant {
+            nanos: unsafe { membrane_host_time_monotonic() }.max(0) as u64,
+        }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration
+    {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration
+    {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            nanos: self.nanos + duration.as_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// the host's wall clock
+pub fn system_time() -> SystemTime
+{
+    UNIX_EPOCH + Duration::from_nanos(unsafe { membrane_host_time_wall() }.max(0) as u64)
+}
+
+/// block for duration. the host may also just move a virtual clock on
+pub fn sleep(duration: Duration)
+{
+    unsafe { membrane_host_sleep(duration.as_nanos() as i64) }
+}
+
+/// fill dest with bytes from the host's CSPRNG
+pub fn fill_random(dest: &mut [u8]) -> Result<(), Error>
+{
+    let bytes = random_bytes(dest.len())?;
+    if bytes.len() != dest.len()
+    {
+        return Err("the host sent the wrong number of random bytes".into());
+    }
+    dest.copy_from_slice(bytes.as_slice());
+    Ok(())
+}
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, Error>
+{
+    membrane_consume_response(unsafe { membrane_host_random(len as i32) })
+}
+
+/// a running timer, see set_timeout and set_interval. dropping it does not cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timer {
+    id: i32,
+}
+
+impl Timer {
+    pub fn id(&self) -> i32
+    {
+        self.id
+    }
+
+    /// stop the timer. returns whether it was still running
+    pub fn cancel(&self) -> bool
+    {
+        let running = unsafe { membrane_host_timer_cancel(self.id) } == 1;
+        if TIMERS.lock().unwrap().remove(&self.id).is_none() && running
+        {
+            // cancelled from inside its own callback
+            CANCELLED.lock().unwrap().insert(self.id);
+        }
+        running
+    }
+}
+
+/// run callback once after delay
+pub fn set_timeout<F>(delay: Duration, callback: F) -> Result<Timer, Error>
+    where F: FnOnce() + Send + 'static
+{
+    start_timer(delay, Duration::from_secs(0), TimerCallback::Once(Box::new(callback)))
+}
+
+/// run callback every interval until the timer is cancelled
+pub fn set_interval<F>(interval: Duration, callback: F) -> Result<Timer, Error>
+    where F: FnMut() + Send + 'static
+{
+    if interval == Duration::from_secs(0)
+    {
+        return Err("an interval must be longer than 0".into());
+    }
+    start_timer(interval, interval, TimerCallback::Repeat(Box::new(callback)))
+}
+
+fn start_timer(delay: Duration, interval: Duration, callback: TimerCallback) -> Result<Timer, Error>
+{
+    let id = unsafe { membrane_host_timer_start(delay.as_nanos() as i64, interval.as_nanos() as i64) };
+    if id < 0
+    {
+        return Err("the host refused to start the timer".into());
+    }
+    TIMERS.lock().unwrap().insert(id, callback);
+    Ok(Timer { id })
+}
+
+/// a future that completes after duration, for tasks run by membrane_spawn
+pub fn delay(duration: Duration) -> Delay
+{
+    Delay {
+        duration,
+        state: Option::None,
+    }
+}
+
+struct DelayState {
+    fired: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+pub struct Delay {
+    duration: Duration,
+    state: Option<Result<Arc<DelayState>, Error>>,
+}
+
+impl Future for Delay {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output>
+    {
+        if self.state.is_none()
+        {
+            let state = Arc::new(DelayState {
+                fired: AtomicBool::new(false),
+                waker: Mutex::new(Option::None),
+            });
+            let timer_state = state.clone();
+            let started = set_timeout(self.duration, move || {
+                timer_state.fired.store(true, Ordering::SeqCst);
+                if let Some(waker) = timer_state.waker.lock().unwrap().take()
+                {
+                    waker.wake();
+                }
+            });
+            self.state = Option::Some(started.map(|_| state));
+        }
+
+        match self.state.as_ref()
+        {
+            Some(Ok(state)) => {
+                if state.fired.load(Ordering::SeqCst)
+                {
+                    return Poll::Ready(Ok(()));
+                }
+                *state.waker.lock().unwrap() = Option::Some(context.waker().clone());
+                Poll::Pending
+            }
+            Some(Err(error)) => Poll::Ready(Err(error.clone())),
+            None => Poll::Pending
+        }
+    }
+}
@@ -1,9 +1,15 @@
 mod utils;
 
+#[cfg(not(target_os = "wasi"))]
 use wasm_bindgen::prelude::*;
-use wasm_membrane_guest::membrane::log;
+use wasm_membrane_guest::fs;
+use wasm_membrane_guest::kv;
+use wasm_membrane_guest::time::{set_interval, set_timeout, Instant, Timer};
+use wasm_membrane_guest::executor::{membrane_spawn, membrane_call_host_async};
+use wasm_membrane_guest::membrane::{log, membrane_write_buffer, membrane_consume_buffer, membrane_init_with_config, membrane_config_map, membrane_subscribe, membrane_on_message, membrane_on_request, membrane_call_host, HostResource};
 use crate::utils::set_panic_hook;
-use std::{time, thread};
+use std::time;
+use std::sync::{Arc, Mutex};
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -11,31 +17,171 @@ use std::{time, thread};
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-#[wasm_bindgen]
+// what the "remember" topic stored, handed to the next module on reload
+static REMEMBERED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn test()
 {
     log( "Test Works!");
 }
 
 
-#[wasm_bindgen]
-pub fn membrane_guest_init()
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_init_with_config(config_buffer: i32) -> i32
 {
     // if you set_panic_hook() it will fail for some odd reason
     //set_panic_hook();
+    membrane_init_with_config(config_buffer, || {
+        if let Some(greeting) = membrane_config_map()?.get("greeting")
+        {
+            log(greeting.as_str());
+        }
+        membrane_subscribe("greeting", |payload| {
+            log(String::from_utf8(payload.to_vec())?.as_str());
+            Ok(())
+        });
+        membrane_subscribe("fail", |_| Err("asked to fail".into()));
+        membrane_subscribe("remember", |payload| {
+            *REMEMBERED.lock().map_err(|_| "remembered state is poisoned")? = payload.to_vec();
+            Ok(())
+        });
+        membrane_subscribe("recall", |_| {
+            log(format!("remembered: {}", String::from_utf8_lossy(REMEMBERED.lock().map_err(|_| "remembered state is poisoned")?.as_slice())).as_str());
+            Ok(())
+        });
+        membrane_on_message(|sender, payload| {
+            log(format!("message from {}: {}", sender, String::from_utf8_lossy(payload)).as_str());
+            Ok(())
+        });
+        membrane_on_request(|_, payload| Ok(payload.to_vec()));
+        membrane_subscribe("call_host", |payload| {
+            let name = String::from_utf8(payload.to_vec())?;
+            match membrane_call_host(name.as_str(), b"ping".to_vec())?.as_slice()
+            {
+                b"pong" => Ok(()),
+                _ => Err("host function did not answer pong".into())
+            }
+        });
+        membrane_subscribe("release_resource", |payload| {
+            let mut handle = [0u8; 4];
+            handle.copy_from_slice(payload.get(0..4).ok_or("expected a u32 handle")?);
+            HostResource::from_handle(u32::from_le_bytes(handle)).release()
+        });
+        membrane_subscribe("render", |_| {
+            let template = fs::read_to_string("templates/page.html")?;
+            fs::write("out/page.html", template.to_uppercase().as_bytes())
+        });
+        membrane_subscribe("count", |_| {
+            loop {
+                let current = kv::get(b"count")?;
+                let mut count = [0u8; 4];
+                if let Some(current) = &current
+                {
+                    count.copy_from_slice(current.get(0..4).ok_or("count is not a u32")?);
+                }
+                let next = (u32::from_le_bytes(count) + 1).to_le_bytes();
+                if kv::compare_and_swap(b"count", current.as_deref(), Some(&next))?
+                {
+                    return Ok(());
+                }
+            }
+        });
+        // only meaningful when built for wasm32-wasi, elsewhere there is no env and stdout goes nowhere
+        membrane_subscribe("print_env", |payload| {
+            let name = String::from_utf8(payload.to_vec())?;
+            println!("{}", std::env::var(name.as_str()).map_err(|e| format!("{}: {}", name, e))?);
+            Ok(())
+        });
+        membrane_subscribe("print_args", |_| {
+            println!("{}", std::env::args().collect::<Vec<String>>().join(" "));
+            Ok(())
+        });
+        membrane_subscribe("timers", |_| {
+            let start = Instant::now();
+            set_timeout(time::Duration::from_millis(10), move || {
+                log(format!("timeout after {}ms", start.elapsed().as_millis()).as_str());
+            })?;
+
+            // stops itself after three ticks
+            let interval: Arc<Mutex<Option<Timer>>> = Arc::new(Mutex::new(None));
+            let own_interval = interval.clone();
+            let mut ticks = 0;
+            *interval.lock().unwrap() = Some(set_interval(time::Duration::from_millis(4), move || {
+                ticks += 1;
+                log(format!("tick {}", ticks).as_str());
+                if ticks == 3
+                {
+                    if let Some(timer) = *own_interval.lock().unwrap()
+                    {
+                        timer.cancel();
+                    }
+                }
+            })?);
+            Ok(())
+        });
+        membrane_subscribe("call_host_async", |payload| {
+            let name = String::from_utf8(payload.to_vec())?;
+            for i in 0..2
+            {
+                let call = membrane_call_host_async(name.as_str(), b"ping".to_vec());
+                membrane_spawn(async move {
+                    match call.await
+                    {
+                        Ok(reply) => log(format!("async call {}: {}", i, String::from_utf8_lossy(reply.as_slice())).as_str()),
+                        Err(error) => log(format!("async call {} failed: {}", i, error.error).as_str())
+                    }
+                });
+            }
+            Ok(())
+        });
+        Ok(())
+    })
+}
+
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_shutdown()
+{
+    log("shutting down");
 }
 
 
 
-#[wasm_bindgen]
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_export_state() -> i32
+{
+    membrane_write_buffer(REMEMBERED.lock().map(|remembered| remembered.clone()).unwrap_or_default())
+}
+
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_import_state(buffer: i32)
+{
+    if let (Ok(state), Ok(mut remembered)) = (membrane_consume_buffer(buffer), REMEMBERED.lock())
+    {
+        *remembered = state;
+    }
+}
+
+// panics abort on wasm so the host sees a trap, which is what supervision tests need
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
+pub fn membrane_guest_example_test_panic()
+{
+    panic!("asked to panic");
+}
+
+// never returns and keeps burning fuel, so that timeout and interrupt tests can stop it
+#[cfg_attr(not(target_os = "wasi"), wasm_bindgen)]
+#[cfg_attr(target_os = "wasi", no_mangle)]
 pub fn membrane_guest_example_test_endless_loop()
 {
-    let ten_secs = time::Duration::from_secs(10);
-    //let now = time::Instant::now();
     loop
     {
-        log("sleeping 10 secs...");
-        //thread::sleep(ten_secs);
+        log("looping until interrupted...");
     }
-
 }
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use wasmer::Module;
+
+use crate::error::Error;
+use crate::membrane::WasmMembrane;
+use crate::supervisor::{Supervisor, SupervisorConfig};
+
+#[derive(Debug, Clone)]
+pub struct ActorConfig {
+    /// messages that may wait in a mailbox before senders are held back
+    pub mailbox_capacity: usize,
+    pub supervisor: SupervisorConfig,
+    /// how long a stopping actor gives membrane_guest_shutdown
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ActorConfig {
+    fn default() -> Self {
+        ActorConfig {
+            mailbox_capacity: 64,
+            supervisor: SupervisorConfig::default(),
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&Supervisor) + Send>;
+
+enum Mail {
+    Job(Job),
+    Stop,
+}
+
+/// a cheap handle for sending to an actor from any thread. messages are events delivered
+/// to the guest's membrane_guest_on_event one at a time, in the order they were sent
+#[derive(Clone)]
+pub struct ActorRef {
+    name: Arc<String>,
+    mailbox: SyncSender<Mail>,
+}
+
+impl ActorRef {
+    pub fn name(&self) -> &str
+    {
+        self.name.as_str()
+    }
+
+    /// queue an event, waiting for room when the mailbox is full.
+    /// a failing handler is logged by the actor and not reported back
+    pub fn tell(&self, topic: &str, payload: &[u8]) -> Result<(), Error>
+    {
+        let job = self.event_job(topic, payload);
+        self.mailbox.send(Mail::Job(job)).map_err(|_| self.stopped())
+    }
+
+    /// like tell but fails right away when the mailbox is full
+    pub fn try_tell(&self, topic: &str, payload: &[u8]) -> Result<(), Error>
+    {
+        let job = self.event_job(topic, payload);
+        match self.mailbox.try_send(Mail::Job(job))
+        {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(format!("mailbox of actor {} is full", self.name).into()),
+            Err(TrySendError::Disconnected(_)) => Err(self.stopped())
+        }
+    }
+
+    /// queue an event and wait until the guest handled it
+    pub fn ask(&self, topic: &str, payload: &[u8]) -> Result<(), Error>
+    {
+        let topic = topic.to_string();
+        let payload = payload.to_vec();
+        self.call(move |membrane| {
+            match membrane.emit(topic.as_str(), payload.as_slice())?
+            {
+                true => Ok(()),
+                false => Err("guest does not export membrane_guest_on_event".into())
+            }
+        })
+    }
+
+    /// run f against the actor's membrane on the actor's thread and wait for its result.
+    /// a trap inside f restarts the membrane through the actor's supervisor
+    pub fn call<F, T>(&self, f: F) -> Result<T, Error>
+        where F: FnOnce(&WasmMembrane) -> Result<T, Error> + Send + 'static,
+              T: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job: Job = Box::new(move |supervisor: &Supervisor| {
+            sender.send(supervisor.call(f)).unwrap_or(());
+        });
+        self.mailbox.send(Mail::Job(job)).map_err(|_| self.stopped())?;
+        receiver.recv().map_err(|_| self.stopped())?
+    }
+
+    fn event_job(&self, topic: &str, payload: &[u8]) -> Job
+    {
+        let name = self.name.clone();
+        let topic = topic.to_string();
+        let payload = payload.to_vec();
+        Box::new(move |supervisor: &Supervisor| {
+            if let Err(error) = supervisor.call(|membrane| membrane.emit(topic.as_str(), payload.as_slice()))
+            {
+                println!("WasmMembrane: actor {} failed to handle {}: {}", name, topic, error.error);
+            }
+        })
+    }
+
+    fn stopped(&self) -> Error
+    {
+        format!("actor {} has stopped", self.name).into()
+    }
+}
+
+/// a supervised membrane that lives on its own thread and is only reached through its mailbox
+pub struct MembraneActor {
+    actor_ref: ActorRef,
+    supervisor: Arc<Supervisor>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MembraneActor {
+    pub fn spawn(name: &str, module: Arc<Module>, config: &ActorConfig) -> Result<Self, Error>
+    {
+        let supervisor = Supervisor::new(module, config.supervisor.clone())?;
+        let (sender, receiver) = mpsc::sync_channel(config.mailbox_capacity);
+
+        let thread_supervisor = supervisor.clone();
+        let shutdown_timeout = config.shutdown_timeout;
+        let thread = thread::Builder::new()
+            .name(format!("membrane-actor-{}", name))
+            .spawn(move || MembraneActor::run(thread_supervisor, receiver, shutdown_timeout))?;
+
+        Ok(MembraneActor {
+            actor_ref: ActorRef {
+                name: Arc::new(name.to_string()),
+                mailbox: sender,
+            },
+            supervisor,
+            thread: Option::Some(thread),
+        })
+    }
+
+    pub fn actor_ref(&self) -> ActorRef
+    {
+        self.actor_ref.clone()
+    }
+
+    /// subscribe to restarts of the actor's membrane here
+    pub fn supervisor(&self) -> Arc<Supervisor>
+    {
+        self.supervisor.clone()
+    }
+
+    /// handle the mail already queued, shut the membrane down and wait for the thread to end
+    pub fn stop(&mut self) -> Result<(), Error>
+    {
+        if let Some(thread) = self.thread.take()
+        {
+            self.actor_ref.mailbox.send(Mail::Stop).unwrap_or(());
+            thread.join().map_err(|_| format!("actor {} panicked", self.actor_ref.name))?;
+        }
+        Ok(())
+    }
+
+    fn run(supervisor: Arc<Supervisor>, mailbox: Receiver<Mail>, shutdown_timeout: Duration)
+    {
+        for mail in mailbox.iter()
+        {
+            match mail
+            {
+                Mail::Job(job) => job(&supervisor),
+                Mail::Stop => break
+            }
+        }
+
+        if let Ok(membrane) = supervisor.membrane()
+        {
+            if let Err(error) = membrane.shutdown(shutdown_timeout)
+            {
+                println!("WasmMembrane: actor shutdown failed: {}", error.error);
+            }
+        }
+    }
+}
+
+impl Drop for MembraneActor {
+    fn drop(&mut self) {
+        self.stop().unwrap_or(());
+    }
+}
+
+/// named actors sharing one ActorConfig
+pub struct ActorRuntime {
+    config: ActorConfig,
+    actors: Mutex<HashMap<String, MembraneActor>>,
+}
+
+impl ActorRuntime {
+    pub fn new(config: ActorConfig) -> Self
+    {
+        ActorRuntime {
+            config,
+            actors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn spawn(&self, name: &str, module: Arc<Module>) -> Result<ActorRef, Error>
+    {
+        let mut actors = self.actors.lock()?;
+        if actors.contains_key(name)
+        {
+            return Err(format!("an actor named {} is already running", name).into());
+        }
+        let actor = MembraneActor::spawn(name, module, &self.config)?;
+        let actor_ref = actor.actor_ref();
+        actors.insert(name.to_string(), actor);
+        Ok(actor_ref)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ActorRef>
+    {
+        self.actors.lock().ok()?.get(name).map(|actor| actor.actor_ref())
+    }
+
+    pub fn supervisor(&self, name: &str) -> Option<Arc<Supervisor>>
+    {
+        self.actors.lock().ok()?.get(name).map(|actor| actor.supervisor())
+    }
+
+    pub fn names(&self) -> Vec<String>
+    {
+        let mut names: Vec<String> = match self.actors.lock()
+        {
+            Ok(actors) => actors.keys().cloned().collect(),
+            Err(_) => vec![]
+        };
+        names.sort();
+        names
+    }
+
+    pub fn stop(&self, name: &str) -> Result<(), Error>
+    {
+        let actor = self.actors.lock()?.remove(name);
+        match actor
+        {
+            Some(mut actor) => actor.stop(),
+            None => Err(format!("there is no actor named {}", name).into())
+        }
+    }
+
+    /// stop every actor
+    pub fn shutdown(&self) -> Result<(), Error>
+    {
+        let actors: Vec<MembraneActor> = self.actors.lock()?.drain().map(|(_, actor)| actor).collect();
+        for mut actor in actors
+        {
+            actor.stop()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::fs::File;
+    use std::io::Read;
+
+    use crate::actor::{ActorConfig, ActorRuntime};
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+
+    #[test]
+    pub fn test_actor() -> Result<(), Error>
+    {
+        let path = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let runtime = ActorRuntime::new(ActorConfig {
+            mailbox_capacity: 1,
+            ..Default::default()
+        });
+        let actor = runtime.spawn("greeter", WasmMembrane::compile(data.as_slice())?)?;
+        assert!(runtime.spawn("greeter", WasmMembrane::compile(data.as_slice())?).is_err());
+
+        actor.tell("greeting", b"hello")?;
+        actor.ask("greeting", b"hello again")?;
+        assert!(actor.ask("fail", b"").is_err());
+
+        assert!(actor.call(|membrane| membrane.test_panic()).is_err());
+        actor.call(|membrane| membrane.test_log())?;
+        assert_eq!(runtime.supervisor("greeter").unwrap().generation()?, 2);
+
+        runtime.shutdown()?;
+        assert!(actor.tell("greeting", b"too late").is_err());
+        Ok(())
+    }
+}
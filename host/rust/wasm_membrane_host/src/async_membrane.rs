@@ -0,0 +1,266 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::Error;
+use crate::extensions::Extensions;
+use crate::membrane::WasmMembrane;
+
+/// an async facade over WasmMembrane for tokio based hosts (feature "async").
+///
+/// guest calls run on tokio's blocking pool so they never stall the reactor, one at a time:
+/// calls made through an AsyncMembrane or its clones wait for the previous one to finish.
+/// dropping the future of a call that is running interrupts the guest, which only works for
+/// membranes compiled with fuel (see MembraneEngineConfig::fuel); others keep running to
+/// completion on the blocking pool. an interrupted membrane is poisoned. dropping the future
+/// of a call that is still waiting for its turn just cancels it
+#[derive(Clone)]
+pub struct AsyncMembrane {
+    membrane: Arc<WasmMembrane>,
+    runtime: Handle,
+    /// one permit, held by the call that is running the guest
+    guest: Arc<Semaphore>,
+}
+
+impl AsyncMembrane {
+    /// must be called from within a tokio runtime
+    pub fn new(membrane: Arc<WasmMembrane>) -> Result<Self, Error>
+    {
+        let runtime = Handle::try_current().map_err(|_| "AsyncMembrane::new must be called within a tokio runtime")?;
+        Ok(AsyncMembrane::with_handle(membrane, runtime))
+    }
+
+    pub fn with_handle(membrane: Arc<WasmMembrane>, runtime: Handle) -> Self
+    {
+        AsyncMembrane {
+            membrane,
+            runtime,
+            guest: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    pub fn membrane(&self) -> Arc<WasmMembrane>
+    {
+        self.membrane.clone()
+    }
+
+    /// run f against the membrane on the blocking pool
+    pub async fn run<F, T>(&self, f: F) -> Result<T, Error>
+        where F: FnOnce(&WasmMembrane) -> Result<T, Error> + Send + 'static,
+              T: Send + 'static
+    {
+        let permit = self.guest.clone().acquire_owned().await.map_err(|_| "the guest lock was closed")?;
+        let membrane = self.membrane.clone();
+        let running = Arc::new(Mutex::new(true));
+        let call = GuestCall { running: running.clone(), _permit: permit };
+        let mut interrupt = InterruptOnDrop { membrane: Option::Some(membrane.clone()), running };
+        let result = self.runtime.spawn_blocking(move || {
+            let _call = call;
+            f(&membrane)
+        }).await;
+        interrupt.disarm();
+        result.map_err(|error| format!("guest call failed: {}", error))?
+    }
+
+    /// like run but gives up after timeout, interrupting the guest
+    pub async fn run_with_timeout<F, T>(&self, timeout: Duration, f: F) -> Result<T, Error>
+        where F: FnOnce(&WasmMembrane) -> Result<T, Error> + Send + 'static,
+              T: Send + 'static
+    {
+        match tokio::time::timeout(timeout, self.run(f)).await
+        {
+            Ok(result) => result,
+            Err(_) => Err(format!("guest call did not finish within {:?}", timeout).into())
+        }
+    }
+
+    pub async fn init(&self) -> Result<(), Error>
+    {
+        self.run(|membrane| membrane.init()).await
+    }
+
+    pub async fn emit(&self, topic: &str, payload: &[u8]) -> Result<bool, Error>
+    {
+        let topic = topic.to_string();
+        let payload = payload.to_vec();
+        self.run(move |membrane| membrane.emit(topic.as_str(), payload.as_slice())).await
+    }
+
+    /// answer the guest's pending membrane_host_call_async calls with the registered host
+    /// functions, running them concurrently on the blocking pool, until the guest stops making
+    /// new ones. returns how many calls were completed
+    pub async fn run_pending_calls(&self) -> Result<usize, Error>
+    {
+        let mut completed = 0;
+        loop {
+            let calls = self.membrane.take_pending_calls()?;
+            if calls.is_empty()
+            {
+                return Ok(completed);
+            }
+
+            let mut results = vec![];
+            for call in calls
+            {
+                let membrane = self.membrane.clone();
+                let result = self.runtime.spawn_blocking(move || {
+                    let result = membrane.call_host_function(call.name.as_str(), call.payload.as_slice());
+                    (call.handle, result)
+                });
+                results.push(result);
+            }
+
+            for result in results
+            {
+                let (handle, result) = result.await.map_err(|error| format!("host function failed: {}", error))?;
+                self.run(move |membrane| membrane.complete(handle, result)).await?;
+                completed += 1;
+            }
+        }
+    }
+
+    /// make an async fn callable by the guest through membrane_host_call. the guest stays blocked
+    /// on its blocking pool thread while the future runs on the runtime, so the guest must be
+    /// called through this facade: a guest called directly from an async task would block the
+    /// reactor and panic when it reaches the function. the future receives the membrane's
+    /// extensions
+    pub fn register_async_host_function<F, Fut>(&self, name: &str, function: F) -> Result<(), Error>
+        where F: Fn(Arc<Extensions>, Vec<u8>) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = Result<Vec<u8>, Error>> + Send + 'static
+    {
+        let runtime = self.runtime.clone();
+        self.membrane.register_host_function(name, Arc::new(move |membrane: &WasmMembrane, payload: &[u8]| {
+            runtime.block_on(function(membrane.extensions(), payload.to_vec()))
+        }))
+    }
+}
+
+/// held by a running call on the blocking pool. it marks the call finished before giving up
+/// the permit, so an interrupt from the call's dropped future cannot reach the next call
+struct GuestCall {
+    running: Arc<Mutex<bool>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for GuestCall {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.running.lock()
+        {
+            *running = false;
+        }
+    }
+}
+
+/// interrupts the guest unless disarmed, so that dropping a call's future stops the call
+struct InterruptOnDrop {
+    membrane: Option<Arc<WasmMembrane>>,
+    running: Arc<Mutex<bool>>,
+}
+
+impl InterruptOnDrop {
+    fn disarm(&mut self)
+    {
+        self.membrane = Option::None;
+    }
+}
+
+impl Drop for InterruptOnDrop {
+    fn drop(&mut self) {
+        if let Some(membrane) = self.membrane.take()
+        {
+            // holding the lock keeps the call from finishing and letting the next one in meanwhile
+            if let Ok(running) = self.running.lock()
+            {
+                if *running
+                {
+                    membrane.interrupt().unwrap_or(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::async_membrane::AsyncMembrane;
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+
+    static WASM_PATH: &str = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_async_host_function() -> Result<(), Error>
+    {
+        let membrane = AsyncMembrane::new(WasmMembrane::from_file(WASM_PATH)?)?;
+        membrane.register_async_host_function("pong", |_, payload| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            match payload.as_slice()
+            {
+                b"ping" => Ok(b"pong".to_vec()),
+                _ => Err("expected ping".into())
+            }
+        })?;
+
+        assert!(membrane.emit("call_host", b"pong").await?);
+        assert!(membrane.emit("call_host", b"missing").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_calls_take_turns() -> Result<(), Error>
+    {
+        let membrane = AsyncMembrane::new(WasmMembrane::from_file(WASM_PATH)?)?;
+        let inside = Arc::new(AtomicUsize::new(0));
+        let call = |membrane: AsyncMembrane, inside: Arc<AtomicUsize>| async move {
+            membrane.run(move |membrane| {
+                assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                std::thread::sleep(Duration::from_millis(20));
+                membrane.test_log()?;
+                inside.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }).await
+        };
+
+        let (first, second, third) = tokio::join!(
+            call(membrane.clone(), inside.clone()),
+            call(membrane.clone(), inside.clone()),
+            call(membrane.clone(), inside.clone())
+        );
+        first?;
+        second?;
+        third?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_timeout() -> Result<(), Error>
+    {
+        let config = MembraneEngineConfig {
+            fuel: Option::Some(u64::MAX / 2),
+            ..Default::default()
+        };
+        let membrane = AsyncMembrane::new(WasmMembrane::from_file_with_config(WASM_PATH, &config)?)?;
+        let result = membrane.run_with_timeout(Duration::from_millis(100), |membrane| membrane.test_endless_loop()).await;
+        assert!(result.is_err());
+
+        // the interrupted call traps on the blocking pool shortly after
+        let mut waited = 0;
+        while !membrane.membrane().trapped() && waited < 50
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            waited += 1;
+        }
+        assert!(membrane.membrane().trapped());
+        assert!(membrane.emit("test", b"").await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use wasm_membrane_host::repl::Repl;
+
+fn usage() -> !
+{
+    eprintln!("usage: wasm-membrane repl <module.wasm>");
+    process::exit(1);
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3
+    {
+        usage();
+    }
+
+    match args[1].as_str()
+    {
+        "repl" => {
+            let mut repl = match Repl::new(Path::new(&args[2]))
+            {
+                Ok(repl) => repl,
+                Err(e) => {
+                    eprintln!("could not load {}: {}", args[2], e.error);
+                    process::exit(1);
+                }
+            };
+
+            if let Err(e) = repl.run()
+            {
+                eprintln!("repl error: {}", e.error);
+                process::exit(1);
+            }
+        }
+        _ => usage()
+    }
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::membrane::WasmMembrane;
+
+/// topic of the events a plain send delivers to membrane_guest_on_event
+pub static MESSAGE_TOPIC: &str = "membrane.message";
+/// topic of the events a request delivers, the handler is expected to call membrane_host_reply
+pub static REQUEST_TOPIC: &str = "membrane.request";
+
+thread_local! {
+    /// names of the bus members that are currently executing on this thread, outermost first
+    static DELIVERY_CHAIN: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageBusConfig {
+    /// longest chain of nested deliveries (a sends to b which sends to c ...)
+    pub max_depth: usize,
+    /// how long a delivery waits for a target that is busy handling a delivery on another thread
+    pub delivery_timeout: Duration,
+}
+
+impl Default for MessageBusConfig {
+    fn default() -> Self {
+        MessageBusConfig {
+            max_depth: 8,
+            delivery_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// the payload of MESSAGE_TOPIC and REQUEST_TOPIC events:
+/// [u32 sender len][sender][u64 correlation id][payload], little endian.
+/// the correlation id is 0 for plain sends
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub sender: String,
+    pub correlation_id: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let mut data = Vec::with_capacity(12 + self.sender.len() + self.payload.len());
+        data.extend_from_slice(&(self.sender.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.sender.as_bytes());
+        data.extend_from_slice(&self.correlation_id.to_le_bytes());
+        data.extend_from_slice(self.payload.as_slice());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Error>
+    {
+        let truncated = || Error::from("envelope is truncated");
+        let mut sender_len = [0u8; 4];
+        sender_len.copy_from_slice(data.get(0..4).ok_or_else(truncated)?);
+        let sender_len = u32::from_le_bytes(sender_len) as usize;
+        let sender = String::from_utf8(data.get(4..4 + sender_len).ok_or_else(truncated)?.to_vec())?;
+        let mut correlation_id = [0u8; 8];
+        correlation_id.copy_from_slice(data.get(4 + sender_len..12 + sender_len).ok_or_else(truncated)?);
+        Ok(Envelope {
+            sender,
+            correlation_id: u64::from_le_bytes(correlation_id),
+            payload: data[12 + sender_len..].to_vec(),
+        })
+    }
+}
+
+struct BusMember {
+    membrane: Weak<WasmMembrane>,
+    /// true while a bus delivery into this membrane is running
+    busy: Mutex<bool>,
+    idle: Condvar,
+}
+
+/// marks a member busy and on the delivery chain of this thread until dropped
+struct DeliveryGuard<'a> {
+    member: &'a BusMember,
+}
+
+impl<'a> Drop for DeliveryGuard<'a> {
+    fn drop(&mut self) {
+        DELIVERY_CHAIN.with(|chain| chain.borrow_mut().pop());
+        if let Ok(mut busy) = self.member.busy.lock()
+        {
+            *busy = false;
+        }
+        self.member.idle.notify_one();
+    }
+}
+
+/// routes messages between membranes addressed by name. guests use the membrane_host_send,
+/// membrane_host_request and membrane_host_reply imports, the host may use send and request
+/// directly. payloads are copied, membranes never share memory.
+///
+/// deliveries are synchronous. a delivery that would re-enter a membrane which is already
+/// executing on the same thread fails instead of deadlocking, and a membrane is only
+/// entered by one bus delivery at a time
+pub struct MessageBus {
+    config: MessageBusConfig,
+    members: RwLock<HashMap<String, Arc<BusMember>>>,
+    next_correlation_id: AtomicU64,
+    /// requests waiting for their reply, keyed by correlation id
+    replies: Mutex<HashMap<u64, PendingReply>>,
+}
+
+/// a request that is being delivered
+#[derive(Debug, PartialEq)]
+struct PendingReply {
+    /// the membrane the request was sent to, the only one allowed to answer it
+    to: String,
+    reply: Option<Vec<u8>>,
+}
+
+impl MessageBus {
+    pub fn new(config: MessageBusConfig) -> Arc<Self>
+    {
+        Arc::new(MessageBus {
+            config,
+            members: RwLock::new(HashMap::new()),
+            next_correlation_id: AtomicU64::new(1),
+            replies: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// make membrane reachable under name and let its guest send under that name
+    pub fn register(self: &Arc<Self>, name: &str, membrane: &Arc<WasmMembrane>) -> Result<(), Error>
+    {
+        let mut members = self.members.write()?;
+        if let Some(member) = members.get(name)
+        {
+            if member.membrane.upgrade().is_some()
+            {
+                return Err(format!("a membrane named {} is already on the bus", name).into());
+            }
+        }
+
+        membrane.attach_bus(Arc::downgrade(self), name)?;
+        members.insert(name.to_string(), Arc::new(BusMember {
+            membrane: Arc::downgrade(membrane),
+            busy: Mutex::new(false),
+            idle: Condvar::new(),
+        }));
+        Ok(())
+    }
+
+    pub fn unregister(&self, name: &str) -> Result<(), Error>
+    {
+        if let Some(member) = self.members.write()?.remove(name)
+        {
+            if let Some(membrane) = member.membrane.upgrade()
+            {
+                membrane.detach_bus()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String>
+    {
+        let mut names: Vec<String> = match self.members.read()
+        {
+            Ok(members) => members.iter().filter(|(_, member)| member.membrane.upgrade().is_some()).map(|(name, _)| name.clone()).collect(),
+            Err(_) => vec![]
+        };
+        names.sort();
+        names
+    }
+
+    /// deliver payload to the MESSAGE_TOPIC handlers of the membrane named to
+    pub fn send(&self, from: &str, to: &str, payload: &[u8]) -> Result<(), Error>
+    {
+        let envelope = Envelope {
+            sender: from.to_string(),
+            correlation_id: 0,
+            payload: payload.to_vec(),
+        };
+        self.deliver(from, to, MESSAGE_TOPIC, &envelope)
+    }
+
+    /// deliver payload to the REQUEST_TOPIC handlers of the membrane named to and return the
+    /// payload it replied with
+    pub fn request(&self, from: &str, to: &str, payload: &[u8]) -> Result<Vec<u8>, Error>
+    {
+        let correlation_id = self.next_correlation_id.fetch_add(1, Ordering::Relaxed);
+        self.replies.lock()?.insert(correlation_id, PendingReply { to: to.to_string(), reply: Option::None });
+
+        let envelope = Envelope {
+            sender: from.to_string(),
+            correlation_id,
+            payload: payload.to_vec(),
+        };
+        let delivered = self.deliver(from, to, REQUEST_TOPIC, &envelope);
+        let reply = self.replies.lock()?.remove(&correlation_id).and_then(|pending| pending.reply);
+
+        delivered?;
+        reply.ok_or_else(|| format!("{} did not reply to request {}", to, correlation_id).into())
+    }
+
+    /// answer the request with correlation_id on behalf of the membrane named from. only the
+    /// membrane the request was sent to may answer, only once and only while the request is
+    /// being delivered
+    pub fn reply(&self, from: &str, correlation_id: u64, payload: &[u8]) -> Result<(), Error>
+    {
+        let mut replies = self.replies.lock()?;
+        match replies.get_mut(&correlation_id)
+        {
+            Some(pending) if pending.to != from => Err(format!("{} cannot answer request {} which was sent to {}", from, correlation_id, pending.to).into()),
+            Some(pending) if pending.reply.is_none() => {
+                pending.reply = Option::Some(payload.to_vec());
+                Ok(())
+            }
+            Some(_) => Err(format!("request {} has already been answered", correlation_id).into()),
+            None => Err(format!("there is no pending request {}", correlation_id).into())
+        }
+    }
+
+    fn deliver(&self, from: &str, to: &str, topic: &str, envelope: &Envelope) -> Result<(), Error>
+    {
+        let member = self.members.read()?.get(to).cloned().ok_or_else(|| format!("there is no membrane named {} on the bus", to))?;
+        let membrane = member.membrane.upgrade().ok_or_else(|| format!("membrane {} is gone", to))?;
+
+        // the sender is executing on this thread even when the host called it directly
+        let chain = DELIVERY_CHAIN.with(|chain| chain.borrow().clone());
+        let mut route = chain.clone();
+        if route.last().map(|last| last.as_str()) != Option::Some(from)
+        {
+            route.push(from.to_string());
+        }
+
+        if route.iter().any(|name| name == to)
+        {
+            return Err(format!("delivery to {} would re-enter it: {} -> {}", to, route.join(" -> "), to).into());
+        }
+        if chain.len() >= self.config.max_depth
+        {
+            return Err(format!("delivery to {} exceeds the maximum depth of {}: {} -> {}", to, self.config.max_depth, route.join(" -> "), to).into());
+        }
+
+        let _guard = self.enter(&member, to)?;
+        match membrane.emit(topic, envelope.encode().as_slice())?
+        {
+            true => Ok(()),
+            false => Err(format!("{} does not export membrane_guest_on_event", to).into())
+        }
+    }
+
+    fn enter<'a>(&self, member: &'a BusMember, name: &str) -> Result<DeliveryGuard<'a>, Error>
+    {
+        let deadline = Instant::now() + self.config.delivery_timeout;
+        let mut busy = member.busy.lock()?;
+        while *busy
+        {
+            let now = Instant::now();
+            if now >= deadline
+            {
+                return Err(format!("{} stayed busy for {:?}, giving up to avoid a deadlock", name, self.config.delivery_timeout).into());
+            }
+            busy = member.idle.wait_timeout(busy, deadline - now)?.0;
+        }
+        *busy = true;
+        DELIVERY_CHAIN.with(|chain| chain.borrow_mut().push(name.to_string()));
+        Ok(DeliveryGuard { member })
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::Arc;
+
+    use crate::bus::{Envelope, MessageBus, MessageBusConfig, PendingReply};
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+
+    #[test]
+    pub fn test_envelope() -> Result<(), Error>
+    {
+        let envelope = Envelope {
+            sender: "alpha".to_string(),
+            correlation_id: 42,
+            payload: b"ping".to_vec(),
+        };
+        assert_eq!(Envelope::decode(envelope.encode().as_slice())?, envelope);
+        assert!(Envelope::decode(&envelope.encode()[..6]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_bus() -> Result<(), Error>
+    {
+        let path = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+        let bus = MessageBus::new(MessageBusConfig::default());
+        let alpha: Arc<WasmMembrane> = WasmMembrane::from_file(path)?;
+        let beta: Arc<WasmMembrane> = WasmMembrane::from_file(path)?;
+        bus.register("alpha", &alpha)?;
+        bus.register("beta", &beta)?;
+        assert!(bus.register("alpha", &beta).is_err());
+        assert_eq!(bus.names(), vec!["alpha".to_string(), "beta".to_string()]);
+
+        bus.send("host", "alpha", b"hello")?;
+        assert_eq!(bus.request("host", "beta", b"echo")?, b"echo".to_vec());
+        assert!(bus.send("alpha", "alpha", b"loop").is_err());
+        assert!(bus.send("host", "gamma", b"nobody").is_err());
+
+        drop(beta);
+        assert!(bus.send("host", "beta", b"gone").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_reply_only_from_target() -> Result<(), Error>
+    {
+        let bus = MessageBus::new(MessageBusConfig::default());
+        // a request to beta that is still being delivered
+        bus.replies.lock()?.insert(7, PendingReply { to: "beta".to_string(), reply: Option::None });
+
+        assert!(bus.reply("gamma", 7, b"forged").is_err());
+        bus.reply("beta", 7, b"answer")?;
+        assert!(bus.reply("beta", 7, b"again").is_err());
+        assert_eq!(bus.replies.lock()?.remove(&7).and_then(|pending| pending.reply), Option::Some(b"answer".to_vec()));
+        Ok(())
+    }
+}
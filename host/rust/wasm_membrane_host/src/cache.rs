@@ -0,0 +1,231 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+use wasmer::{Module, Store};
+
+use crate::error::Error;
+
+static CACHE_MAGIC: &[u8] = b"WMCACHE\0";
+static CACHE_FORMAT_VERSION: u32 = 1;
+
+/// hit and miss counters for a ModuleCache.
+/// rejected counts cache files that existed but were corrupted or stale and had to be recompiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub rejected: u64,
+}
+
+/// stores serialized compiled modules in a directory so that a process start does not
+/// have to run the compiler again for a guest it has already seen.
+///
+/// entries are keyed by a hash of the wasm bytes, the wasmer version, the compilation target
+/// and a caller supplied description of the engine settings.
+///
+/// entries are native code that is loaded without verification. their checksum only catches
+/// truncated or corrupted files, anyone who can write to the directory can replace an entry
+/// and its checksum together. the directory must only be writable by this process' user
+#[derive(Debug)]
+pub struct ModuleCache {
+    dir: PathBuf,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ModuleCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error>
+    {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(ModuleCache {
+            dir: dir.as_ref().to_path_buf(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path
+    {
+        self.dir.as_path()
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn key(&self, store: &Store, wasm: &[u8], engine_settings: &str) -> String
+    {
+        let target = store.engine().target();
+        let mut hasher = Sha256::new();
+        hasher.update(wasmer::VERSION.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(target.triple().to_string().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(format!("{:?}", target.cpu_features()).as_bytes());
+        hasher.update(b"\0");
+        hasher.update(engine_settings.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(wasm);
+        to_hex(&hasher.finalize()[..])
+    }
+
+    /// return the cached module for this wasm if there is a valid one, otherwise compile it
+    /// and store the artifact for next time
+    pub fn load_or_compile(&self, store: &Store, wasm: &[u8], engine_settings: &str) -> Result<Module, Error>
+    {
+        let path = self.dir.join(format!("{}.wasmcache", self.key(store, wasm, engine_settings)));
+
+        if path.exists()
+        {
+            match self.load(store, &path)
+            {
+                Ok(module) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(module);
+                }
+                Err(_) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    fs::remove_file(&path).unwrap_or(());
+                }
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let module = Module::new(store, wasm)?;
+        self.store(&path, module.serialize()?.as_slice())?;
+        Ok(module)
+    }
+
+    /// remove every entry from the cache directory
+    pub fn clear(&self) -> Result<(), Error>
+    {
+        for entry in fs::read_dir(&self.dir)?
+        {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "wasmcache").unwrap_or(false)
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&self, store: &Store, path: &Path) -> Result<Module, Error>
+    {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let header_len = CACHE_MAGIC.len() + 4 + 32 + 8;
+        if data.len() < header_len || !data.starts_with(CACHE_MAGIC)
+        {
+            return Err("cache entry has a bad header".into());
+        }
+
+        let mut offset = CACHE_MAGIC.len();
+        let version = u32::from_le_bytes(read_array(&data, &mut offset)?);
+        if version != CACHE_FORMAT_VERSION
+        {
+            return Err(format!("cache entry has format version {} expected {}", version, CACHE_FORMAT_VERSION).into());
+        }
+        let checksum: [u8; 32] = read_array(&data, &mut offset)?;
+        let len = u64::from_le_bytes(read_array(&data, &mut offset)?) as usize;
+        let artifact = &data[offset..];
+        if artifact.len() != len || Sha256::digest(artifact)[..] != checksum[..]
+        {
+            return Err("cache entry is corrupted".into());
+        }
+
+        // the checksum rules out a torn or corrupted file and the key a different wasmer version
+        // or target. nothing proves we wrote the entry, that relies on nobody else being able
+        // to write to the cache directory
+        Ok(unsafe { Module::deserialize(store, artifact)? })
+    }
+
+    fn store(&self, path: &Path, artifact: &[u8]) -> Result<(), Error>
+    {
+        let mut data = Vec::with_capacity(artifact.len() + 52);
+        data.extend_from_slice(CACHE_MAGIC);
+        data.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&Sha256::digest(artifact)[..]);
+        data.extend_from_slice(&(artifact.len() as u64).to_le_bytes());
+        data.extend_from_slice(artifact);
+
+        // write then rename so that a concurrent reader never sees a partial entry
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(data.as_slice())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn read_array<A: Default + AsMut<[u8]>>(data: &[u8], offset: &mut usize) -> Result<A, Error>
+{
+    let mut array = A::default();
+    let len = array.as_mut().len();
+    let bytes = data.get(*offset..*offset + len).ok_or("cache entry is truncated")?;
+    array.as_mut().copy_from_slice(bytes);
+    *offset += len;
+    Ok(array)
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::fs;
+    use std::env;
+
+    use crate::cache::{ModuleCache, CacheStats};
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+
+    static WAT: &str = "(module (func (export \"answer\") (result i32) i32.const 42))";
+
+    #[test]
+    pub fn test_cache() -> Result<(), Error>
+    {
+        let dir = env::temp_dir().join(format!("wasm_membrane_cache_test_{}", std::process::id()));
+        let cache = ModuleCache::new(&dir)?;
+        cache.clear()?;
+        let store = MembraneEngineConfig::default().store()?;
+
+        cache.load_or_compile(&store, WAT.as_bytes(), "test")?;
+        cache.load_or_compile(&store, WAT.as_bytes(), "test")?;
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, rejected: 0 });
+
+        // different engine settings must not share an entry
+        cache.load_or_compile(&store, WAT.as_bytes(), "other")?;
+        assert_eq!(cache.stats().misses, 2);
+
+        // corrupt the entry and expect a recompile
+        let path = dir.join(format!("{}.wasmcache", cache.key(&store, WAT.as_bytes(), "test")));
+        let mut data = fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data)?;
+        cache.load_or_compile(&store, WAT.as_bytes(), "test")?;
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 3, rejected: 1 });
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use wasmer::wasmparser::Operator;
+use wasmer::{FunctionMiddleware, LocalFunctionIndex, MiddlewareError, MiddlewareReaderState, ModuleMiddleware};
+
+/// how a deterministic engine deals with float operations whose NaN results differ between cpus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FloatPolicy {
+    /// refuse to compile modules that use them
+    Reject,
+    /// compile them so that every NaN they produce is the canonical NaN.
+    /// not supported by the singlepass compiler
+    CanonicalizeNaNs,
+}
+
+/// settings of MembraneEngineConfig::determinism. with them two runs of a guest given the same
+/// inputs compute bit-identical results: threads are refused, floats follow the policy and the
+/// guest's time and randomness come from a virtual clock and a seeded generator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Determinism {
+    pub floats: FloatPolicy,
+    /// seed of the random bytes the guest gets
+    pub seed: u64,
+    /// where the virtual clock starts, in nanoseconds since the unix epoch
+    pub start_time: u64,
+    /// how far the virtual clock moves every time the guest reads it, in nanoseconds
+    pub clock_step: u64,
+}
+
+impl Default for Determinism {
+    fn default() -> Self {
+        Determinism {
+            floats: FloatPolicy::CanonicalizeNaNs,
+            seed: 0,
+            start_time: 0,
+            clock_step: 1_000,
+        }
+    }
+}
+
+/// a clock that only moves when it is read or advanced
+#[derive(Debug)]
+pub struct VirtualClock {
+    nanos: AtomicU64,
+    step: u64,
+}
+
+impl VirtualClock {
+    pub fn new(start: u64, step: u64) -> Self
+    {
+        VirtualClock {
+            nanos: AtomicU64::new(start),
+            step,
+        }
+    }
+
+    /// the current time in nanoseconds, moving the clock on by its step
+    pub fn now(&self) -> u64
+    {
+        self.nanos.fetch_add(self.step, Ordering::SeqCst)
+    }
+
+    /// the current time without moving the clock
+    pub fn peek(&self) -> u64
+    {
+        self.nanos.load(Ordering::SeqCst)
+    }
+
+    pub fn advance(&self, nanos: u64)
+    {
+        self.nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, nanos: u64)
+    {
+        self.nanos.store(nanos, Ordering::SeqCst);
+    }
+}
+
+/// splitmix64. reproducible from its seed, which makes it unfit for secrets
+#[derive(Debug, Clone)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self
+    {
+        SeededRandom { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64
+    {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill(&mut self, dest: &mut [u8])
+    {
+        for chunk in dest.chunks_mut(8)
+        {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// the time and randomness a deterministic membrane hands its guest
+#[derive(Debug)]
+pub struct DeterministicSources {
+    pub clock: Arc<VirtualClock>,
+    pub random: Mutex<SeededRandom>,
+}
+
+impl DeterministicSources {
+    pub fn new(determinism: &Determinism) -> Self
+    {
+        DeterministicSources {
+            clock: Arc::new(VirtualClock::new(determinism.start_time, determinism.clock_step)),
+            random: Mutex::new(SeededRandom::new(determinism.seed)),
+        }
+    }
+}
+
+/// fails compilation of any function using a float operation that may produce a NaN
+#[derive(Debug)]
+pub(crate) struct RejectNanSensitiveFloats;
+
+impl ModuleMiddleware for RejectNanSensitiveFloats {
+    fn generate_function_middleware(&self, _local_function_index: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(RejectNanSensitiveFloatsFunction)
+    }
+}
+
+#[derive(Debug)]
+struct RejectNanSensitiveFloatsFunction;
+
+impl FunctionMiddleware for RejectNanSensitiveFloatsFunction {
+    fn feed<'a>(&mut self, operator: Operator<'a>, state: &mut MiddlewareReaderState<'a>) -> Result<(), MiddlewareError> {
+        if nan_sensitive(&operator)
+        {
+            return Err(MiddlewareError::new("determinism", format!("{:?} may produce a non-deterministic NaN", operator)));
+        }
+        state.push_operator(operator);
+        Ok(())
+    }
+}
+
+/// float operations whose NaN bits are not fixed by the spec. sign operations, comparisons,
+/// conversions from integers and reinterpretations are deterministic
+fn nan_sensitive(operator: &Operator) -> bool
+{
+    matches!(operator,
+        Operator::F32Add | Operator::F32Sub | Operator::F32Mul | Operator::F32Div
+        | Operator::F32Min | Operator::F32Max | Operator::F32Sqrt
+        | Operator::F32Ceil | Operator::F32Floor | Operator::F32Trunc | Operator::F32Nearest
+        | Operator::F64Add | Operator::F64Sub | Operator::F64Mul | Operator::F64Div
+        | Operator::F64Min | Operator::F64Max | Operator::F64Sqrt
+        | Operator::F64Ceil | Operator::F64Floor | Operator::F64Trunc | Operator::F64Nearest
+        | Operator::F32DemoteF64 | Operator::F64PromoteF32
+        | Operator::F32x4Add | Operator::F32x4Sub | Operator::F32x4Mul | Operator::F32x4Div
+        | Operator::F32x4Min | Operator::F32x4Max | Operator::F32x4Sqrt
+        | Operator::F64x2Add | Operator::F64x2Sub | Operator::F64x2Mul | Operator::F64x2Div
+        | Operator::F64x2Min | Operator::F64x2Max | Operator::F64x2Sqrt)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::determinism::{Determinism, FloatPolicy, SeededRandom, VirtualClock};
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use wasmer::{imports, Instance, Module, Val};
+
+    static FLOAT_WAT: &str = "(module (func (export \"nan\") (result i32) f32.const 0 f32.const 0 f32.div i32.reinterpret_f32))";
+    static SHARED_MEMORY_WAT: &str = "(module (memory 1 1 shared))";
+
+    #[test]
+    pub fn test_sources()
+    {
+        let clock = VirtualClock::new(100, 10);
+        assert_eq!(clock.now(), 100);
+        assert_eq!(clock.now(), 110);
+        clock.advance(1000);
+        assert_eq!(clock.peek(), 1120);
+
+        let mut a = [0u8; 13];
+        let mut b = [0u8; 13];
+        SeededRandom::new(7).fill(&mut a);
+        SeededRandom::new(7).fill(&mut b);
+        assert_eq!(a, b);
+        SeededRandom::new(8).fill(&mut b);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    pub fn test_determinism() -> Result<(), Error>
+    {
+        let reject = MembraneEngineConfig {
+            determinism: Option::Some(Determinism { floats: FloatPolicy::Reject, ..Default::default() }),
+            ..Default::default()
+        };
+        assert!(Module::new(&reject.store()?, FLOAT_WAT).is_err());
+        assert!(Module::new(&reject.store()?, SHARED_MEMORY_WAT).is_err());
+        assert!(MembraneEngineConfig { threads: true, ..reject.clone() }.store().is_err());
+
+        // 0/0 gives the canonical NaN whatever the cpu
+        let canonical = MembraneEngineConfig {
+            determinism: Option::Some(Determinism::default()),
+            ..Default::default()
+        };
+        let instance = Instance::new(&Module::new(&canonical.store()?, FLOAT_WAT)?, &imports! {})?;
+        let nan = instance.exports.get_function("nan")?.call(&[])?;
+        assert_eq!(nan[0], Val::I32(0x7fc0_0000));
+        assert_ne!(reject.cache_key(), canonical.cache_key());
+
+        let bytes = std::fs::read("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        let membrane = WasmMembrane::from_bytes_with_config(bytes.as_slice(), &canonical)?;
+        assert_eq!(membrane.metadata().determinism, Option::Some(Determinism::default()));
+        assert!(membrane.deterministic_sources().is_some());
+        assert!(WasmMembrane::from_bytes(bytes.as_slice())?.metadata().determinism.is_none());
+        Ok(())
+    }
+}
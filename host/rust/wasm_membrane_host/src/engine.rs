@@ -0,0 +1,330 @@
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use wasmer::vm::{self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition};
+use wasmer::wasmparser::Operator;
+use wasmer::{BaseTunables, CompilerConfig, Engine, Features, MemoryType, Pages, Store, TableType, Tunables, JIT};
+use wasmer_middlewares::Metering;
+
+use crate::cache::ModuleCache;
+use crate::determinism::{Determinism, FloatPolicy, RejectNanSensitiveFloats};
+use crate::error::Error;
+
+/// which compiler backend turns guest wasm into native code.
+/// each one is only available when the host crate is built with the matching cargo feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembraneCompiler {
+    /// fast compiles and reasonably fast code (feature "cranelift", on by default)
+    Cranelift,
+    /// very fast linear time compiles with slower code (feature "singlepass")
+    Singlepass,
+    /// slow compiles with the fastest code (feature "llvm")
+    LLVM,
+}
+
+impl Default for MembraneCompiler {
+    #[allow(unreachable_code)]
+    fn default() -> Self {
+        #[cfg(feature = "cranelift")]
+        return MembraneCompiler::Cranelift;
+        #[cfg(feature = "llvm")]
+        return MembraneCompiler::LLVM;
+        MembraneCompiler::Singlepass
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembraneOptLevel {
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+/// settings for the engine that compiles and runs a membrane's module
+#[derive(Debug, Clone)]
+pub struct MembraneEngineConfig {
+    pub compiler: MembraneCompiler,
+    /// ignored by Singlepass which does not optimize
+    pub opt_level: MembraneOptLevel,
+    pub simd: bool,
+    pub threads: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+    /// when set the module is compiled with metering and starts with this much fuel
+    /// (one unit per wasm operator). a guest that runs out traps
+    pub fuel: Option<u64>,
+    /// when set memories may not grow beyond this many 64KiB pages
+    pub max_memory_pages: Option<u32>,
+    /// when set compiled modules are reused from this cache
+    pub cache: Option<Arc<ModuleCache>>,
+    /// when set modules are compiled and membranes created for reproducible runs, see Determinism
+    pub determinism: Option<Determinism>,
+}
+
+impl Default for MembraneEngineConfig {
+    fn default() -> Self {
+        MembraneEngineConfig {
+            compiler: MembraneCompiler::default(),
+            opt_level: MembraneOptLevel::Speed,
+            simd: false,
+            threads: false,
+            bulk_memory: true,
+            reference_types: false,
+            fuel: Option::None,
+            max_memory_pages: Option::None,
+            cache: Option::None,
+            determinism: Option::None,
+        }
+    }
+}
+
+impl MembraneEngineConfig {
+    pub fn new(compiler: MembraneCompiler) -> Self
+    {
+        MembraneEngineConfig {
+            compiler,
+            ..Default::default()
+        }
+    }
+
+    pub fn features(&self) -> Features
+    {
+        let mut features = Features::new();
+        features.simd(self.simd)
+            .threads(self.threads && self.determinism.is_none())
+            .bulk_memory(self.bulk_memory)
+            .reference_types(self.reference_types);
+        features
+    }
+
+    /// create a store with a fresh engine for these settings. with fuel set the store's
+    /// metering middleware can only instrument one module, compile every module on its own store
+    pub fn store(&self) -> Result<Store, Error>
+    {
+        if self.threads && self.determinism.is_some()
+        {
+            return Err("threads can not be enabled in deterministic mode".into());
+        }
+        let engine = JIT::new(self.compiler_config()?).features(self.features()).engine();
+        match self.max_memory_pages
+        {
+            Some(pages) => {
+                let tunables = MemoryLimitTunables {
+                    base: BaseTunables::for_target(engine.target()),
+                    limit: Pages(pages),
+                };
+                Ok(Store::new_with_tunables(&engine, tunables))
+            }
+            None => Ok(Store::new(&engine))
+        }
+    }
+
+    /// describes everything about these settings that changes the compiled artifact.
+    /// used to key the module cache
+    pub fn cache_key(&self) -> String
+    {
+        format!("jit:{:?}:{:?}:simd={}:threads={}:bulk_memory={}:reference_types={}:fuel={:?}:floats={:?}",
+                self.compiler,
+                self.opt_level,
+                self.simd,
+                self.threads,
+                self.bulk_memory,
+                self.reference_types,
+                self.fuel,
+                self.determinism.as_ref().map(|determinism| determinism.floats))
+    }
+
+    fn compiler_config(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        let mut compiler = match self.compiler
+        {
+            MembraneCompiler::Cranelift => self.cranelift()?,
+            MembraneCompiler::Singlepass => self.singlepass()?,
+            MembraneCompiler::LLVM => self.llvm()?,
+        };
+
+        if let Some(fuel) = self.fuel
+        {
+            compiler.push_middleware(Arc::new(Metering::new(fuel, operator_cost)));
+        }
+
+        match self.determinism.as_ref().map(|determinism| determinism.floats)
+        {
+            Some(FloatPolicy::Reject) => compiler.push_middleware(Arc::new(RejectNanSensitiveFloats)),
+            Some(FloatPolicy::CanonicalizeNaNs) if self.compiler == MembraneCompiler::Singlepass => {
+                return Err("the singlepass compiler can not canonicalize NaNs, use FloatPolicy::Reject".into());
+            }
+            // set by the cranelift and llvm constructors, it is not part of CompilerConfig
+            Some(FloatPolicy::CanonicalizeNaNs) | None => {}
+        }
+
+        Ok(compiler)
+    }
+
+    fn canonicalize_nans(&self) -> bool
+    {
+        self.determinism.as_ref().map(|determinism| determinism.floats) == Option::Some(FloatPolicy::CanonicalizeNaNs)
+    }
+
+    #[cfg(feature = "cranelift")]
+    fn cranelift(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        use wasmer::{Cranelift, CraneliftOptLevel};
+        let mut compiler = Cranelift::default();
+        compiler.opt_level(match self.opt_level
+        {
+            MembraneOptLevel::None => CraneliftOptLevel::None,
+            MembraneOptLevel::Speed => CraneliftOptLevel::Speed,
+            MembraneOptLevel::SpeedAndSize => CraneliftOptLevel::SpeedAndSize,
+        });
+        compiler.canonicalize_nans(self.canonicalize_nans());
+        Ok(Box::new(compiler))
+    }
+
+    #[cfg(not(feature = "cranelift"))]
+    fn cranelift(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        Err("the cranelift compiler requires the 'cranelift' feature of wasm_membrane_host".into())
+    }
+
+    #[cfg(feature = "singlepass")]
+    fn singlepass(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        Ok(Box::new(wasmer::Singlepass::default()))
+    }
+
+    #[cfg(not(feature = "singlepass"))]
+    fn singlepass(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        Err("the singlepass compiler requires the 'singlepass' feature of wasm_membrane_host".into())
+    }
+
+    #[cfg(feature = "llvm")]
+    fn llvm(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        use wasmer::{LLVMOptLevel, LLVM};
+        let mut compiler = LLVM::default();
+        compiler.opt_level(match self.opt_level
+        {
+            MembraneOptLevel::None => LLVMOptLevel::None,
+            MembraneOptLevel::Speed => LLVMOptLevel::Aggressive,
+            MembraneOptLevel::SpeedAndSize => LLVMOptLevel::Default,
+        });
+        compiler.canonicalize_nans(self.canonicalize_nans());
+        Ok(Box::new(compiler))
+    }
+
+    #[cfg(not(feature = "llvm"))]
+    fn llvm(&self) -> Result<Box<dyn CompilerConfig>, Error>
+    {
+        Err("the llvm compiler requires the 'llvm' feature of wasm_membrane_host".into())
+    }
+}
+
+fn operator_cost(_operator: &Operator) -> u64
+{
+    1
+}
+
+/// BaseTunables that refuse memories which could grow beyond a page limit.
+/// memories without a declared maximum get the limit as their maximum
+struct MemoryLimitTunables {
+    base: BaseTunables,
+    limit: Pages,
+}
+
+impl MemoryLimitTunables {
+    fn adjust_memory(&self, requested: &MemoryType) -> MemoryType
+    {
+        let mut adjusted = *requested;
+        if requested.maximum.is_none() || requested.maximum.unwrap() > self.limit
+        {
+            adjusted.maximum = Option::Some(self.limit);
+        }
+        adjusted
+    }
+
+    fn validate_memory(&self, ty: &MemoryType) -> Result<(), MemoryError>
+    {
+        if ty.minimum > self.limit
+        {
+            return Err(MemoryError::Generic(format!("memory needs {:?} at minimum which exceeds the limit of {:?}", ty.minimum, self.limit)));
+        }
+        Ok(())
+    }
+}
+
+impl Tunables for MemoryLimitTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(&self.adjust_memory(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(&self, ty: &MemoryType, style: &MemoryStyle) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base.create_host_memory(&adjusted, style)
+    }
+
+    unsafe fn create_vm_memory(&self, ty: &MemoryType, style: &MemoryStyle, vm_definition_location: NonNull<VMMemoryDefinition>) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base.create_vm_memory(&adjusted, style, vm_definition_location)
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(&self, ty: &TableType, style: &TableStyle, vm_definition_location: NonNull<VMTableDefinition>) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use wasmer::{imports, Instance, Module};
+
+    static SIMD_WAT: &str = "(module (func (export \"zero\") (result v128) v128.const i64x2 0 0))";
+
+    #[test]
+    pub fn test_features() -> Result<(), Error>
+    {
+        let config = MembraneEngineConfig::default();
+        assert!(Module::new(&config.store()?, SIMD_WAT).is_err());
+
+        let simd = MembraneEngineConfig {
+            simd: true,
+            ..Default::default()
+        };
+        Module::new(&simd.store()?, SIMD_WAT)?;
+        assert_ne!(config.cache_key(), simd.cache_key());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_limits() -> Result<(), Error>
+    {
+        let limited = MembraneEngineConfig {
+            fuel: Option::Some(1000),
+            max_memory_pages: Option::Some(1),
+            ..Default::default()
+        };
+        // the metering middleware refuses to instrument a second module, so each gets a store
+        let module = Module::new(&limited.store()?, "(module (memory 2))")?;
+        assert!(Instance::new(&module, &imports! {}).is_err());
+
+        let module = Module::new(&limited.store()?, "(module (func (export \"spin\") (loop (br 0))))")?;
+        let instance = Instance::new(&module, &imports! {})?;
+        assert!(instance.exports.get_function("spin")?.call(&[]).is_err());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use wasmer::{CompileError, RuntimeError, ExportError, InstantiationError};
+use wasmer::{CompileError, RuntimeError, ExportError, InstantiationError, SerializeError, DeserializeError, MemoryError};
 use std::fmt::{Formatter, Debug};
 use core::fmt;
 use std::string::FromUtf8Error;
@@ -104,4 +104,28 @@ impl From<InstantiationError> for Error{
             error: format!("{:?}",e)
         }
     }
+}
+
+impl From<SerializeError> for Error{
+    fn from(e: SerializeError) -> Self {
+        Error{
+            error: format!("{:?}",e)
+        }
+    }
+}
+
+impl From<DeserializeError> for Error{
+    fn from(e: DeserializeError) -> Self {
+        Error{
+            error: format!("{:?}",e)
+        }
+    }
+}
+
+impl From<MemoryError> for Error{
+    fn from(e: MemoryError) -> Self {
+        Error{
+            error: format!("{:?}",e)
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// application context attached to a membrane, one value per type. host functions, log
+/// sinks and embedders reach it through WasmMembrane::extensions, for instance to find the
+/// tenant a membrane runs for or a database handle, without global statics.
+/// wrap values that need to change in a Mutex or use atomics
+#[derive(Default)]
+pub struct Extensions {
+    values: RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self
+    {
+        Extensions::default()
+    }
+
+    /// store value, returning the value of the same type it replaced
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<Arc<T>>
+    {
+        let previous = self.values.write().ok()?.insert(TypeId::of::<T>(), Arc::new(value));
+        previous.and_then(|previous| previous.downcast::<T>().ok())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>>
+    {
+        let value = self.values.read().ok()?.get(&TypeId::of::<T>()).cloned();
+        value.and_then(|value| value.downcast::<T>().ok())
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool
+    {
+        self.get::<T>().is_some()
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&self) -> Option<Arc<T>>
+    {
+        let value = self.values.write().ok()?.remove(&TypeId::of::<T>());
+        value.and_then(|value| value.downcast::<T>().ok())
+    }
+
+    /// store every value of other, sharing them rather than cloning
+    pub(crate) fn copy_from(&self, other: &Extensions)
+    {
+        let copied = match other.values.read()
+        {
+            Ok(values) => values.clone(),
+            Err(_) => return
+        };
+        if let Ok(mut values) = self.values.write()
+        {
+            values.extend(copied);
+        }
+    }
+
+    pub fn clear(&self)
+    {
+        if let Ok(mut values) = self.values.write()
+        {
+            values.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.values.read().map(|values| values.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::extensions::Extensions;
+
+    struct Tenant(String);
+
+    #[test]
+    pub fn test_extensions()
+    {
+        let extensions = Extensions::new();
+        assert!(extensions.insert(Tenant("acme".to_string())).is_none());
+        extensions.insert(AtomicUsize::new(0));
+
+        assert_eq!(extensions.get::<Tenant>().unwrap().0, "acme");
+        extensions.get::<AtomicUsize>().unwrap().fetch_add(1, Ordering::Relaxed);
+        assert_eq!(extensions.get::<AtomicUsize>().unwrap().load(Ordering::Relaxed), 1);
+
+        let replaced = extensions.insert(Tenant("globex".to_string()));
+        assert_eq!(replaced.unwrap().0, "acme");
+        assert!(extensions.remove::<Tenant>().is_some());
+        assert!(!extensions.contains::<Tenant>());
+        assert_eq!(extensions.len(), 1);
+
+        extensions.clear();
+        assert!(extensions.is_empty());
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+use crate::membrane::Env;
+
+/// flags of membrane_host_kv_cas telling which of the expected and new buffers hold a value.
+/// a missing expected value means the key must not exist, a missing new value deletes the key
+pub static CAS_EXPECTED: i32 = 1;
+pub static CAS_NEW: i32 = 2;
+
+static KV_MAGIC: &[u8] = b"WMKV\0\0\0\0";
+static KV_FORMAT_VERSION: u32 = 1;
+
+/// where the entries of a KvStore live. namespaces are independent sets of keys, each
+/// membrane gets its own. implementations must apply every method atomically
+pub trait KvBackend: Send + Sync {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), Error>;
+
+    /// returns whether the key existed
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool, Error>;
+
+    /// keys starting with prefix, in ascending order
+    fn list_prefix(&self, namespace: &str, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// set key to new (deleting it for None) only if its current value is expected (absent for None).
+    /// returns whether the swap happened
+    fn compare_and_swap(&self, namespace: &str, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, Error>;
+
+    /// bytes used by the keys and values of namespace
+    fn size(&self, namespace: &str) -> Result<u64, Error>;
+}
+
+type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn entries_size(entries: &Entries) -> u64
+{
+    entries.iter().map(|(key, value)| (key.len() + value.len()) as u64).sum()
+}
+
+fn compare_and_swap(entries: &mut Entries, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> bool
+{
+    if entries.get(key).map(|value| value.as_slice()) != expected
+    {
+        return false;
+    }
+    match new
+    {
+        Some(new) => { entries.insert(key.to_vec(), new.to_vec()); }
+        None => { entries.remove(key); }
+    }
+    true
+}
+
+/// keeps every namespace in memory, gone when the process exits
+#[derive(Default)]
+pub struct MemoryKvBackend {
+    namespaces: Mutex<HashMap<String, Entries>>,
+}
+
+impl MemoryKvBackend {
+    pub fn new() -> Self
+    {
+        MemoryKvBackend::default()
+    }
+}
+
+impl KvBackend for MemoryKvBackend {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>
+    {
+        Ok(self.namespaces.lock()?.get(namespace).and_then(|entries| entries.get(key).cloned()))
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), Error>
+    {
+        self.namespaces.lock()?.entry(namespace.to_string()).or_default().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool, Error>
+    {
+        Ok(self.namespaces.lock()?.get_mut(namespace).and_then(|entries| entries.remove(key)).is_some())
+    }
+
+    fn list_prefix(&self, namespace: &str, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>
+    {
+        Ok(match self.namespaces.lock()?.get(namespace)
+        {
+            Some(entries) => entries.range(prefix.to_vec()..).map(|(key, _)| key).take_while(|key| key.starts_with(prefix)).cloned().collect(),
+            None => vec![]
+        })
+    }
+
+    fn compare_and_swap(&self, namespace: &str, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, Error>
+    {
+        Ok(compare_and_swap(self.namespaces.lock()?.entry(namespace.to_string()).or_default(), key, expected, new))
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, Error>
+    {
+        Ok(self.namespaces.lock()?.get(namespace).map(entries_size).unwrap_or(0))
+    }
+}
+
+/// keeps each namespace in a file of a directory, so state survives restarts of the process.
+/// namespaces are loaded on first use and the whole file is rewritten on every change,
+/// which suits the small amounts of state plugins keep
+pub struct FileKvBackend {
+    dir: PathBuf,
+    namespaces: Mutex<HashMap<String, Entries>>,
+}
+
+impl FileKvBackend {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error>
+    {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(FileKvBackend {
+            dir: dir.as_ref().to_path_buf(),
+            namespaces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn dir(&self) -> &Path
+    {
+        self.dir.as_path()
+    }
+
+    /// namespaces are hex encoded so that any name makes a valid file name inside dir
+    fn path(&self, namespace: &str) -> PathBuf
+    {
+        let name: String = namespace.as_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(format!("{}.kv", name))
+    }
+
+    fn with_entries<R, F>(&self, namespace: &str, f: F) -> Result<R, Error>
+        where F: FnOnce(&mut Entries) -> (R, bool)
+    {
+        let mut namespaces = self.namespaces.lock()?;
+        if !namespaces.contains_key(namespace)
+        {
+            let entries = self.load(namespace)?;
+            namespaces.insert(namespace.to_string(), entries);
+        }
+        let entries = namespaces.get_mut(namespace).ok_or("namespace was not loaded")?;
+        let (result, changed) = f(entries);
+        if changed
+        {
+            if let Err(error) = self.store(namespace, entries)
+            {
+                // forget the change, the namespace is loaded from the file again on next use
+                namespaces.remove(namespace);
+                return Err(error);
+            }
+        }
+        Ok(result)
+    }
+
+    fn load(&self, namespace: &str) -> Result<Entries, Error>
+    {
+        let path = self.path(namespace);
+        if !path.exists()
+        {
+            return Ok(Entries::new());
+        }
+        let data = fs::read(&path)?;
+        if !data.starts_with(KV_MAGIC)
+        {
+            return Err(format!("{} is not a kv file", path.display()).into());
+        }
+        let mut offset = KV_MAGIC.len();
+        if read_u32(data.as_slice(), &mut offset)? != KV_FORMAT_VERSION
+        {
+            return Err(format!("{} has an unsupported format version", path.display()).into());
+        }
+        let mut entries = Entries::new();
+        while offset < data.len()
+        {
+            let key = read_bytes(data.as_slice(), &mut offset)?;
+            let value = read_bytes(data.as_slice(), &mut offset)?;
+            entries.insert(key, value);
+        }
+        Ok(entries)
+    }
+
+    fn store(&self, namespace: &str, entries: &Entries) -> Result<(), Error>
+    {
+        let path = self.path(namespace);
+        let mut data = Vec::with_capacity(entries_size(entries) as usize + 8 * entries.len() + 12);
+        data.extend_from_slice(KV_MAGIC);
+        data.extend_from_slice(&KV_FORMAT_VERSION.to_le_bytes());
+        for (key, value) in entries
+        {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+
+        // write then rename so that a crash never leaves a partial file behind
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(data.as_slice())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn read_u32(data: &[u8], offset: &mut usize) -> Result<u32, Error>
+{
+    let bytes = data.get(*offset..*offset + 4).ok_or("kv file is truncated")?;
+    let mut array = [0u8; 4];
+    array.copy_from_slice(bytes);
+    *offset += 4;
+    Ok(u32::from_le_bytes(array))
+}
+
+fn read_bytes(data: &[u8], offset: &mut usize) -> Result<Vec<u8>, Error>
+{
+    let len = read_u32(data, offset)? as usize;
+    let bytes = data.get(*offset..*offset + len).ok_or("kv file is truncated")?;
+    *offset += len;
+    Ok(bytes.to_vec())
+}
+
+impl KvBackend for FileKvBackend {
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>
+    {
+        self.with_entries(namespace, |entries| (entries.get(key).cloned(), false))
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), Error>
+    {
+        self.with_entries(namespace, |entries| {
+            let changed = entries.get(key).map(|current| current.as_slice()) != Some(value);
+            entries.insert(key.to_vec(), value.to_vec());
+            ((), changed)
+        })
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool, Error>
+    {
+        self.with_entries(namespace, |entries| {
+            let existed = entries.remove(key).is_some();
+            (existed, existed)
+        })
+    }
+
+    fn list_prefix(&self, namespace: &str, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>
+    {
+        self.with_entries(namespace, |entries| {
+            (entries.range(prefix.to_vec()..).map(|(key, _)| key).take_while(|key| key.starts_with(prefix)).cloned().collect(), false)
+        })
+    }
+
+    fn compare_and_swap(&self, namespace: &str, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, Error>
+    {
+        self.with_entries(namespace, |entries| {
+            let swapped = compare_and_swap(entries, key, expected, new);
+            (swapped, swapped)
+        })
+    }
+
+    fn size(&self, namespace: &str) -> Result<u64, Error>
+    {
+        self.with_entries(namespace, |entries| (entries_size(entries), false))
+    }
+}
+
+/// a KvBackend with size quotas. hand a namespace of it to each membrane with
+/// WasmMembrane::grant_kv
+pub struct KvStore {
+    backend: Arc<dyn KvBackend>,
+    default_quota: Option<u64>,
+    quotas: Mutex<HashMap<String, u64>>,
+    // writes are serialized so that the quota check and the write cannot interleave
+    write_lock: Mutex<()>,
+}
+
+impl KvStore {
+    pub fn new(backend: Arc<dyn KvBackend>) -> Self
+    {
+        KvStore {
+            backend,
+            default_quota: Option::None,
+            quotas: Mutex::new(HashMap::new()),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// the most bytes of keys and values any namespace without its own quota may hold
+    pub fn with_default_quota(mut self, bytes: u64) -> Self
+    {
+        self.default_quota = Option::Some(bytes);
+        self
+    }
+
+    /// writes that would take namespace over bytes are refused. lowering a quota does not
+    /// remove entries already stored
+    pub fn set_quota(&self, namespace: &str, bytes: u64) -> Result<(), Error>
+    {
+        self.quotas.lock()?.insert(namespace.to_string(), bytes);
+        Ok(())
+    }
+
+    pub fn quota(&self, namespace: &str) -> Result<Option<u64>, Error>
+    {
+        Ok(self.quotas.lock()?.get(namespace).cloned().or(self.default_quota))
+    }
+
+    pub fn backend(&self) -> Arc<dyn KvBackend>
+    {
+        self.backend.clone()
+    }
+
+    pub fn namespace(self: &Arc<Self>, namespace: &str) -> KvNamespace
+    {
+        KvNamespace {
+            store: self.clone(),
+            namespace: namespace.to_string(),
+        }
+    }
+
+    /// fail if replacing the value of key with new would take namespace over its quota
+    fn check_quota(&self, namespace: &str, key: &[u8], new: Option<&[u8]>) -> Result<(), Error>
+    {
+        let quota = match self.quota(namespace)?
+        {
+            Some(quota) => quota,
+            None => return Ok(())
+        };
+        let current = self.backend.get(namespace, key)?.map(|value| (key.len() + value.len()) as u64).unwrap_or(0);
+        let next = new.map(|value| (key.len() + value.len()) as u64).unwrap_or(0);
+        let size = self.backend.size(namespace)? - current + next;
+        if next > current && size > quota
+        {
+            return Err(format!("kv namespace {} would hold {} bytes, over its quota of {}", namespace, size, quota).into());
+        }
+        Ok(())
+    }
+}
+
+/// the part of a KvStore one membrane sees
+#[derive(Clone)]
+pub struct KvNamespace {
+    store: Arc<KvStore>,
+    namespace: String,
+}
+
+impl KvNamespace {
+    pub fn name(&self) -> &str
+    {
+        self.namespace.as_str()
+    }
+
+    pub fn store(&self) -> Arc<KvStore>
+    {
+        self.store.clone()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>
+    {
+        self.store.backend.get(self.name(), key)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error>
+    {
+        let _write = self.store.write_lock.lock()?;
+        self.store.check_quota(self.name(), key, Option::Some(value))?;
+        self.store.backend.put(self.name(), key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<bool, Error>
+    {
+        let _write = self.store.write_lock.lock()?;
+        self.store.backend.delete(self.name(), key)
+    }
+
+    pub fn list_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error>
+    {
+        self.store.backend.list_prefix(self.name(), prefix)
+    }
+
+    pub fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, Error>
+    {
+        let _write = self.store.write_lock.lock()?;
+        if self.store.backend.get(self.name(), key)?.as_deref() != expected
+        {
+            return Ok(false);
+        }
+        self.store.check_quota(self.name(), key, new)?;
+        self.store.backend.compare_and_swap(self.name(), key, expected, new)
+    }
+
+    pub fn size(&self) -> Result<u64, Error>
+    {
+        self.store.backend.size(self.name())
+    }
+}
+
+// host imports, see membrane_host_kv_* in the guest crate. values and key lists travel as
+// length prefixed byte strings
+
+fn encode_list(items: &[Vec<u8>]) -> Vec<u8>
+{
+    let mut data = Vec::new();
+    for item in items
+    {
+        data.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        data.extend_from_slice(item);
+    }
+    data
+}
+
+/// responds with a presence byte followed by the value
+pub(crate) fn host_kv_get(env: &Env, key_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let key = membrane.consume_buffer(key_buffer)?;
+        Ok(match membrane.kv()?.get(key.as_slice())?
+        {
+            Some(value) => {
+                let mut data = vec![1u8];
+                data.extend_from_slice(value.as_slice());
+                data
+            }
+            None => vec![0u8]
+        })
+    })
+}
+
+pub(crate) fn host_kv_put(env: &Env, key_buffer: i32, value_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let key = membrane.consume_buffer(key_buffer)?;
+        let value = membrane.consume_buffer(value_buffer)?;
+        membrane.kv()?.put(key.as_slice(), value.as_slice())?;
+        Ok(vec![])
+    })
+}
+
+pub(crate) fn host_kv_delete(env: &Env, key_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let key = membrane.consume_buffer(key_buffer)?;
+        Ok(vec![membrane.kv()?.delete(key.as_slice())? as u8])
+    })
+}
+
+pub(crate) fn host_kv_list(env: &Env, prefix_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let prefix = membrane.consume_buffer(prefix_buffer)?;
+        Ok(encode_list(membrane.kv()?.list_prefix(prefix.as_slice())?.as_slice()))
+    })
+}
+
+/// both value buffers are always written by the guest, flags says which of them count
+pub(crate) fn host_kv_cas(env: &Env, key_buffer: i32, expected_buffer: i32, new_buffer: i32, flags: i32) -> i32
+{
+    env.respond(|membrane| {
+        let key = membrane.consume_buffer(key_buffer)?;
+        let expected = membrane.consume_buffer(expected_buffer)?;
+        let new = membrane.consume_buffer(new_buffer)?;
+        let expected = if flags & CAS_EXPECTED != 0 { Option::Some(expected.as_slice()) } else { Option::None };
+        let new = if flags & CAS_NEW != 0 { Option::Some(new.as_slice()) } else { Option::None };
+        Ok(vec![membrane.kv()?.compare_and_swap(key.as_slice(), expected, new)? as u8])
+    })
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::Arc;
+
+    use crate::error::Error;
+    use crate::kv::{FileKvBackend, KvBackend, KvStore, MemoryKvBackend};
+    use crate::membrane::WasmMembrane;
+
+    fn exercise(backend: Arc<dyn KvBackend>) -> Result<(), Error>
+    {
+        let store = Arc::new(KvStore::new(backend));
+        let a = store.namespace("a");
+        let b = store.namespace("b");
+
+        a.put(b"user/1", b"alice")?;
+        a.put(b"user/2", b"bob")?;
+        a.put(b"session", b"x")?;
+        assert_eq!(a.get(b"user/1")?, Option::Some(b"alice".to_vec()));
+        assert_eq!(b.get(b"user/1")?, Option::None);
+        assert_eq!(a.list_prefix(b"user/")?, vec![b"user/1".to_vec(), b"user/2".to_vec()]);
+
+        assert!(!a.compare_and_swap(b"user/1", Option::Some(b"bob"), Option::Some(b"carol"))?);
+        assert!(a.compare_and_swap(b"user/1", Option::Some(b"alice"), Option::Some(b"carol"))?);
+        assert!(a.compare_and_swap(b"counter", Option::None, Option::Some(b"1"))?);
+        assert!(!a.compare_and_swap(b"counter", Option::None, Option::Some(b"1"))?);
+        assert!(a.delete(b"session")?);
+        assert!(!a.delete(b"session")?);
+        assert_eq!(a.size()?, (6 + 5) + (6 + 3) + (7 + 1));
+
+        store.set_quota("b", 8)?;
+        b.put(b"key", b"12345")?;
+        assert!(b.put(b"key", b"123456").is_err());
+        assert!(b.put(b"other", b"1").is_err());
+        // shrinking stays possible over quota
+        b.put(b"key", b"1")?;
+        assert_eq!(b.get(b"key")?, Option::Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_memory_kv() -> Result<(), Error>
+    {
+        exercise(Arc::new(MemoryKvBackend::new()))
+    }
+
+    #[test]
+    pub fn test_file_kv() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(format!("wasm_membrane_kv_test_{}", std::process::id()));
+        exercise(Arc::new(FileKvBackend::new(&dir)?))?;
+
+        // a new backend over the same directory sees what the first one stored
+        let reopened = FileKvBackend::new(&dir)?;
+        assert_eq!(reopened.get("a", b"user/1")?, Option::Some(b"carol".to_vec()));
+        assert_eq!(reopened.get("a", b"counter")?, Option::Some(b"1".to_vec()));
+        assert_eq!(reopened.get("a", b"session")?, Option::None);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_guest_kv() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        assert!(membrane.emit("count", b"").is_err());
+
+        let store = Arc::new(KvStore::new(Arc::new(MemoryKvBackend::new())));
+        membrane.grant_kv(Option::Some(store.namespace("example")))?;
+        membrane.emit("count", b"")?;
+        membrane.emit("count", b"")?;
+        assert_eq!(store.namespace("example").get(b"count")?, Option::Some(2u32.to_le_bytes().to_vec()));
+        assert_eq!(store.namespace("other").get(b"count")?, Option::None);
+        Ok(())
+    }
+}
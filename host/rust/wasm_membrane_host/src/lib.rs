@@ -1,2 +1,24 @@
 pub mod membrane;
 pub mod error;
+pub mod repl;
+pub mod cache;
+pub mod engine;
+pub mod pool;
+pub mod snapshot;
+pub mod reload;
+pub mod plugin;
+pub mod supervisor;
+pub mod bus;
+pub mod actor;
+#[cfg(feature = "async")]
+pub mod async_membrane;
+pub mod extensions;
+pub mod resource;
+pub mod vfs;
+pub mod kv;
+#[cfg(feature = "wasi")]
+pub mod wasi;
+pub mod determinism;
+pub mod time;
+pub mod trace;
+pub mod metrics;
\ No newline at end of file
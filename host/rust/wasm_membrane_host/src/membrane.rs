@@ -1,19 +1,184 @@
-use std::sync::{Arc, RwLock, Weak};
-
-
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+use crate::bus::MessageBus;
+use crate::determinism::{Determinism, DeterministicSources};
+use crate::engine::MembraneEngineConfig;
 use crate::error::Error;
-use wasmer::{Module, Instance, WasmPtr, Array, WasmerEnv, imports, Function, RuntimeError};
+use crate::extensions::Extensions;
+use crate::kv::{self, KvNamespace};
+use crate::metrics::{self, CallKind, Metrics, MetricsSnapshot};
+use crate::resource::ResourceTable;
+use crate::vfs::{self, Vfs};
+use crate::snapshot::{GlobalValue, MembraneSnapshot};
+use crate::time::{self, Clock, SystemClock, Timer};
+use crate::trace::{self, Recorder, Replayer, Trace, TraceEvent, TraceMode};
+#[cfg(feature = "wasi")]
+use crate::wasi::{self, WasiConfig};
+#[cfg(feature = "wasi")]
+use wasmer::ChainableNamedResolver;
+use wasmer::{Module, Instance, ImportObject, WasmPtr, Array, WasmerEnv, imports, Function, RuntimeError, Val, ExternType, JITArtifact, Extern, Mutability, Pages, WASM_PAGE_SIZE};
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 
 pub static VERSION: i32 = 1;
 
+/// the most bytes a host import allocates on the guest's behalf in one call, so that a guest
+/// cannot make the host allocate arbitrary amounts by passing a large length
+pub static MAX_HOST_ALLOCATION: usize = 64 * 1024;
+
+/// one of the globals wasmer_middlewares::Metering adds to a module, its presence tells
+/// whether the metering functions can be used on an instance
+static METERING_EXHAUSTED_GLOBAL: &str = "wasmer_metering_points_exhausted";
+
+/// receives every log line produced by the membrane: log_type is "wasm" for host side
+/// verification messages, "guest" for messages sent by membrane_host_log and "stdout" or
+/// "stderr" for lines a WASI guest printed when WasiConfig captures them
+pub type LogSink = Arc<dyn Fn(&WasmMembrane, &str, &str) + Send + Sync>;
+
+/// a function the guest calls by name through membrane_host_call, taking and returning bytes.
+/// it receives the calling membrane so it can reach the membrane's extensions
+pub type HostFunction = Arc<dyn Fn(&WasmMembrane, &[u8]) -> Result<Vec<u8>, Error> + Send + Sync>;
+
+/// a membrane_host_call_async the guest is waiting on. the host answers it with
+/// WasmMembrane::complete once the result is known
+#[derive(Debug, Clone)]
+pub struct PendingCall {
+    pub handle: i32,
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+/// Created -> Initialized -> Running -> Poisoned / ShutDown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembraneState {
+    /// instantiated but init has not passed yet. only init may call into the guest
+    Created,
+    /// init passed, no other call has been made yet
+    Initialized,
+    /// at least one call has been made since init
+    Running,
+    /// a call trapped or init failed. the guest's memory may be inconsistent so no more calls are allowed
+    Poisoned,
+    /// shutdown ran, no more calls are allowed
+    ShutDown,
+}
+
+impl MembraneState {
+    pub fn to_u8(&self) -> u8
+    {
+        match self
+        {
+            MembraneState::Created => 0,
+            MembraneState::Initialized => 1,
+            MembraneState::Running => 2,
+            MembraneState::Poisoned => 3,
+            MembraneState::ShutDown => 4,
+        }
+    }
+
+    pub fn from_u8(state: u8) -> Result<Self, Error>
+    {
+        match state
+        {
+            0 => Ok(MembraneState::Created),
+            1 => Ok(MembraneState::Initialized),
+            2 => Ok(MembraneState::Running),
+            3 => Ok(MembraneState::Poisoned),
+            4 => Ok(MembraneState::ShutDown),
+            state => Err(format!("unknown membrane state {}", state).into())
+        }
+    }
+}
+
+/// how a membrane was set up, for diagnostics and for comparing runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MembraneMetadata {
+    /// MembraneEngineConfig::cache_key of the config the membrane was created with,
+    /// None when it was created from a module without its config
+    pub engine_settings: Option<String>,
+    /// the active determinism settings, None outside deterministic mode
+    pub determinism: Option<Determinism>,
+}
+
 pub struct WasmMembrane {
     pub instance: Instance,
-    //host: Arc<RwLock<WasmHost>>,
+    log_sink: RwLock<Option<LogSink>>,
+    extensions: Arc<Extensions>,
+    resources: ResourceTable,
+    filesystem: RwLock<Option<Arc<dyn Vfs>>>,
+    kv: RwLock<Option<KvNamespace>>,
+    metadata: MembraneMetadata,
+    /// identifies the module the membrane was created from, see module_fingerprint
+    fingerprint: [u8;32],
+    deterministic_sources: Option<DeterministicSources>,
+    /// what init_with_config was given, so that a reload can init the new instance alike
+    init_config: RwLock<Option<Vec<u8>>>,
+    clock: RwLock<Arc<dyn Clock>>,
+    trace: RwLock<Option<TraceMode>>,
+    metrics: Metrics,
+    state: RwLock<MembraneState>,
+    initializing: AtomicBool,
+    host: Arc<RwLock<WasmHost>>,
 }
 
 impl WasmMembrane {
 
     pub fn init(&self)->Result<(),Error>
+    {
+        self.init_with_config(None)
+    }
+
+    /// init with a configuration serialized as json, for instance a HashMap<String,String>
+    /// or any struct the guest knows how to deserialize
+    pub fn init_with_json<T: Serialize>(&self, config: &T)->Result<(),Error>
+    {
+        let config = serde_json::to_vec(config).map_err(|e| format!("could not serialize init config: {}", e))?;
+        self.init_with_config(Some(config.as_slice()))
+    }
+
+    /// verify the guest implements the membrane protocol and initialize it.
+    ///
+    /// guests that export membrane_guest_init_with_config( i32 ) -> i32 receive config in a buffer
+    /// they take ownership of (or -1 when there is none) and return -1 on success or the id
+    /// of a buffer holding an error message. otherwise membrane_guest_init() is called and the
+    /// config is not delivered
+    pub fn init_with_config(&self, config: Option<&[u8]>)->Result<(),Error>
+    {
+        if self.state() != MembraneState::Created
+        {
+            return Err(format!("cannot init a membrane in state {:?}", self.state()).into());
+        }
+
+        *self.init_config.write()? = config.map(|config| config.to_vec());
+        self.initializing.store(true, Ordering::Relaxed);
+        let result = self.run_init(config);
+        self.initializing.store(false, Ordering::Relaxed);
+
+        self.set_state(match result
+        {
+            Ok(_) => MembraneState::Initialized,
+            Err(_) => MembraneState::Poisoned
+        })?;
+        result
+    }
+
+    /// the config the membrane was initialized with, None before init or when there was none
+    pub fn init_config(&self)->Option<Vec<u8>>
+    {
+        self.init_config.read().ok()?.clone()
+    }
+
+    fn run_init(&self, config: Option<&[u8]>)->Result<(),Error>
     {
         let mut pass = true;
         match self.instance.exports.get_memory("memory")
@@ -28,9 +193,9 @@ impl WasmMembrane {
         }
 
         match self.instance.exports.get_native_function::<(),i32>("membrane_guest_version"){
-            Ok(func) => {
+            Ok(_) => {
                 self.log("wasm", "verified: membrane_guest_version( ) -> i32");
-                match func.call()
+                match self.call_guest("membrane_guest_version", &[]).and_then(|results| single_i32(&results))
                 {
                     Ok(version) => {
                         if version == VERSION
@@ -93,26 +258,63 @@ impl WasmMembrane {
             }
         }
 
-        match self.instance.exports.get_native_function::<(),()>("membrane_guest_init"){
+        let mut init_error = Option::None;
+        match self.instance.exports.get_native_function::<i32,i32>("membrane_guest_init_with_config"){
 
-            Ok(func) => {
-                self.log("wasm", "verified: membrane_guest_init()");
+            Ok(_) => {
+                self.log("wasm", "verified: membrane_guest_init_with_config( i32 ) -> i32");
 
-                match func.call()
+                let config_buffer = match config
                 {
-                    Ok(_) => {
-                        self.log("wasm", "passed: membrane_guest_init()");
+                    Some(config) => self.write_buffer(&config.to_vec()),
+                    None => Ok(-1)
+                };
+
+                match config_buffer.and_then(|config_buffer| self.call_guest("membrane_guest_init_with_config", &[Val::I32(config_buffer)])).and_then(|results| single_i32(&results))
+                {
+                    Ok(-1) => {
+                        self.log("wasm", "passed: membrane_guest_init_with_config( i32 ) -> i32");
+                    }
+                    Ok(error_buffer) => {
+                        self.metrics.export_failed();
+                        let message = self.consume_string(error_buffer).unwrap_or("INIT ERROR".to_string());
+                        self.log("wasm", format!("failed: membrane_guest_init_with_config( i32 ) -> i32 GUEST ERROR: {}",message).as_str());
+                        init_error = Option::Some(message);
+                        pass = false;
                     }
                     Err(error) => {
-
-                        self.log("wasm", format!("failed: membrane_guest_init() ERROR: {:?}",error).as_str());
+                        self.log("wasm", format!("failed: membrane_guest_init_with_config( i32 ) -> i32 ERROR: {:?}",error).as_str());
                         pass = false;
                     }
                 }
-
             }
             Err(_) => {
-                self.log("wasm", "failed: membrane_guest_init() [NOT REQUIRED]");
+                match self.instance.exports.get_native_function::<(),()>("membrane_guest_init"){
+
+                    Ok(_) => {
+                        self.log("wasm", "verified: membrane_guest_init()");
+                        if config.is_some()
+                        {
+                            self.log("wasm", "warning: membrane_guest_init() takes no config, implement membrane_guest_init_with_config( i32 ) -> i32 to receive it");
+                        }
+
+                        match self.call_guest("membrane_guest_init", &[])
+                        {
+                            Ok(_) => {
+                                self.log("wasm", "passed: membrane_guest_init()");
+                            }
+                            Err(error) => {
+
+                                self.log("wasm", format!("failed: membrane_guest_init() ERROR: {:?}",error).as_str());
+                                pass = false;
+                            }
+                        }
+
+                    }
+                    Err(_) => {
+                        self.log("wasm", "failed: membrane_guest_init() [NOT REQUIRED]");
+                    }
+                }
             }
         }
 
@@ -127,34 +329,726 @@ impl WasmMembrane {
                     self.log("wasm", format!("failed: write_string() test {:?}", e).as_str());
                     pass = false;
 
-                }
-            };
+                }
+            };
+        }
+
+        match (pass, init_error){
+            (true, _) => Ok(()),
+            (false, Some(init_error)) => Err(format!("init failed: {}", init_error).into()),
+            (false, None) => Err("init failed".into())
+        }
+
+    }
+
+    pub fn log( &self, log_type:&str, message: &str )
+    {
+        match self.log_sink()
+        {
+            Some(sink) => sink(self, log_type, message),
+            None => println!("{} : {}",log_type,message)
+        }
+    }
+
+    /// replace the destination of log lines. passing None restores printing to stdout
+    pub fn set_log_sink( &self, sink: Option<LogSink> )->Result<(),Error>
+    {
+        *self.log_sink.write()? = sink;
+        Ok(())
+    }
+
+    pub fn log_sink(&self)->Option<LogSink>
+    {
+        match self.log_sink.read()
+        {
+            Ok(sink) => sink.clone(),
+            Err(_) => None
+        }
+    }
+
+    /// application context attached to this membrane, see Extensions
+    pub fn extensions(&self)->Arc<Extensions>
+    {
+        self.extensions.clone()
+    }
+
+    /// host objects handed to the guest by handle, see ResourceTable
+    pub fn resources(&self)->&ResourceTable
+    {
+        &self.resources
+    }
+
+    /// give the guest the filesystem capability backed by vfs, or take it away with None.
+    /// files the guest already opened stay open
+    pub fn grant_filesystem(&self, vfs: Option<Arc<dyn Vfs>> )->Result<(),Error>
+    {
+        *self.filesystem.write()? = vfs;
+        Ok(())
+    }
+
+    pub fn filesystem(&self)->Result<Arc<dyn Vfs>,Error>
+    {
+        match &*self.filesystem.read()?
+        {
+            Some(vfs) => Ok(vfs.clone()),
+            None => Err("this membrane was not granted the filesystem capability".into())
+        }
+    }
+
+    /// give the guest the key-value capability over namespace, or take it away with None.
+    /// give every membrane its own namespace, membranes sharing one see each other's keys
+    pub fn grant_kv(&self, namespace: Option<KvNamespace> )->Result<(),Error>
+    {
+        *self.kv.write()? = namespace;
+        Ok(())
+    }
+
+    pub fn kv(&self)->Result<KvNamespace,Error>
+    {
+        match &*self.kv.read()?
+        {
+            Some(namespace) => Ok(namespace.clone()),
+            None => Err("this membrane was not granted the key-value capability".into())
+        }
+    }
+
+    pub fn metadata(&self)->&MembraneMetadata
+    {
+        &self.metadata
+    }
+
+    /// the virtual clock and seeded random source of a deterministic membrane
+    pub fn deterministic_sources(&self)->Option<&DeterministicSources>
+    {
+        self.deterministic_sources.as_ref()
+    }
+
+    /// the clock the guest reads through membrane_host_time_*. the system clock unless
+    /// set_clock replaced it, the virtual clock for deterministic membranes
+    pub fn clock(&self)->Result<Arc<dyn Clock>,Error>
+    {
+        Ok(self.clock.read()?.clone())
+    }
+
+    /// timers already started keep their deadlines, which are read against the new clock
+    pub fn set_clock(&self, clock: Arc<dyn Clock> )->Result<(),Error>
+    {
+        *self.clock.write()? = clock;
+        Ok(())
+    }
+
+    /// len bytes from the operating system's CSPRNG, or from the seeded source of a
+    /// deterministic membrane
+    pub fn random_bytes(&self, len: usize )->Result<Vec<u8>,Error>
+    {
+        let mut bytes = vec![0u8; len];
+        match &self.deterministic_sources
+        {
+            Some(sources) => sources.random.lock()?.fill(bytes.as_mut_slice()),
+            None => getrandom::getrandom(bytes.as_mut_slice()).map_err(|e| format!("could not get random bytes: {}", e))?
+        }
+        Ok(bytes)
+    }
+
+    /// the monotonic time at which the earliest of the guest's timers fires
+    pub fn next_timer(&self)->Option<Duration>
+    {
+        self.host.read().ok()?.timers.values().map(|timer| timer.deadline).min()
+    }
+
+    /// fire every timer that is due by calling the guest's membrane_guest_on_timer( i32 ).
+    /// repeating timers are rescheduled, one-shot timers removed. call it whenever next_timer
+    /// has passed. returns how many timers fired
+    pub fn run_timers(&self)->Result<usize,Error>
+    {
+        let now = self.clock()?.monotonic();
+        let due: Vec<i32> = {
+            let mut host = self.host.write()?;
+            let mut due: Vec<(Duration,i32)> = host.timers.iter()
+                .filter(|(_, timer)| timer.deadline <= now)
+                .map(|(id, timer)| (timer.deadline, *id))
+                .collect();
+            due.sort();
+            for (_, id) in &due
+            {
+                let repeat = match host.timers.get_mut(id)
+                {
+                    Some(timer) => match timer.interval
+                    {
+                        Some(interval) => {
+                            timer.deadline = (timer.deadline + interval).max(now + interval);
+                            true
+                        }
+                        None => false
+                    },
+                    None => false
+                };
+                if !repeat
+                {
+                    host.timers.remove(id);
+                }
+            }
+            due.into_iter().map(|(_, id)| id).collect()
+        };
+
+        if due.is_empty()
+        {
+            return Ok(0);
+        }
+        self.instance.exports.get_native_function::<i32,()>("membrane_guest_on_timer")?;
+        for id in &due
+        {
+            self.enter()?;
+            self.call_guest("membrane_guest_on_timer", &[Val::I32(*id)])?;
+        }
+        Ok(due.len())
+    }
+
+    /// list every export of the instance along with its type
+    pub fn exports(&self)->Vec<(String,ExternType)>
+    {
+        self.instance.exports.iter().map(|(name,export)| (name.clone(), export.ty())).collect()
+    }
+
+    pub fn state(&self)->MembraneState
+    {
+        match self.state.read()
+        {
+            Ok(state) => *state,
+            Err(_) => MembraneState::Poisoned
+        }
+    }
+
+    fn set_state(&self, state: MembraneState)->Result<(),Error>
+    {
+        *self.state.write()? = state;
+        Ok(())
+    }
+
+    /// true once any call into the guest has trapped. the guest's memory may be in an
+    /// inconsistent state after a trap so the membrane should not be trusted afterwards
+    pub fn trapped(&self)->bool
+    {
+        self.state() == MembraneState::Poisoned
+    }
+
+    /// reject calls in states that do not allow them and move Initialized to Running
+    fn enter(&self)->Result<(),Error>
+    {
+        let mut state = self.state.write()?;
+        match *state
+        {
+            MembraneState::Initialized => {
+                *state = MembraneState::Running;
+                Ok(())
+            }
+            MembraneState::Running => Ok(()),
+            MembraneState::Created if self.initializing.load(Ordering::Relaxed) => Ok(()),
+            MembraneState::Created => Err("membrane has not been initialized".into()),
+            MembraneState::Poisoned => Err("membrane is poisoned by an earlier trap".into()),
+            MembraneState::ShutDown => Err("membrane has been shut down".into())
+        }
+    }
+
+    fn check_trap<T>(&self, result: Result<T,RuntimeError>)->Result<T,Error>
+    {
+        match result
+        {
+            Ok(rtn) => Ok(rtn),
+            Err(error) => {
+                self.set_state(MembraneState::Poisoned).unwrap_or(());
+                Err(error.into())
+            }
+        }
+    }
+
+    /// call the guest's optional membrane_guest_shutdown() export so it can flush its state,
+    /// then refuse any further calls. the guest gets timeout to finish, after that the
+    /// membrane is marked ShutDown anyway and an error is returned while the call keeps
+    /// running on its own thread until it returns or traps
+    pub fn shutdown(self: &Arc<Self>, timeout: Duration)->Result<(),Error>
+    {
+        match self.state()
+        {
+            MembraneState::ShutDown => return Ok(()),
+            MembraneState::Initialized | MembraneState::Running => {}
+            // nothing to flush in a guest that never initialized and nothing to trust in a poisoned one
+            _ => return self.shut_down()
+        }
+
+        if self.instance.exports.get_native_function::<(),()>("membrane_guest_shutdown").is_err()
+        {
+            return self.shut_down();
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let membrane = self.clone();
+        thread::spawn(move || {
+            sender.send(membrane.call_shutdown()).unwrap_or(());
+        });
+
+        let result = match receiver.recv_timeout(timeout)
+        {
+            Ok(result) => result,
+            Err(_) => Err(format!("membrane_guest_shutdown() did not finish within {:?}", timeout).into())
+        };
+        self.shut_down()?;
+        result
+    }
+
+    /// mark the membrane ShutDown and let go of the host resources the guest held
+    fn shut_down(&self)->Result<(),Error>
+    {
+        self.set_state(MembraneState::ShutDown)?;
+        self.host.write()?.timers.clear();
+        self.resources.clear()
+    }
+
+    /// take back everything the host attached to the membrane since creation: the log sink,
+    /// extensions, host functions, clock, trace mode, bus registration, the filesystem and
+    /// key-value grants, resources, timers and unanswered async calls
+    pub(crate) fn clear_host_state(&self)->Result<(),Error>
+    {
+        self.set_log_sink(Option::None)?;
+        self.extensions.clear();
+        self.set_clock(default_clock(&self.deterministic_sources))?;
+        *self.trace.write()? = Option::None;
+        self.grant_filesystem(Option::None)?;
+        self.grant_kv(Option::None)?;
+        let bus = {
+            let mut host = self.host.write()?;
+            host.functions.clear();
+            host.timers.clear();
+            host.pending_calls.clear();
+            host.bus.take()
+        };
+        // unregister outside the host lock since the bus detaches the membrane through it
+        if let Some((bus, name)) = bus
+        {
+            if let Some(bus) = bus.upgrade()
+            {
+                bus.unregister(name.as_str())?;
+            }
+        }
+        self.resources.clear()
+    }
+
+    fn call_shutdown(&self)->Result<(),Error>
+    {
+        self.enter()?;
+        self.log("wasm", "calling: membrane_guest_shutdown()");
+        self.instance.exports.get_native_function::<(),()>("membrane_guest_shutdown")?;
+        self.call_guest("membrane_guest_shutdown", &[])?;
+        Ok(())
+    }
+
+    /// fuel left when the module was compiled with MembraneEngineConfig::fuel, otherwise None
+    pub fn remaining_fuel(&self)->Option<u64>
+    {
+        if !self.metered()
+        {
+            return None;
+        }
+        match get_remaining_points(&self.instance)
+        {
+            MeteringPoints::Remaining(fuel) => Some(fuel),
+            MeteringPoints::Exhausted => Some(0)
+        }
+    }
+
+    /// top the fuel back up, usually before handing the membrane a new unit of work.
+    /// this also clears the exhausted flag of a guest that ran out
+    pub fn refuel(&self, fuel: u64)->Result<(),Error>
+    {
+        if !self.metered()
+        {
+            return Err("this membrane was not compiled with fuel metering".into());
+        }
+        set_remaining_points(&self.instance, fuel);
+        Ok(())
+    }
+
+    /// the metering functions panic on instances compiled without the middleware
+    fn metered(&self)->bool
+    {
+        self.instance.exports.get_global(METERING_EXHAUSTED_GLOBAL).is_ok()
+    }
+
+    /// take all fuel away so a running guest traps at its next metering check. this is the only
+    /// way to stop a call that is already running, the membrane is poisoned afterwards.
+    /// fails when the membrane was not compiled with fuel metering
+    pub fn interrupt(&self)->Result<(),Error>
+    {
+        self.refuel(0)
+    }
+
+    /// capture the guest's linear memory, mutable exported globals and the host side bookkeeping.
+    /// take snapshots between calls: non-exported globals such as the shadow stack pointer are
+    /// not captured and are only guaranteed to be back at their base value when no call is running
+    pub fn snapshot(&self)->Result<MembraneSnapshot,Error>
+    {
+        let memory = self.instance.exports.get_memory("memory")?;
+        let data = unsafe { memory.data_unchecked() }.to_vec();
+
+        let mut globals = vec!();
+        for (name, export) in self.instance.exports.iter()
+        {
+            if let Extern::Global(global) = export
+            {
+                if global.ty().mutability == Mutability::Var
+                {
+                    let value = GlobalValue::from_val(&global.get()).map_err(|error| format!("global {}: {}", name, error.error))?;
+                    globals.push((name.clone(), value));
+                }
+            }
+        }
+
+        Ok(MembraneSnapshot{
+            fingerprint: self.fingerprint,
+            memory: data,
+            globals,
+            state: self.state()
+        })
+    }
+
+    /// put the guest back into the state captured by snapshot. memory cannot shrink so if it
+    /// has grown since the snapshot the extra pages are zeroed instead
+    pub fn restore(&self, snapshot: &MembraneSnapshot )->Result<(),Error>
+    {
+        if self.state() == MembraneState::ShutDown
+        {
+            return Err("cannot restore a membrane that has been shut down".into());
+        }
+
+        if snapshot.fingerprint != self.fingerprint
+        {
+            return Err("snapshot was taken from a different module".into());
+        }
+
+        let memory = self.instance.exports.get_memory("memory")?;
+        let current = memory.data_size() as usize;
+        if current < snapshot.memory.len()
+        {
+            let missing = snapshot.memory.len() - current;
+            memory.grow(Pages(missing.div_ceil(WASM_PAGE_SIZE) as u32))?;
+        }
+
+        let data = unsafe { memory.data_unchecked_mut() };
+        data[..snapshot.memory.len()].copy_from_slice(snapshot.memory.as_slice());
+        for byte in data[snapshot.memory.len()..].iter_mut()
+        {
+            *byte = 0;
+        }
+
+        for (name, val) in &snapshot.globals
+        {
+            self.instance.exports.get_global(name)?.set(val.to_val())?;
+        }
+
+        self.set_state(snapshot.state)?;
+        Ok(())
+    }
+
+    /// call any exported function by name with raw wasm values
+    pub fn call_export(&self, name: &str, args: &[Val] )->Result<Box<[Val]>,Error>
+    {
+        self.enter()?;
+        self.call_guest(name, args)
+    }
+
+    /// call a guest export, measuring it and recording the call and its outcome while the
+    /// membrane is recording. callers enter() first
+    pub(crate) fn call_guest(&self, name: &str, args: &[Val] )->Result<Box<[Val]>,Error>
+    {
+        let fuel = self.remaining_fuel();
+        self.metrics.begin();
+        let start = Instant::now();
+        let result = self.traced_call_guest(name, args);
+        self.metrics.end(CallKind::Export, name, start.elapsed(), result.is_err(), metrics::fuel_used(fuel, self.remaining_fuel()));
+        result
+    }
+
+    fn traced_call_guest(&self, name: &str, args: &[Val] )->Result<Box<[Val]>,Error>
+    {
+        let recorder = self.recorder();
+        if let Some(recorder) = &recorder
+        {
+            recorder.record(TraceEvent::ExportCall{ name: name.to_string(), args: trace::trace_values(args)? });
+        }
+
+        let result = match self.instance.exports.get_function(name)
+        {
+            Ok(func) => self.check_trap(func.call(args)),
+            Err(error) => Err(error.into())
+        };
+
+        if let Some(recorder) = &recorder
+        {
+            recorder.record(TraceEvent::ExportReturn{ result: match &result
+            {
+                Ok(results) => trace::trace_values(results).map_err(|error| error.error),
+                Err(error) => Err(error.error.clone())
+            }});
+        }
+        result
+    }
+
+    /// calls, errors, latencies, buffer traffic and fuel of every guest export and host import
+    /// called since the membrane was created or reset_metrics. buffers the host writes while
+    /// no call is running count toward the next export call, buffers it reads toward the last
+    pub fn metrics(&self)->MetricsSnapshot
+    {
+        self.metrics.snapshot()
+    }
+
+    pub fn reset_metrics(&self)
+    {
+        self.metrics.reset()
+    }
+
+    pub(crate) fn call_metrics(&self)->&Metrics
+    {
+        &self.metrics
+    }
+
+    pub(crate) fn trace_mode(&self)->Option<TraceMode>
+    {
+        self.trace.read().ok()?.clone()
+    }
+
+    fn recorder(&self)->Option<Arc<Recorder>>
+    {
+        match self.trace_mode()
+        {
+            Some(TraceMode::Record(recorder)) => Option::Some(recorder),
+            _ => Option::None
+        }
+    }
+
+    /// record everything that crosses the boundary from now on: export calls, import calls
+    /// and their results, and the buffers the host writes, reads and frees. start before init
+    /// for a trace that can be replayed on a fresh membrane. guests importing WASI cannot be
+    /// recorded since their WASI calls are not traced
+    pub fn start_recording(&self)->Result<(),Error>
+    {
+        self.check_traceable()?;
+        let mut trace = self.trace.write()?;
+        if trace.is_some()
+        {
+            return Err("membrane is already recording or replaying".into());
+        }
+        *trace = Option::Some(TraceMode::Record(Arc::new(Recorder::default())));
+        Ok(())
+    }
+
+    /// traces only cover the membrane's own imports, so a guest that also talks to WASI
+    /// would not replay the same way
+    fn check_traceable(&self)->Result<(),Error>
+    {
+        if self.instance.module().imports().any(|import| import.module().starts_with("wasi"))
+        {
+            return Err("membranes importing WASI cannot be recorded or replayed".into());
+        }
+        Ok(())
+    }
+
+    /// stop recording and hand over the trace, for instance to Trace::save it
+    pub fn stop_recording(&self)->Result<Trace,Error>
+    {
+        let mut trace = self.trace.write()?;
+        match trace.take()
+        {
+            Some(TraceMode::Record(recorder)) => Ok(Trace{
+                metadata: self.metadata.clone(),
+                events: recorder.take()?
+            }),
+            mode => {
+                *trace = mode;
+                Err("membrane is not recording".into())
+            }
+        }
+    }
+
+    /// re-run a trace against this membrane, which must be fresh and created from the same module
+    /// and config as the recorded one. the host's side of the trace is performed again while the
+    /// guest's import calls are answered from the trace, so no bus, filesystem or kv store is
+    /// needed. returns how many events were replayed or an error naming the first event the
+    /// guest diverged from
+    pub fn replay(&self, trace: &Trace)->Result<usize,Error>
+    {
+        self.check_traceable()?;
+        if self.state() != MembraneState::Created
+        {
+            return Err(format!("can only replay onto a fresh membrane, this one is {:?}", self.state()).into());
+        }
+        if trace.metadata != self.metadata
+        {
+            return Err(format!("trace was recorded on a membrane set up as {:?}", trace.metadata).into());
+        }
+
+        let replayer = Arc::new(Replayer::new(trace));
+        {
+            let mut mode = self.trace.write()?;
+            if mode.is_some()
+            {
+                return Err("membrane is already recording or replaying".into());
+            }
+            *mode = Option::Some(TraceMode::Replay(replayer.clone()));
+        }
+
+        // init is replayed from the trace like any other call
+        self.set_state(MembraneState::Initialized)?;
+        let result = replayer.run(self);
+        *self.trace.write()? = Option::None;
+        result
+    }
+
+    /// deliver an event to the guest's membrane_guest_on_event( i32, i32 ) -> i32 export.
+    /// the guest takes ownership of the topic and payload buffers and returns -1 when its
+    /// handlers succeeded or the id of a buffer holding an error message.
+    /// returns false when the guest does not export membrane_guest_on_event
+    pub fn emit(&self, topic: &str, payload: &[u8] )->Result<bool,Error>
+    {
+        if self.instance.exports.get_native_function::<(i32,i32),i32>("membrane_guest_on_event").is_err()
+        {
+            return Ok(false);
+        }
+
+        let topic_buffer = self.write_string(topic)?;
+        let payload_buffer = self.write_buffer(&payload.to_vec())?;
+        self.enter()?;
+        let error_buffer = single_i32(&self.call_guest("membrane_guest_on_event", &[Val::I32(topic_buffer), Val::I32(payload_buffer)])?)?;
+        if error_buffer >= 0
+        {
+            self.metrics.export_failed();
+            let message = self.consume_string(error_buffer)?;
+            return Err(format!("event {} failed: {}", topic, message).into());
+        }
+        Ok(true)
+    }
+
+    /// make f callable by the guest as name through membrane_host_call, replacing an
+    /// earlier function of that name
+    pub fn register_host_function(&self, name: &str, function: HostFunction )->Result<(),Error>
+    {
+        self.host.write()?.functions.insert(name.to_string(), function);
+        Ok(())
+    }
+
+    /// the membrane_host_call_async calls the guest made since the last take. the caller
+    /// becomes responsible for answering each of them with complete
+    pub fn take_pending_calls(&self)->Result<Vec<PendingCall>,Error>
+    {
+        Ok(std::mem::take(&mut self.host.write()?.pending_calls))
+    }
+
+    /// hand the result of a pending call to the guest's membrane_guest_complete( i32, i32 ),
+    /// which wakes the future waiting on it and runs the guest's ready tasks
+    pub fn complete(&self, handle: i32, result: Result<Vec<u8>,Error> )->Result<(),Error>
+    {
+        self.instance.exports.get_native_function::<(i32,i32),()>("membrane_guest_complete")?;
+        let response_buffer = self.write_response(result)?;
+        self.enter()?;
+        self.call_guest("membrane_guest_complete", &[Val::I32(handle), Val::I32(response_buffer)])?;
+        Ok(())
+    }
+
+    /// answer pending calls with the registered host functions until the guest stops making
+    /// new ones. returns how many calls were completed
+    pub fn run_pending_calls(&self)->Result<usize,Error>
+    {
+        let mut completed = 0;
+        loop {
+            let calls = self.take_pending_calls()?;
+            if calls.is_empty()
+            {
+                return Ok(completed);
+            }
+            for call in calls
+            {
+                let result = self.call_host_function(call.name.as_str(), call.payload.as_slice());
+                self.complete(call.handle, result)?;
+                completed += 1;
+            }
         }
+    }
 
-        match pass{
-            true => Ok(()),
-            false => Err("init failed".into())
+    /// run a function registered with register_host_function from the host side
+    pub fn call_host_function(&self, name: &str, payload: &[u8] )->Result<Vec<u8>,Error>
+    {
+        let function = self.host.read()?.functions.get(name).cloned();
+        match function
+        {
+            Some(function) => function(self, payload),
+            None => Err(format!("no host function named {}", name).into())
         }
+    }
 
+    /// run the guest's ready tasks through membrane_guest_poll( ) -> i32, returns how many
+    /// tasks are still waiting
+    pub fn poll(&self)->Result<i32,Error>
+    {
+        self.instance.exports.get_native_function::<(),i32>("membrane_guest_poll")?;
+        self.enter()?;
+        single_i32(&self.call_guest("membrane_guest_poll", &[])?)
     }
 
-    pub fn log( &self, log_type:&str, message: &str )
+    pub(crate) fn attach_bus(&self, bus: Weak<MessageBus>, name: &str )->Result<(),Error>
+    {
+        self.host.write()?.bus = Option::Some((bus, name.to_string()));
+        Ok(())
+    }
+
+    /// the bus this membrane is registered on and its name there
+    pub(crate) fn bus_registration(&self)->Result<Option<(Arc<MessageBus>,String)>,Error>
+    {
+        Ok(self.host.read()?.bus.as_ref().and_then(|(bus, name)| bus.upgrade().map(|bus| (bus, name.clone()))))
+    }
+
+    /// set this membrane up like other: the same log sink, extensions, host functions and
+    /// filesystem and key-value grants. reload uses it before initializing the new instance
+    pub(crate) fn inherit_host_state(&self, other: &WasmMembrane)->Result<(),Error>
+    {
+        self.set_log_sink(other.log_sink())?;
+        self.extensions.copy_from(&other.extensions);
+        self.grant_filesystem(other.filesystem.read()?.clone())?;
+        self.grant_kv(other.kv.read()?.clone())?;
+        let functions = other.host.read()?.functions.clone();
+        self.host.write()?.functions.extend(functions);
+        Ok(())
+    }
+
+    pub(crate) fn detach_bus(&self)->Result<(),Error>
     {
-        println!("{} : {}",log_type,message);
+        self.host.write()?.bus = Option::None;
+        Ok(())
     }
 
     pub fn write_string(&self, string: &str )->Result<i32,Error>
     {
-        let string = string.as_bytes();
-        let memory = self.instance.exports.get_memory("memory")?;
-        let buffer_id = self.alloc_buffer(string.len() as _ )?;
-        let buffer_ptr = self.get_buffer_ptr(buffer_id)?;
-        let values = buffer_ptr.deref(memory, 0, string.len() as u32).unwrap();
-        for i in 0..string.len() {
-            values[i].set(string[i] );
-        }
+        self.write_buffer(&string.as_bytes().to_vec())
+    }
 
-        Ok(buffer_id)
+    /// write the outcome of a host operation for the guest: a status byte (0 result,
+    /// 1 error message) followed by the result or the message
+    pub(crate) fn write_response(&self, result: Result<Vec<u8>,Error> )->Result<i32,Error>
+    {
+        let response = match result
+        {
+            Ok(result) => [&[0u8][..], result.as_slice()].concat(),
+            Err(error) => {
+                self.metrics.failed();
+                [&[1u8][..], error.error.as_bytes()].concat()
+            }
+        };
+        self.write_buffer(&response)
+    }
+
+    /// write an error message for the guest from within a host import, which counts as failed
+    pub(crate) fn write_error(&self, error: &Error )->Result<i32,Error>
+    {
+        self.metrics.failed();
+        self.write_string(error.error.as_str())
     }
 
     pub fn write_buffer(&self, bytes: &Vec<u8> )->Result<i32,Error>
@@ -167,25 +1061,34 @@ impl WasmMembrane {
             values[i].set(bytes[i] );
         }
 
+        self.metrics.wrote(bytes.len());
+        if let Some(recorder) = self.recorder()
+        {
+            recorder.record(TraceEvent::BufferWrite{ id: buffer_id, bytes: bytes.clone() });
+        }
         Ok(buffer_id)
     }
 
 
-    fn alloc_buffer(&self, len: i32 ) ->Result<i32,Error>
+    pub fn alloc_buffer(&self, len: i32 ) ->Result<i32,Error>
     {
-        let buffer_id= self.instance.exports.get_native_function::<i32,i32>("membrane_guest_alloc_buffer").unwrap().call(len.clone())?;
+        self.enter()?;
+        let buffer_id= self.instance.exports.get_native_function::<i32,i32>("membrane_guest_alloc_buffer").unwrap().call(len.clone());
+        let buffer_id = self.check_trap(buffer_id)?;
         Ok(buffer_id)
     }
 
     fn get_buffer_ptr( &self, buffer_id: i32 )->Result<WasmPtr<u8,Array>,Error>
     {
-        Ok(self.instance.exports.get_native_function::<i32, WasmPtr<u8, Array>>("membrane_guest_get_buffer_ptr").unwrap().call(buffer_id)?)
+        self.enter()?;
+        self.check_trap(self.instance.exports.get_native_function::<i32, WasmPtr<u8, Array>>("membrane_guest_get_buffer_ptr").unwrap().call(buffer_id))
     }
 
     pub fn read_buffer(&self, buffer_id: i32 ) ->Result<Vec<u8>,Error>
     {
-        let ptr = self.instance.exports.get_native_function::<i32,WasmPtr<u8,Array>>("membrane_guest_get_buffer_ptr").unwrap().call(buffer_id )?;
-        let len = self.instance.exports.get_native_function::<i32,i32>("membrane_guest_get_buffer_len").unwrap().call(buffer_id )?;
+        self.enter()?;
+        let ptr = self.check_trap(self.instance.exports.get_native_function::<i32,WasmPtr<u8,Array>>("membrane_guest_get_buffer_ptr").unwrap().call(buffer_id ))?;
+        let len = self.check_trap(self.instance.exports.get_native_function::<i32,i32>("membrane_guest_get_buffer_len").unwrap().call(buffer_id ))?;
         let memory = self.instance.exports.get_memory("memory")?;
         let values = ptr.deref(memory, 0, len as u32).unwrap();
         let mut rtn = vec!();
@@ -193,6 +1096,11 @@ impl WasmMembrane {
            rtn.push( values[i].get() )
         }
 
+        self.metrics.read(rtn.len());
+        if let Some(recorder) = self.recorder()
+        {
+            recorder.record(TraceEvent::BufferRead{ id: buffer_id, bytes: rtn.clone() });
+        }
         Ok(rtn)
     }
 
@@ -204,7 +1112,14 @@ impl WasmMembrane {
         Ok(rtn)
     }
 
-    fn consume_string(&self, buffer_id: i32 ) ->Result<String,Error>
+    pub(crate) fn consume_buffer(&self, buffer_id: i32 ) ->Result<Vec<u8>,Error>
+    {
+        let raw = self.read_buffer(buffer_id)?;
+        self.membrane_guest_dealloc_buffer(buffer_id)?;
+        Ok(raw)
+    }
+
+    pub(crate) fn consume_string(&self, buffer_id: i32 ) ->Result<String,Error>
     {
         let raw = self.read_buffer(buffer_id)?;
         let rtn = String::from_utf8(raw)?;
@@ -212,16 +1127,27 @@ impl WasmMembrane {
         Ok(rtn)
     }
 
+    pub fn dealloc_buffer( &self, buffer_id: i32 )->Result<(),Error>
+    {
+        self.membrane_guest_dealloc_buffer(buffer_id)
+    }
+
     fn membrane_guest_dealloc_buffer( &self, buffer_id: i32 )->Result<(),Error>
     {
-        self.instance.exports.get_native_function::<i32,()>("membrane_guest_dealloc_buffer")?.call(buffer_id.clone())?;
+        self.enter()?;
+        self.check_trap(self.instance.exports.get_native_function::<i32,()>("membrane_guest_dealloc_buffer")?.call(buffer_id.clone()))?;
+        if let Some(recorder) = self.recorder()
+        {
+            recorder.record(TraceEvent::BufferFree{ id: buffer_id });
+        }
         Ok(())
     }
 
 
     pub fn test_panic(&self)->Result<(),Error>
     {
-        self.instance.exports.get_native_function::<(),()>("wasm_test_panic").unwrap().call()?;
+        self.enter()?;
+        self.call_guest("membrane_guest_example_test_panic", &[])?;
         Ok(())
     }
 
@@ -230,13 +1156,15 @@ impl WasmMembrane {
     {
         let log_message_string = "Some Log Message";
         let log_message_buffer = self.write_string(log_message_string)?;
-        self.instance.exports.get_native_function::<i32,()>("membrane_guest_test_log").unwrap().call(log_message_buffer)?;
+        self.enter()?;
+        self.call_guest("membrane_guest_test_log", &[Val::I32(log_message_buffer)])?;
         Ok(())
     }
 
     pub fn test_endless_loop(&self)->Result<(),Error>
     {
-        self.instance.exports.get_native_function::<(),()>("membrane_guest_example_test_endless_loop").unwrap().call()?;
+        self.enter()?;
+        self.call_guest("membrane_guest_example_test_endless_loop", &[])?;
         Ok(())
     }
 
@@ -267,6 +1195,13 @@ impl WasmBuffer
 
 struct WasmHost {
     membrane: Option<Weak<WasmMembrane>>,
+    /// the bus this membrane is registered on and its name there
+    bus: Option<(Weak<MessageBus>, String)>,
+    functions: HashMap<String, HostFunction>,
+    pending_calls: Vec<PendingCall>,
+    next_handle: i32,
+    timers: BTreeMap<i32, Timer>,
+    next_timer_id: i32,
 }
 
 
@@ -276,13 +1211,19 @@ impl  WasmHost{
     {
         WasmHost{
             membrane: Option::None,
+            bus: Option::None,
+            functions: HashMap::new(),
+            pending_calls: vec![],
+            next_handle: 0,
+            timers: BTreeMap::new(),
+            next_timer_id: 0,
         }
     }
 
 }
 
 #[derive(WasmerEnv, Clone)]
-struct Env {
+pub(crate) struct Env {
     host: Arc<RwLock<WasmHost>>,
 }
 
@@ -321,14 +1262,181 @@ impl Env
         }
         Ok(membrane)
     }
+
+    /// run a host import that answers with write_response, -1 when the membrane is gone
+    pub(crate) fn respond<F>(&self, operation: F) -> i32
+        where F: FnOnce(&WasmMembrane) -> Result<Vec<u8>, Error>
+    {
+        match self.unwrap()
+        {
+            Ok(membrane) => membrane.write_response(operation(&membrane)).unwrap_or(-1),
+            Err(_) => -1
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Result<HostFunction, Error>
+    {
+        match self.host.read()?.functions.get(name)
+        {
+            Some(function) => Ok(function.clone()),
+            None => Err(format!("no host function named {}", name).into())
+        }
+    }
+
+    pub fn defer(&self, name: String, payload: Vec<u8>) -> Result<i32, Error>
+    {
+        let mut host = self.host.write()?;
+        let handle = host.next_handle;
+        host.next_handle = host.next_handle.wrapping_add(1) & i32::MAX;
+        host.pending_calls.push(PendingCall {
+            handle,
+            name,
+            payload,
+        });
+        Ok(handle)
+    }
+
+    pub fn start_timer(&self, timer: Timer) -> Result<i32, Error>
+    {
+        let mut host = self.host.write()?;
+        let id = host.next_timer_id;
+        host.next_timer_id = host.next_timer_id.wrapping_add(1) & i32::MAX;
+        host.timers.insert(id, timer);
+        Ok(id)
+    }
+
+    pub fn cancel_timer(&self, id: i32) -> Result<bool, Error>
+    {
+        Ok(self.host.write()?.timers.remove(&id).is_some())
+    }
+
+    pub fn bus(&self) -> Result<(Arc<MessageBus>, String), Error>
+    {
+        match &self.host.read()?.bus
+        {
+            Some((bus, name)) => Ok((bus.upgrade().ok_or("the message bus is gone")?, name.clone())),
+            None => Err("membrane is not registered on a message bus".into())
+        }
+    }
+}
+
+/// fingerprints of the modules membranes are created from, keyed by the address of the module's
+/// Arc so that the membranes of one module share it. the Weak tells a live module from a
+/// dropped one whose address was reused
+static MODULE_FINGERPRINTS: Mutex<ModuleFingerprints> = Mutex::new(BTreeMap::new());
+
+type ModuleFingerprints = BTreeMap<usize, (Weak<Module>, [u8;32])>;
+
+fn hash_fingerprint(parts: &[&[u8]])->[u8;32]
+{
+    let mut hasher = Sha256::new();
+    for part in parts
+    {
+        hasher.update(part);
+    }
+    let mut fingerprint = [0u8;32];
+    fingerprint.copy_from_slice(&hasher.finalize()[..]);
+    fingerprint
+}
+
+/// remember the fingerprint of module, forgetting the modules that have been dropped
+fn register_fingerprint(module: &Arc<Module>, fingerprint: [u8;32])->Result<(),Error>
+{
+    let mut fingerprints = MODULE_FINGERPRINTS.lock()?;
+    fingerprints.retain(|_, (module, _)| module.strong_count() > 0);
+    fingerprints.insert(Arc::as_ptr(module) as usize, (Arc::downgrade(module), fingerprint));
+    Ok(())
+}
+
+/// what a snapshot records to only be restored into membranes of the same module: a hash of
+/// the wasm and engine settings for modules from compile_with_config, of the artifact for
+/// precompiled ones. modules built elsewhere are serialized and hashed once
+fn module_fingerprint(module: &Arc<Module>)->Result<[u8;32],Error>
+{
+    if let Some((registered, fingerprint)) = MODULE_FINGERPRINTS.lock()?.get(&(Arc::as_ptr(module) as usize))
+    {
+        if registered.strong_count() > 0
+        {
+            return Ok(*fingerprint);
+        }
+    }
+
+    let fingerprint = hash_fingerprint(&[module.serialize()?.as_slice()]);
+    register_fingerprint(module, fingerprint)?;
+    Ok(fingerprint)
+}
+
+/// the clock a membrane starts with: the virtual clock in deterministic mode, the system clock otherwise
+fn default_clock(deterministic_sources: &Option<DeterministicSources>)->Arc<dyn Clock>
+{
+    match deterministic_sources
+    {
+        Some(sources) => sources.clock.clone(),
+        None => Arc::new(SystemClock::new())
+    }
+}
+
+/// the result of an export returning a single i32
+fn single_i32(results: &[Val])->Result<i32,Error>
+{
+    results.first().and_then(|result| result.i32()).ok_or_else(|| Error::from("expected the export to return an i32"))
+}
+
+/// wrap a host import so that it is measured, a recording membrane traces its calls and a
+/// replaying one answers them from its trace, see metrics::measured and trace::traced.
+/// takes an inline closure or the path of a function
+macro_rules! traced {
+    ($name:expr, |$env:ident, $($arg:ident: $ty:ty),*| -> $ret:ty $body:block) => {
+        |$env: &Env, $($arg: $ty),*| -> $ret {
+            metrics::measured($env, $name, || trace::traced($env, $name, &[$($arg as i64),*], || -> $ret { $body }))
+        }
+    };
+    ($name:expr, $($function:ident)::+($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        |env: &Env, $($arg: $ty),*| -> $ret {
+            metrics::measured(env, $name, || trace::traced(env, $name, &[$($arg as i64),*], || $($function)::+(env, $($arg),*)))
+        }
+    };
 }
 
 impl WasmMembrane {
     pub fn new(module: Arc<Module>) -> Result<Arc<Self>, Error> {
+        WasmMembrane::instantiate(module, Option::None, |module, _, imports| Ok(Instance::new(module, &imports)?))
+    }
+
+    /// like new for a module compiled with config, such as by compile_with_config.
+    /// the settings are recorded in the membrane's metadata and a deterministic config
+    /// gives the membrane its virtual clock and seeded random source
+    pub fn new_with_config(module: Arc<Module>, config: &MembraneEngineConfig) -> Result<Arc<Self>, Error> {
+        WasmMembrane::instantiate(module, Option::Some(config), |module, _, imports| Ok(Instance::new(module, &imports)?))
+    }
+
+    /// like new_with_config but the guest also gets the WASI imports, set up as wasi_config says.
+    /// modules that do not import WASI simply leave them unused. in deterministic mode the WASI
+    /// clocks and random bytes come from the membrane's deterministic sources
+    #[cfg(feature = "wasi")]
+    pub fn new_with_wasi(module: Arc<Module>, config: &MembraneEngineConfig, wasi_config: &WasiConfig) -> Result<Arc<Self>, Error> {
+        WasmMembrane::instantiate(module, Option::Some(config), |module, env, imports| {
+            let wasi_imports = wasi::import_object(module, wasi_config, env.clone())?;
+            match config.determinism
+            {
+                Some(_) => {
+                    let overrides = wasi::deterministic_import_object(module, env);
+                    Ok(Instance::new(module, &overrides.chain_back(wasi_imports).chain_back(imports))?)
+                }
+                None => Ok(Instance::new(module, &wasi_imports.chain_back(imports))?)
+            }
+        })
+    }
+
+    /// build the membrane imports and let link create the instance from them
+    fn instantiate<F>(module: Arc<Module>, config: Option<&MembraneEngineConfig>, link: F) -> Result<Arc<Self>, Error>
+        where F: FnOnce(&Module, Env, ImportObject) -> Result<Instance, Error>
+    {
+        let fingerprint = module_fingerprint(&module)?;
         let host = Arc::new(RwLock::new(WasmHost::new()));
 
         let imports = imports! { "env"=>{
-        "membrane_host_log"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},|env:&Env,buffer:i32| {
+        "membrane_host_log"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_log", |env, buffer: i32| -> () {
                 match env.unwrap()
                 {
                    Ok(membrane)=>{
@@ -337,9 +1445,122 @@ impl WasmMembrane {
                    },
                    Err(_)=>{}
                 }
-            }),
+            })),
+
+        "membrane_host_send"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_send", |env, target_buffer: i32, payload_buffer: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                let result = (|| -> Result<(),Error> {
+                    let target = membrane.consume_string(target_buffer)?;
+                    let payload = membrane.consume_buffer(payload_buffer)?;
+                    let (bus, name) = env.bus()?;
+                    bus.send(name.as_str(), target.as_str(), payload.as_slice())
+                })();
+                match result
+                {
+                    Ok(_) => -1,
+                    Err(error) => membrane.write_error(&error).unwrap_or(-1)
+                }
+            })),
+
+        "membrane_host_request"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_request", |env, target_buffer: i32, payload_buffer: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                let result = (|| -> Result<Vec<u8>,Error> {
+                    let target = membrane.consume_string(target_buffer)?;
+                    let payload = membrane.consume_buffer(payload_buffer)?;
+                    let (bus, name) = env.bus()?;
+                    bus.request(name.as_str(), target.as_str(), payload.as_slice())
+                })();
+                membrane.write_response(result).unwrap_or(-1)
+            })),
+
+        "membrane_host_reply"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_reply", |env, correlation_id: i64, payload_buffer: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                let result = (|| -> Result<(),Error> {
+                    let payload = membrane.consume_buffer(payload_buffer)?;
+                    let (bus, name) = env.bus()?;
+                    bus.reply(name.as_str(), correlation_id as u64, payload.as_slice())
+                })();
+                match result
+                {
+                    Ok(_) => -1,
+                    Err(error) => membrane.write_error(&error).unwrap_or(-1)
+                }
+            })),
 
-        "membrane_host_panic"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},|env:&Env,buffer_id:i32| {
+        "membrane_host_call"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_call", |env, name_buffer: i32, payload_buffer: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                let result = (|| -> Result<Vec<u8>,Error> {
+                    let name = membrane.consume_string(name_buffer)?;
+                    let payload = membrane.consume_buffer(payload_buffer)?;
+                    // the host lock is released before the function runs so it may call back into the host
+                    let function = env.function(name.as_str())?;
+                    function(&membrane, payload.as_slice())
+                })();
+                membrane.write_response(result).unwrap_or(-1)
+            })),
+
+        "membrane_host_call_async"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_call_async", |env, name_buffer: i32, payload_buffer: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                let result = (|| -> Result<i32,Error> {
+                    let name = membrane.consume_string(name_buffer)?;
+                    let payload = membrane.consume_buffer(payload_buffer)?;
+                    env.defer(name, payload)
+                })();
+                result.unwrap_or(-1)
+            })),
+
+        "membrane_host_release_resource"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_release_resource", |env, handle: i32| -> i32 {
+                let membrane = match env.unwrap()
+                {
+                    Ok(membrane) => membrane,
+                    Err(_) => return -1
+                };
+                match membrane.resources().release(handle as u32)
+                {
+                    Ok(_) => -1,
+                    Err(error) => membrane.write_error(&error).unwrap_or(-1)
+                }
+            })),
+
+        "membrane_host_fs_open"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_open", vfs::host_fs_open(path_buffer: i32, flags: i32) -> i32)),
+        "membrane_host_fs_read"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_read", vfs::host_fs_read(handle: i32, len: i32) -> i32)),
+        "membrane_host_fs_write"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_write", vfs::host_fs_write(handle: i32, data_buffer: i32) -> i32)),
+        "membrane_host_fs_list"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_list", vfs::host_fs_list(path_buffer: i32) -> i32)),
+        "membrane_host_fs_stat"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_stat", vfs::host_fs_stat(path_buffer: i32) -> i32)),
+        "membrane_host_fs_remove"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_fs_remove", vfs::host_fs_remove(path_buffer: i32) -> i32)),
+        "membrane_host_time_monotonic"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_time_monotonic", time::host_time_monotonic() -> i64)),
+        "membrane_host_time_wall"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_time_wall", time::host_time_wall() -> i64)),
+        "membrane_host_sleep"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_sleep", time::host_sleep(duration: i64) -> ())),
+        "membrane_host_random"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_random", time::host_random(len: i32) -> i32)),
+        "membrane_host_timer_start"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_timer_start", time::host_timer_start(delay: i64, interval: i64) -> i32)),
+        "membrane_host_timer_cancel"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_timer_cancel", time::host_timer_cancel(id: i32) -> i32)),
+        "membrane_host_kv_get"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_kv_get", kv::host_kv_get(key_buffer: i32) -> i32)),
+        "membrane_host_kv_put"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_kv_put", kv::host_kv_put(key_buffer: i32, value_buffer: i32) -> i32)),
+        "membrane_host_kv_delete"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_kv_delete", kv::host_kv_delete(key_buffer: i32) -> i32)),
+        "membrane_host_kv_list"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_kv_list", kv::host_kv_list(prefix_buffer: i32) -> i32)),
+        "membrane_host_kv_cas"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_kv_cas", kv::host_kv_cas(key_buffer: i32, expected_buffer: i32, new_buffer: i32, flags: i32) -> i32)),
+
+        "membrane_host_panic"=>Function::new_native_with_env(module.store(),Env{host:host.clone()},traced!("membrane_host_panic", |env, buffer_id: i32| -> () {
                 match env.unwrap()
                 {
                    Ok(membrane)=>{
@@ -350,15 +1571,35 @@ impl WasmMembrane {
                    println!("error panic");
                    }
                 }
-            }),
+            })),
         } };
 
 
-        let instance = Instance::new(&module, &imports)?;
+        let instance = link(module.as_ref(), Env{host:host.clone()}, imports)?;
+
+        let deterministic_sources = config.and_then(|config| config.determinism.as_ref()).map(DeterministicSources::new);
+        let clock = default_clock(&deterministic_sources);
 
         let membrane = Arc::new(WasmMembrane {
             instance: instance,
-            //host: host.clone()
+            log_sink: RwLock::new(Option::None),
+            extensions: Arc::new(Extensions::new()),
+            resources: ResourceTable::new(),
+            filesystem: RwLock::new(Option::None),
+            kv: RwLock::new(Option::None),
+            metadata: MembraneMetadata {
+                engine_settings: config.map(|config| config.cache_key()),
+                determinism: config.and_then(|config| config.determinism.clone()),
+            },
+            fingerprint,
+            deterministic_sources,
+            init_config: RwLock::new(Option::None),
+            clock: RwLock::new(clock),
+            trace: RwLock::new(Option::None),
+            metrics: Metrics::default(),
+            state: RwLock::new(MembraneState::Created),
+            initializing: AtomicBool::new(false),
+            host: host.clone()
         });
 
         {
@@ -367,6 +1608,137 @@ impl WasmMembrane {
 
         return Ok(membrane);
     }
+
+    /// compile, instantiate and init a membrane from wasm bytes. precompiled artifacts are
+    /// refused, load those with from_precompiled
+    pub fn from_bytes(bytes: &[u8]) -> Result<Arc<Self>, Error>
+    {
+        WasmMembrane::from_bytes_with_config(bytes, &MembraneEngineConfig::default())
+    }
+
+    pub fn from_bytes_with_config(bytes: &[u8], config: &MembraneEngineConfig) -> Result<Arc<Self>, Error>
+    {
+        let membrane = WasmMembrane::new_with_config(WasmMembrane::compile_with_config(bytes, config)?, config)?;
+        membrane.init()?;
+        Ok(membrane)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Arc<Self>, Error>
+    {
+        WasmMembrane::from_file_with_config(path, &MembraneEngineConfig::default())
+    }
+
+    pub fn from_file_with_config<P: AsRef<Path>>(path: P, config: &MembraneEngineConfig) -> Result<Arc<Self>, Error>
+    {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        WasmMembrane::from_bytes_with_config(data.as_slice(), config)
+    }
+
+    /// create a store for the default engine and compile a wasm module
+    pub fn compile(bytes: &[u8]) -> Result<Arc<Module>, Error>
+    {
+        WasmMembrane::compile_with_config(bytes, &MembraneEngineConfig::default())
+    }
+
+    /// create a store for the configured engine and compile a wasm module, through the
+    /// config's module cache if it has one
+    pub fn compile_with_config(bytes: &[u8], config: &MembraneEngineConfig) -> Result<Arc<Module>, Error>
+    {
+        if WasmMembrane::is_precompiled(bytes)
+        {
+            return Err("precompiled artifacts hold native code, load them with the unsafe WasmMembrane::from_precompiled".into());
+        }
+
+        let store = config.store()?;
+        let module = if let Some(cache) = &config.cache
+        {
+            cache.load_or_compile(&store, bytes, config.cache_key().as_str())?
+        }
+        else
+        {
+            Module::new(&store, bytes)?
+        };
+        let module = Arc::new(module);
+        register_fingerprint(&module, hash_fingerprint(&[config.cache_key().as_bytes(), bytes]))?;
+        Ok(module)
+    }
+
+    /// compile wasm ahead of time so that from_bytes does not have to run the compiler on startup.
+    /// the artifact is only valid for the wasmer version and engine config it was created with
+    pub fn precompile(wasm: &[u8]) -> Result<Vec<u8>, Error>
+    {
+        WasmMembrane::precompile_with_config(wasm, &MembraneEngineConfig::default())
+    }
+
+    pub fn precompile_with_config(wasm: &[u8], config: &MembraneEngineConfig) -> Result<Vec<u8>, Error>
+    {
+        let module = Module::new(&config.store()?, wasm)?;
+        Ok(module.serialize()?)
+    }
+
+    /// whether bytes look like an artifact written by precompile rather than wasm
+    pub fn is_precompiled(bytes: &[u8]) -> bool
+    {
+        JITArtifact::is_deserializable(bytes)
+    }
+
+    /// instantiate and init a membrane from an artifact written by precompile.
+    ///
+    /// # Safety
+    ///
+    /// the artifact is native code that runs unchecked. the caller must make sure bytes were
+    /// written by precompile of this version of the host and come from a source as trusted as
+    /// the host binary itself
+    pub unsafe fn from_precompiled(bytes: &[u8]) -> Result<Arc<Self>, Error>
+    {
+        WasmMembrane::from_precompiled_with_config(bytes, &MembraneEngineConfig::default())
+    }
+
+    /// instantiate and init a membrane from an artifact written by precompile_with_config.
+    ///
+    /// # Safety
+    ///
+    /// the artifact is native code that runs unchecked. the caller must make sure bytes were
+    /// written by precompile_with_config of this version of the host, with the same config, and
+    /// come from a source as trusted as the host binary itself. nothing verifies the config:
+    /// middlewares such as fuel metering or the determinism checks are only present if the
+    /// artifact was compiled with them
+    pub unsafe fn from_precompiled_with_config(bytes: &[u8], config: &MembraneEngineConfig) -> Result<Arc<Self>, Error>
+    {
+        let module = Arc::new(Module::deserialize(&config.store()?, bytes)?);
+        register_fingerprint(&module, hash_fingerprint(&[bytes]))?;
+        let membrane = WasmMembrane::new_with_config(module, config)?;
+        membrane.init()?;
+        Ok(membrane)
+    }
+}
+
+/// embed a guest module in the host binary and create a membrane from it:
+///
+/// let membrane = include_membrane!("../plugins/my_plugin.wasm")?;
+///
+/// the path may point to plain wasm or to an artifact written by WasmMembrane::precompile,
+/// which is trusted like the rest of the host's source tree it was built from.
+/// a MembraneEngineConfig may be passed as a second argument
+#[macro_export]
+macro_rules! include_membrane {
+    ($path:expr) => {
+        $crate::include_membrane!($path, &$crate::engine::MembraneEngineConfig::default())
+    };
+    ($path:expr, $config:expr) => {{
+        let bytes: &'static [u8] = include_bytes!($path);
+        if $crate::membrane::WasmMembrane::is_precompiled(bytes)
+        {
+            // embedded at build time, as trusted as the host binary
+            unsafe { $crate::membrane::WasmMembrane::from_precompiled_with_config(bytes, $config) }
+        }
+        else
+        {
+            $crate::membrane::WasmMembrane::from_bytes_with_config(bytes, $config)
+        }
+    }};
 }
 
 pub struct BufferLock
@@ -405,48 +1777,182 @@ impl Drop for BufferLock
 }
 
 
+impl Drop for WasmMembrane
+{
+    /// give the guest a chance to flush its state. there is no time limit here, use
+    /// shutdown() before dropping the last reference when the guest may take long.
+    /// host imports cannot reach the membrane anymore at this point so guest logs are lost
+    fn drop(&mut self) {
+        match self.state()
+        {
+            MembraneState::Initialized | MembraneState::Running => {
+                if let Ok(func) = self.instance.exports.get_native_function::<(),()>("membrane_guest_shutdown")
+                {
+                    func.call().unwrap_or(());
+                }
+                self.set_state(MembraneState::ShutDown).unwrap_or(());
+            }
+            _ => {}
+        }
+    }
+}
+
 #[cfg(test)]
 mod test
 {
     use std::fs::File;
     use std::io::Read;
     use std::sync::Arc;
-    use crate::membrane::WasmMembrane;
+    use crate::membrane::{WasmMembrane, MembraneState};
+    use crate::engine::MembraneEngineConfig;
     use crate::error::Error;
-    use wasmer::{Store, JIT, Cranelift, Module};
     use std::env;
+    use std::time::Duration;
 
+    static WASM_PATH: &str = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
 
     fn membrane() -> Result<Arc<WasmMembrane>, Error>
     {
         println!("CURRENT DIR {:?}", env::current_dir()? );
-        let path = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+        WasmMembrane::from_file(WASM_PATH)
+    }
 
-        let mut file = File::open(path)?;
+
+    #[test]
+    pub fn test_wasm() -> Result<(), Error>
+    {
+        let membrane = membrane()?;
+
+        let buffer_id = membrane.write_string("Hello From MEMBRANE!")?;
+
+        membrane.membrane_guest_dealloc_buffer(buffer_id)?;
+
+        Ok(())
+    }
+
+
+    #[test]
+    pub fn test_precompiled() -> Result<(), Error>
+    {
+        let mut file = File::open(WASM_PATH)?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
 
-        let store = Store::new(&JIT::new(Cranelift::default()).engine());
-        let module = Module::new(&store, data)?;
-        let membrane = WasmMembrane::new(Arc::new(module)).unwrap();
+        let precompiled = WasmMembrane::precompile(data.as_slice())?;
+        assert!(WasmMembrane::from_bytes(precompiled.as_slice()).is_err());
+        let membrane = unsafe { WasmMembrane::from_precompiled(precompiled.as_slice())? };
+        membrane.test_log()?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_init_with_config() -> Result<(), Error>
+    {
+        let data = std::fs::read(WASM_PATH)?;
+        let membrane = WasmMembrane::new(WasmMembrane::compile(data.as_slice())?)?;
+
+        let lines = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink_lines = lines.clone();
+        membrane.set_log_sink(Option::Some(Arc::new(move |_: &WasmMembrane, log_type: &str, message: &str| {
+            sink_lines.lock().unwrap().push(format!("{}: {}", log_type, message));
+        })))?;
+
+        let mut config = std::collections::HashMap::new();
+        config.insert("greeting", "Hello From Init Config!");
+        membrane.init_with_json(&config)?;
+        // the example guest logs the greeting it was configured with
+        assert!(lines.lock().unwrap().contains(&"guest: Hello From Init Config!".to_string()));
+
+        // a config that is not a json object makes the guest answer with an error buffer
+        let failing = WasmMembrane::new(WasmMembrane::compile(data.as_slice())?)?;
+        let error = failing.init_with_config(Option::Some(b"[1, 2]")).err().ok_or("init should have failed")?;
+        assert!(error.error.contains("init config is not a json object"));
+        assert_eq!(failing.state(), MembraneState::Poisoned);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_lifecycle() -> Result<(), Error>
+    {
+        let data = std::fs::read(WASM_PATH)?;
+        let membrane = WasmMembrane::new(WasmMembrane::compile(data.as_slice())?)?;
+        assert_eq!(membrane.state(), MembraneState::Created);
+        assert!(membrane.write_string("too early").is_err());
+
         membrane.init()?;
+        assert_eq!(membrane.state(), MembraneState::Initialized);
+        assert!(membrane.init().is_err());
 
-        Ok(membrane)
+        membrane.test_log()?;
+        assert_eq!(membrane.state(), MembraneState::Running);
+
+        membrane.shutdown(Duration::from_secs(1))?;
+        assert_eq!(membrane.state(), MembraneState::ShutDown);
+        assert!(membrane.test_log().is_err());
+
+        Ok(())
     }
 
+    #[test]
+    pub fn test_emit() -> Result<(), Error>
+    {
+        let membrane = membrane()?;
+        assert!(membrane.emit("greeting", b"hello")?);
+        assert!(membrane.emit("unsubscribed", b"")?);
+        assert!(membrane.emit("fail", b"").is_err());
+        Ok(())
+    }
 
     #[test]
-    pub fn test_wasm() -> Result<(), Error>
+    pub fn test_pending_calls() -> Result<(), Error>
     {
         let membrane = membrane()?;
+        membrane.register_host_function("pong", Arc::new(|_: &WasmMembrane, _: &[u8]| Ok(b"pong".to_vec())))?;
+        membrane.emit("call_host_async", b"pong")?;
+        assert_eq!(membrane.poll()?, 2);
+        assert_eq!(membrane.run_pending_calls()?, 2);
+        assert_eq!(membrane.poll()?, 0);
+        Ok(())
+    }
 
-        let buffer_id = membrane.write_string("Hello From MEMBRANE!")?;
+    #[test]
+    pub fn test_extensions() -> Result<(), Error>
+    {
+        struct Tenant(&'static str);
 
-        membrane.membrane_guest_dealloc_buffer(buffer_id)?;
+        let membrane = membrane()?;
+        membrane.extensions().insert(Tenant("acme"));
+        membrane.register_host_function("pong", Arc::new(|membrane: &WasmMembrane, _: &[u8]| {
+            match membrane.extensions().get::<Tenant>()
+            {
+                Some(tenant) if tenant.0 == "acme" => Ok(b"pong".to_vec()),
+                _ => Err("no tenant".into())
+            }
+        }))?;
+        membrane.emit("call_host", b"pong")?;
 
+        membrane.extensions().remove::<Tenant>();
+        assert!(membrane.emit("call_host", b"pong").is_err());
         Ok(())
     }
 
+    #[test]
+    pub fn test_resources() -> Result<(), Error>
+    {
+        let membrane = membrane()?;
+        let handle = membrane.resources().insert("open file".to_string())?;
+        membrane.resources().insert(42u32)?;
+
+        membrane.emit("release_resource", &handle.to_le_bytes())?;
+        assert!(membrane.resources().get::<String>(handle).is_err());
+        assert!(membrane.emit("release_resource", &handle.to_le_bytes()).is_err());
+
+        membrane.shutdown(Duration::from_secs(1))?;
+        assert!(membrane.resources().is_empty());
+        Ok(())
+    }
 
     #[test]
     pub fn test_log() -> Result<(), Error>
@@ -466,6 +1972,29 @@ mod test
         Ok(())
     }
 
+    #[test]
+    pub fn test_fuel() -> Result<(), Error>
+    {
+        let unmetered = membrane()?;
+        assert_eq!(unmetered.remaining_fuel(), Option::None);
+        assert!(unmetered.refuel(10).is_err());
+
+        let config = MembraneEngineConfig {
+            fuel: Option::Some(1_000_000),
+            ..Default::default()
+        };
+        let membrane = WasmMembrane::from_file_with_config(WASM_PATH, &config)?;
+        assert!(membrane.remaining_fuel().unwrap() < 1_000_000);
+        membrane.refuel(1_000_000)?;
+        assert_eq!(membrane.remaining_fuel(), Option::Some(1_000_000));
+
+        // the endless loop only ends when the fuel runs out
+        assert!(membrane.test_endless_loop().is_err());
+        assert_eq!(membrane.remaining_fuel(), Option::Some(0));
+        assert!(membrane.trapped());
+        Ok(())
+    }
+
 
 }
 
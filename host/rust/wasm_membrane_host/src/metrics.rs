@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::membrane::Env;
+
+/// upper bounds of the latency histogram buckets, in seconds
+pub static LATENCY_BUCKETS: &[f64] = &[0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// a histogram with fixed bucket bounds. counts holds one count per bound plus one for the
+/// values above the last bound
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub bounds: Vec<f64>,
+    pub counts: Vec<u64>,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self
+    {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64)
+    {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64
+    {
+        self.counts.iter().sum()
+    }
+
+    /// how many values are at or below each bound, the last entry being the total
+    pub fn cumulative(&self) -> Vec<u64>
+    {
+        self.counts.iter().scan(0, |total, count| {
+            *total += count;
+            Option::Some(*total)
+        }).collect()
+    }
+}
+
+/// what the calls to one guest export or host import added up to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CallMetrics {
+    pub calls: u64,
+    /// exports that trapped or failed, imports that answered the guest with an error
+    pub errors: u64,
+    /// wall time of each call in seconds, nested calls included
+    pub latency: Histogram,
+    /// bytes the host wrote into guest buffers during the calls
+    pub bytes_in: u64,
+    /// bytes the host read out of guest buffers during the calls
+    pub bytes_out: u64,
+    /// fuel burnt during the calls, 0 without fuel metering
+    pub fuel: u64,
+}
+
+impl Default for CallMetrics {
+    fn default() -> Self {
+        CallMetrics {
+            calls: 0,
+            errors: 0,
+            latency: Histogram::new(LATENCY_BUCKETS),
+            bytes_in: 0,
+            bytes_out: 0,
+            fuel: 0,
+        }
+    }
+}
+
+/// the metrics of a membrane at one point in time, see WasmMembrane::metrics
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// by export name
+    pub exports: BTreeMap<String, CallMetrics>,
+    /// by import name
+    pub imports: BTreeMap<String, CallMetrics>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CallKind {
+    Export,
+    Import,
+}
+
+#[derive(Default)]
+struct Frame {
+    failed: bool,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    snapshot: MetricsSnapshot,
+    /// the calls in progress, buffer traffic is counted for the innermost one
+    frames: Vec<Frame>,
+    /// bytes written while no call was running, counted for the next export call
+    pending_in: u64,
+    /// the export that finished last, reads while no call is running are counted for it
+    last_export: Option<String>,
+}
+
+/// collects the call metrics of a membrane
+#[derive(Default)]
+pub(crate) struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Metrics {
+    pub fn begin(&self)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            let bytes_in = std::mem::replace(&mut state.pending_in, 0);
+            state.frames.push(Frame { bytes_in, ..Default::default() });
+        }
+    }
+
+    pub fn end(&self, kind: CallKind, name: &str, latency: Duration, failed: bool, fuel: u64)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            let frame = state.frames.pop().unwrap_or_default();
+            let calls = match kind
+            {
+                CallKind::Export => &mut state.snapshot.exports,
+                CallKind::Import => &mut state.snapshot.imports,
+            };
+            let metrics = calls.entry(name.to_string()).or_insert_with(CallMetrics::default);
+            metrics.calls += 1;
+            if failed || frame.failed
+            {
+                metrics.errors += 1;
+            }
+            metrics.latency.observe(latency.as_secs_f64());
+            metrics.bytes_in += frame.bytes_in;
+            metrics.bytes_out += frame.bytes_out;
+            metrics.fuel += fuel;
+            if kind == CallKind::Export
+            {
+                state.last_export = Option::Some(name.to_string());
+            }
+        }
+    }
+
+    /// the call in progress failed, for instance an import that answered the guest with an error
+    pub fn failed(&self)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            if let Some(frame) = state.frames.last_mut()
+            {
+                frame.failed = true;
+            }
+        }
+    }
+
+    /// the export that finished last returned an error to the host
+    pub fn export_failed(&self)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            if let Some(metrics) = last_export(&mut state)
+            {
+                metrics.errors += 1;
+            }
+        }
+    }
+
+    pub fn wrote(&self, bytes: usize)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            let state = &mut *state;
+            match state.frames.last_mut()
+            {
+                Some(frame) => frame.bytes_in += bytes as u64,
+                None => state.pending_in += bytes as u64
+            }
+        }
+    }
+
+    pub fn read(&self, bytes: usize)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            let state = &mut *state;
+            match state.frames.last_mut()
+            {
+                Some(frame) => frame.bytes_out += bytes as u64,
+                None => {
+                    // the host reading what the last export left behind, such as its error message
+                    if let Some(metrics) = last_export(state)
+                    {
+                        metrics.bytes_out += bytes as u64;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot
+    {
+        self.state.lock().map(|state| state.snapshot.clone()).unwrap_or_default()
+    }
+
+    pub fn reset(&self)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            state.snapshot = MetricsSnapshot::default();
+            state.pending_in = 0;
+            state.last_export = Option::None;
+        }
+    }
+}
+
+fn last_export(state: &mut MetricsState) -> Option<&mut CallMetrics>
+{
+    match &state.last_export
+    {
+        Some(name) => state.snapshot.exports.get_mut(name),
+        None => Option::None
+    }
+}
+
+/// fuel burnt between two readings of WasmMembrane::remaining_fuel
+pub(crate) fn fuel_used(before: Option<u64>, after: Option<u64>) -> u64
+{
+    match (before, after)
+    {
+        (Some(before), Some(after)) => before.saturating_sub(after),
+        _ => 0
+    }
+}
+
+/// run a host import and record it in the membrane's metrics
+pub(crate) fn measured<R, F: FnOnce() -> R>(env: &Env, name: &str, import: F) -> R
+{
+    let membrane = match env.unwrap()
+    {
+        Ok(membrane) => membrane,
+        Err(_) => return import()
+    };
+    let fuel = membrane.remaining_fuel();
+    membrane.call_metrics().begin();
+    let start = Instant::now();
+    let result = import();
+    membrane.call_metrics().end(CallKind::Import, name, start.elapsed(), false, fuel_used(fuel, membrane.remaining_fuel()));
+    result
+}
+
+/// a Prometheus counter: its name, help text and how to read it from a call's metrics
+#[cfg(feature = "prometheus")]
+type Counter = (&'static str, &'static str, fn(&CallMetrics) -> u64);
+
+/// the snapshots of several membranes in the Prometheus text exposition format, each sample
+/// labelled with the membrane's name, the kind of call (export or import) and its name
+#[cfg(feature = "prometheus")]
+pub fn encode_prometheus(snapshots: &[(&str, &MetricsSnapshot)]) -> String
+{
+    let mut calls: Vec<(String, &CallMetrics)> = vec![];
+    for (membrane, snapshot) in snapshots
+    {
+        for (kind, metrics) in [("export", &snapshot.exports), ("import", &snapshot.imports)].iter()
+        {
+            for (name, metrics) in metrics.iter()
+            {
+                let labels = format!("membrane=\"{}\",kind=\"{}\",name=\"{}\"", escape_label(membrane), kind, escape_label(name));
+                calls.push((labels, metrics));
+            }
+        }
+    }
+
+    let mut out = String::new();
+    let counters: [Counter; 5] = [
+        ("wasm_membrane_calls_total", "Calls made across the membrane.", |metrics| metrics.calls),
+        ("wasm_membrane_errors_total", "Calls that failed.", |metrics| metrics.errors),
+        ("wasm_membrane_bytes_in_total", "Bytes the host wrote into guest buffers during calls.", |metrics| metrics.bytes_in),
+        ("wasm_membrane_bytes_out_total", "Bytes the host read from guest buffers during calls.", |metrics| metrics.bytes_out),
+        ("wasm_membrane_fuel_used_total", "Fuel burnt during calls.", |metrics| metrics.fuel),
+    ];
+    for (name, help, value) in counters.iter()
+    {
+        out.push_str(format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name).as_str());
+        for (labels, metrics) in &calls
+        {
+            out.push_str(format!("{}{{{}}} {}\n", name, labels, value(metrics)).as_str());
+        }
+    }
+
+    out.push_str("# HELP wasm_membrane_call_duration_seconds How long calls took.\n# TYPE wasm_membrane_call_duration_seconds histogram\n");
+    for (labels, metrics) in &calls
+    {
+        let cumulative = metrics.latency.cumulative();
+        for (bound, count) in metrics.latency.bounds.iter().zip(cumulative.iter())
+        {
+            out.push_str(format!("wasm_membrane_call_duration_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, bound, count).as_str());
+        }
+        out.push_str(format!("wasm_membrane_call_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels, metrics.latency.count()).as_str());
+        out.push_str(format!("wasm_membrane_call_duration_seconds_sum{{{}}} {}\n", labels, metrics.latency.sum).as_str());
+        out.push_str(format!("wasm_membrane_call_duration_seconds_count{{{}}} {}\n", labels, metrics.latency.count()).as_str());
+    }
+    out
+}
+
+#[cfg(feature = "prometheus")]
+impl MetricsSnapshot {
+    /// this snapshot alone in the Prometheus text format, see encode_prometheus
+    pub fn to_prometheus(&self, membrane: &str) -> String
+    {
+        encode_prometheus(&[(membrane, self)])
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn escape_label(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::metrics::{Histogram, LATENCY_BUCKETS};
+
+    static WASM_PATH: &str = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+
+    #[test]
+    pub fn test_histogram()
+    {
+        let mut histogram = Histogram::new(&[1.0, 2.0]);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(7.0);
+        assert_eq!(histogram.counts, vec![1, 1, 1]);
+        assert_eq!(histogram.cumulative(), vec![1, 2, 3]);
+        assert_eq!(histogram.sum, 9.5);
+        assert_eq!(Histogram::new(LATENCY_BUCKETS).count(), 0);
+    }
+
+    #[test]
+    pub fn test_metrics() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file(WASM_PATH)?;
+        membrane.reset_metrics();
+        membrane.emit("greeting", b"hello")?;
+        membrane.emit("greeting", b"hello")?;
+        assert!(membrane.emit("fail", b"").is_err());
+
+        let metrics = membrane.metrics();
+        let on_event = &metrics.exports["membrane_guest_on_event"];
+        assert_eq!(on_event.calls, 3);
+        assert_eq!(on_event.errors, 1);
+        assert_eq!(on_event.latency.count(), 3);
+        // the topics and payloads written ahead of the calls, "greeting" twice and "fail"
+        assert_eq!(on_event.bytes_in, 2 * 13 + 4);
+        assert!(on_event.bytes_out > 0);
+        assert_eq!(on_event.fuel, 0);
+
+        // the guest logs each greeting, handing the host a five byte buffer
+        let log = &metrics.imports["membrane_host_log"];
+        assert_eq!(log.calls, 2);
+        assert_eq!(log.errors, 0);
+        assert_eq!(log.bytes_out, 10);
+
+        membrane.reset_metrics();
+        assert!(membrane.metrics().exports.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fuel_metrics() -> Result<(), Error>
+    {
+        let config = MembraneEngineConfig {
+            fuel: Option::Some(10_000_000),
+            ..Default::default()
+        };
+        let membrane = WasmMembrane::from_file_with_config(WASM_PATH, &config)?;
+        membrane.emit("greeting", b"hello")?;
+        assert!(membrane.metrics().exports["membrane_guest_on_event"].fuel > 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    pub fn test_prometheus() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file(WASM_PATH)?;
+        membrane.emit("greeting", b"hello")?;
+        let text = membrane.metrics().to_prometheus("example \"one\"");
+        assert!(text.contains("# TYPE wasm_membrane_calls_total counter\n"));
+        assert!(text.contains("wasm_membrane_calls_total{membrane=\"example \\\"one\\\"\",kind=\"export\",name=\"membrane_guest_on_event\"} 1\n"));
+        assert!(text.contains("wasm_membrane_call_duration_seconds_bucket{membrane=\"example \\\"one\\\"\",kind=\"import\",name=\"membrane_host_log\",le=\"+Inf\"} 1\n"));
+        Ok(())
+    }
+}
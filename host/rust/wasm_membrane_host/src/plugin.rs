@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::engine::MembraneEngineConfig;
+use crate::error::Error;
+use crate::kv::KvStore;
+use crate::membrane::WasmMembrane;
+use crate::vfs::Vfs;
+
+/// the capabilities a manifest may list: "fs" grants the registry's filesystem and "kv" a
+/// namespace of the registry's key-value store named after the plugin
+pub static CAPABILITIES: &[&str] = &["fs", "kv"];
+
+/// settings for one plugin, read from the plugin.toml next to its wasm file:
+///
+/// plugins/greeter.wasm
+/// plugins/greeter.plugin.toml
+///
+/// name = "greeter"
+/// capabilities = ["fs"]
+/// fuel = 10000000
+/// max_memory_pages = 256
+///
+/// [init]
+/// greeting = "hello"
+///
+/// every field is optional. a plugin without a manifest is named after its file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginManifest {
+    pub name: Option<String>,
+    /// host capabilities this plugin is granted, see CAPABILITIES
+    pub capabilities: Vec<String>,
+    /// overrides MembraneEngineConfig::fuel
+    pub fuel: Option<u64>,
+    /// overrides MembraneEngineConfig::max_memory_pages
+    pub max_memory_pages: Option<u32>,
+    /// configuration handed to the guest as json when it is initialized
+    pub init: toml::value::Table,
+}
+
+impl PluginManifest {
+    pub fn parse(manifest: &str) -> Result<Self, Error>
+    {
+        toml::from_str(manifest).map_err(|e| format!("bad plugin manifest: {}", e).into())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error>
+    {
+        PluginManifest::parse(fs::read_to_string(path)?.as_str())
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool
+    {
+        self.capabilities.iter().any(|granted| granted == capability)
+    }
+}
+
+/// modification times of a plugin's wasm and manifest, used to notice changes
+type PluginStamp = (SystemTime, Option<SystemTime>);
+
+pub struct Plugin {
+    pub name: String,
+    pub wasm_path: PathBuf,
+    pub manifest: PluginManifest,
+    pub membrane: Arc<WasmMembrane>,
+    stamp: PluginStamp,
+}
+
+#[derive(Debug, Clone)]
+pub struct PluginFailure {
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+/// what changed during a PluginRegistry::sync
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    /// plugins that were added or replaced by a changed version
+    pub loaded: Vec<String>,
+    /// plugins whose wasm file disappeared
+    pub unloaded: Vec<String>,
+    pub failed: Vec<PluginFailure>,
+}
+
+impl LoadReport {
+    pub fn is_empty(&self) -> bool
+    {
+        self.loaded.is_empty() && self.unloaded.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// loads every *.wasm file in a directory as a named membrane
+pub struct PluginRegistry {
+    dir: PathBuf,
+    config: MembraneEngineConfig,
+    filesystem: Option<Arc<dyn Vfs>>,
+    kv: Option<Arc<KvStore>>,
+    plugins: RwLock<HashMap<String, Arc<Plugin>>>,
+    /// files that failed to load, so they are only retried once they change
+    failed: RwLock<HashMap<PathBuf, (PluginStamp, Error)>>,
+}
+
+impl PluginRegistry {
+    /// config is the base engine config, manifests may override fuel and memory limits
+    pub fn new<P: AsRef<Path>>(dir: P, config: MembraneEngineConfig) -> Self
+    {
+        PluginRegistry {
+            dir: dir.as_ref().to_path_buf(),
+            config,
+            filesystem: Option::None,
+            kv: Option::None,
+            plugins: RwLock::new(HashMap::new()),
+            failed: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// the filesystem granted to plugins with the "fs" capability, shared by all of them
+    pub fn with_filesystem(mut self, vfs: Arc<dyn Vfs>) -> Self
+    {
+        self.filesystem = Option::Some(vfs);
+        self
+    }
+
+    /// the store plugins with the "kv" capability get a namespace of
+    pub fn with_kv(mut self, store: Arc<KvStore>) -> Self
+    {
+        self.kv = Option::Some(store);
+        self
+    }
+
+    pub fn dir(&self) -> &Path
+    {
+        self.dir.as_path()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Plugin>>
+    {
+        self.plugins.read().ok()?.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String>
+    {
+        let mut names: Vec<String> = match self.plugins.read()
+        {
+            Ok(plugins) => plugins.keys().cloned().collect(),
+            Err(_) => vec![]
+        };
+        names.sort();
+        names
+    }
+
+    pub fn plugins(&self) -> Vec<Arc<Plugin>>
+    {
+        match self.plugins.read()
+        {
+            Ok(plugins) => plugins.values().cloned().collect(),
+            Err(_) => vec![]
+        }
+    }
+
+    /// emit an event to every loaded plugin. plugins without membrane_guest_on_event are
+    /// skipped, the ones whose handlers failed are returned by name
+    pub fn emit(&self, topic: &str, payload: &[u8]) -> Vec<(String, Error)>
+    {
+        let mut failed = vec![];
+        for plugin in self.plugins()
+        {
+            if let Err(error) = plugin.membrane.emit(topic, payload)
+            {
+                failed.push((plugin.name.clone(), error));
+            }
+        }
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        failed
+    }
+
+    /// the plugins that currently fail to load and why
+    pub fn failures(&self) -> Vec<PluginFailure>
+    {
+        match self.failed.read()
+        {
+            Ok(failed) => failed.iter().map(|(path, (_, error))| PluginFailure { path: path.clone(), error: error.clone() }).collect(),
+            Err(_) => vec![]
+        }
+    }
+
+    /// bring the registry in line with the directory: load new plugins, reload changed ones and
+    /// drop plugins whose wasm was removed. a changed plugin that fails to load keeps its
+    /// previous version running
+    pub fn sync(&self) -> Result<LoadReport, Error>
+    {
+        let mut report = LoadReport::default();
+        let mut seen = HashSet::new();
+
+        let mut paths = vec![];
+        for entry in fs::read_dir(&self.dir)?
+        {
+            let path = entry?.path();
+            if path.is_file() && path.extension().map(|ext| ext == "wasm").unwrap_or(false)
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        for path in paths
+        {
+            seen.insert(path.clone());
+            let stamp = match PluginRegistry::stamp(&path)
+            {
+                Ok(stamp) => stamp,
+                // the file went away while scanning, the next sync will unload it
+                Err(_) => continue
+            };
+
+            let existing = self.plugins.read()?.values().find(|plugin| plugin.wasm_path == path).cloned();
+            if existing.as_ref().map(|plugin| plugin.stamp == stamp).unwrap_or(false)
+            {
+                continue;
+            }
+            if self.failed.read()?.get(&path).map(|(failed, _)| *failed == stamp).unwrap_or(false)
+            {
+                continue;
+            }
+
+            match self.load(&path, stamp)
+            {
+                Ok(plugin) => {
+                    let mut plugins = self.plugins.write()?;
+                    if let Some(other) = plugins.get(&plugin.name)
+                    {
+                        if other.wasm_path != path
+                        {
+                            let error: Error = format!("plugin name '{}' is already used by {:?}", plugin.name, other.wasm_path).into();
+                            report.failed.push(PluginFailure { path: path.clone(), error: error.clone() });
+                            self.failed.write()?.insert(path, (stamp, error));
+                            continue;
+                        }
+                    }
+                    if let Some(existing) = existing
+                    {
+                        plugins.remove(&existing.name);
+                    }
+                    self.failed.write()?.remove(&path);
+                    report.loaded.push(plugin.name.clone());
+                    plugins.insert(plugin.name.clone(), Arc::new(plugin));
+                }
+                Err(error) => {
+                    report.failed.push(PluginFailure { path: path.clone(), error: error.clone() });
+                    self.failed.write()?.insert(path, (stamp, error));
+                }
+            }
+        }
+
+        self.plugins.write()?.retain(|name, plugin| {
+            if seen.contains(&plugin.wasm_path)
+            {
+                true
+            } else {
+                report.unloaded.push(name.clone());
+                false
+            }
+        });
+        self.failed.write()?.retain(|path, _| seen.contains(path));
+
+        Ok(report)
+    }
+
+    /// poll the directory every interval and sync changes until the returned watcher is stopped
+    /// or dropped. on_change is called for every sync that changed something
+    pub fn watch<F>(self: &Arc<Self>, interval: Duration, on_change: F) -> PluginWatcher
+        where F: Fn(&LoadReport) + Send + 'static
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let registry: Weak<PluginRegistry> = Arc::downgrade(self);
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed)
+                {
+                    let registry = match registry.upgrade()
+                    {
+                        Some(registry) => registry,
+                        None => return
+                    };
+                    match registry.sync()
+                    {
+                        Ok(report) => {
+                            if !report.is_empty()
+                            {
+                                on_change(&report);
+                            }
+                        }
+                        Err(error) => println!("PluginRegistry: could not scan {:?}: {}", registry.dir, error.error)
+                    }
+                    drop(registry);
+                    thread::park_timeout(interval);
+                }
+            })
+        };
+
+        PluginWatcher {
+            stop,
+            thread: Option::Some(thread),
+        }
+    }
+
+    fn stamp(wasm_path: &Path) -> Result<PluginStamp, Error>
+    {
+        let wasm = fs::metadata(wasm_path)?.modified()?;
+        let manifest = match fs::metadata(PluginRegistry::manifest_path(wasm_path))
+        {
+            Ok(metadata) => Option::Some(metadata.modified()?),
+            Err(_) => Option::None
+        };
+        Ok((wasm, manifest))
+    }
+
+    fn manifest_path(wasm_path: &Path) -> PathBuf
+    {
+        wasm_path.with_extension("plugin.toml")
+    }
+
+    fn load(&self, wasm_path: &Path, stamp: PluginStamp) -> Result<Plugin, Error>
+    {
+        let manifest_path = PluginRegistry::manifest_path(wasm_path);
+        let manifest = if manifest_path.exists()
+        {
+            PluginManifest::load(&manifest_path)?
+        } else {
+            PluginManifest::default()
+        };
+
+        let name = match &manifest.name
+        {
+            Some(name) => name.clone(),
+            None => wasm_path.file_stem().ok_or("plugin file has no name")?.to_string_lossy().to_string()
+        };
+
+        if let Some(unknown) = manifest.capabilities.iter().find(|capability| !CAPABILITIES.contains(&capability.as_str()))
+        {
+            return Err(format!("plugin {} asks for unknown capability '{}'", name, unknown).into());
+        }
+
+        let mut config = self.config.clone();
+        if manifest.fuel.is_some()
+        {
+            config.fuel = manifest.fuel;
+        }
+        if manifest.max_memory_pages.is_some()
+        {
+            config.max_memory_pages = manifest.max_memory_pages;
+        }
+
+        let module = WasmMembrane::compile_with_config(fs::read(wasm_path)?.as_slice(), &config)?;
+        let membrane = WasmMembrane::new_with_config(module, &config)?;
+        // lets host functions check the plugin's capabilities
+        membrane.extensions().insert(manifest.clone());
+        if manifest.has_capability("fs")
+        {
+            membrane.grant_filesystem(Option::Some(self.filesystem.clone().ok_or("plugin needs the fs capability but the registry has no filesystem")?))?;
+        }
+        if manifest.has_capability("kv")
+        {
+            membrane.grant_kv(Option::Some(self.kv.as_ref().ok_or("plugin needs the kv capability but the registry has no key-value store")?.namespace(name.as_str())))?;
+        }
+        if manifest.init.is_empty()
+        {
+            membrane.init()?;
+        } else {
+            membrane.init_with_json(&manifest.init)?;
+        }
+
+        Ok(Plugin {
+            name,
+            wasm_path: wasm_path.to_path_buf(),
+            manifest,
+            membrane,
+            stamp,
+        })
+    }
+}
+
+/// handle to the thread started by PluginRegistry::watch
+pub struct PluginWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PluginWatcher {
+    pub fn stop(&mut self)
+    {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take()
+        {
+            thread.thread().unpark();
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for PluginWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::kv::{KvStore, MemoryKvBackend};
+    use crate::plugin::{PluginManifest, PluginRegistry};
+
+    #[test]
+    pub fn test_manifest() -> Result<(), Error>
+    {
+        let manifest = PluginManifest::parse(r#"
+            name = "greeter"
+            capabilities = ["fs"]
+            fuel = 1000
+
+            [init]
+            greeting = "hello"
+        "#)?;
+        assert_eq!(manifest.name, Some("greeter".to_string()));
+        assert!(manifest.has_capability("fs"));
+        assert_eq!(manifest.fuel, Some(1000));
+        assert_eq!(manifest.max_memory_pages, None);
+        assert_eq!(manifest.init.get("greeting").and_then(|value| value.as_str()), Some("hello"));
+
+        assert!(PluginManifest::parse("unknown_field = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_registry() -> Result<(), Error>
+    {
+        let dir = env::temp_dir().join(format!("wasm_membrane_plugin_test_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::copy("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm", dir.join("example.wasm"))?;
+        fs::write(dir.join("broken.wasm"), "not wasm")?;
+        fs::write(dir.join("example.plugin.toml"), "name = \"renamed\"")?;
+
+        let registry = PluginRegistry::new(&dir, MembraneEngineConfig::default());
+        let report = registry.sync()?;
+        assert_eq!(report.loaded, vec!["renamed".to_string()]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(registry.get("renamed").is_some());
+
+        // nothing changed so nothing is retried
+        assert!(registry.sync()?.is_empty());
+
+        fs::remove_file(dir.join("example.wasm"))?;
+        assert_eq!(registry.sync()?.unloaded, vec!["renamed".to_string()]);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_capabilities() -> Result<(), Error>
+    {
+        let dir = env::temp_dir().join(format!("wasm_membrane_plugin_capabilities_test_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        for name in &["granted", "plain", "unknown"]
+        {
+            fs::copy("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm", dir.join(format!("{}.wasm", name)))?;
+        }
+        fs::write(dir.join("granted.plugin.toml"), "capabilities = [\"kv\"]")?;
+        fs::write(dir.join("unknown.plugin.toml"), "capabilities = [\"network\"]")?;
+
+        let registry = PluginRegistry::new(&dir, MembraneEngineConfig::default())
+            .with_kv(Arc::new(KvStore::new(Arc::new(MemoryKvBackend::new()))));
+        let report = registry.sync()?;
+        assert_eq!(report.failed.len(), 1);
+        assert!(registry.get("granted").ok_or("granted was not loaded")?.membrane.kv().is_ok());
+        let plain = registry.get("plain").ok_or("plain was not loaded")?;
+        assert!(plain.membrane.kv().is_err());
+        assert!(plain.membrane.filesystem().is_err());
+        assert!(registry.get("unknown").is_none());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use wasmer::Module;
+
+use crate::engine::MembraneEngineConfig;
+use crate::error::Error;
+use crate::membrane::WasmMembrane;
+use crate::snapshot::MembraneSnapshot;
+
+#[derive(Debug, Clone)]
+pub struct MembranePoolConfig {
+    /// membranes that are instantiated up front and kept alive even when idle
+    pub min_size: usize,
+    /// upper bound on idle plus checked out membranes
+    pub max_size: usize,
+    /// idle membranes beyond min_size are evicted after this long without use
+    pub idle_timeout: Option<Duration>,
+    /// how long checkout waits for a membrane to be returned when the pool is at max_size
+    pub checkout_timeout: Duration,
+    /// passed to WasmMembrane::init_with_config for every membrane the pool creates
+    pub init_config: Option<Vec<u8>>,
+    /// passed to WasmMembrane::new_with_config, should match the config the module was compiled with
+    pub engine_config: MembraneEngineConfig,
+}
+
+impl Default for MembranePoolConfig {
+    fn default() -> Self {
+        MembranePoolConfig {
+            min_size: 1,
+            max_size: 8,
+            idle_timeout: Option::Some(Duration::from_secs(60)),
+            checkout_timeout: Duration::from_secs(5),
+            init_config: Option::None,
+            engine_config: MembraneEngineConfig::default(),
+        }
+    }
+}
+
+/// a membrane and the snapshot taken right after its init, which it is reset to on checkin
+struct PoolEntry {
+    membrane: Arc<WasmMembrane>,
+    initialized: Arc<MembraneSnapshot>,
+}
+
+struct PoolState {
+    idle: Vec<(PoolEntry, Instant)>,
+    /// idle + checked out
+    size: usize,
+}
+
+/// keeps initialized membranes of one module ready so that a request can take one without
+/// paying for instantiation and init. membranes that come back are reset to their state right
+/// after init: the guest's memory and globals are restored and whatever the host attached
+/// since, such as extensions, host functions, a log sink or capability grants, is dropped, so
+/// nothing a request left in the guest or granted to it leaks into the next one.
+/// membranes that trapped are discarded
+pub struct MembranePool {
+    module: Arc<Module>,
+    config: MembranePoolConfig,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl MembranePool {
+    pub fn new(module: Arc<Module>, config: MembranePoolConfig) -> Result<Arc<Self>, Error>
+    {
+        if config.max_size == 0 || config.min_size > config.max_size
+        {
+            return Err(format!("invalid pool size min {} max {}", config.min_size, config.max_size).into());
+        }
+
+        let pool = Arc::new(MembranePool {
+            module,
+            config,
+            state: Mutex::new(PoolState { idle: vec![], size: 0 }),
+            returned: Condvar::new(),
+        });
+
+        for _ in 0..pool.config.min_size
+        {
+            let entry = pool.instantiate()?;
+            let mut state = pool.state.lock()?;
+            state.idle.push((entry, Instant::now()));
+            state.size += 1;
+        }
+
+        Ok(pool)
+    }
+
+    pub fn config(&self) -> &MembranePoolConfig
+    {
+        &self.config
+    }
+
+    /// number of membranes currently alive, idle or checked out
+    pub fn size(&self) -> Result<usize, Error>
+    {
+        Ok(self.state.lock()?.size)
+    }
+
+    pub fn idle(&self) -> Result<usize, Error>
+    {
+        Ok(self.state.lock()?.idle.len())
+    }
+
+    /// take an initialized membrane out of the pool. a new one is instantiated if none are
+    /// idle and the pool has not reached max_size, otherwise this waits for one to be returned
+    pub fn checkout(self: &Arc<Self>) -> Result<PooledMembrane, Error>
+    {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut state = self.state.lock()?;
+        loop {
+            if let Some((entry, _)) = state.idle.pop()
+            {
+                return Ok(PooledMembrane::new(self.clone(), entry));
+            }
+
+            if state.size < self.config.max_size
+            {
+                state.size += 1;
+                drop(state);
+                return match self.instantiate()
+                {
+                    Ok(entry) => Ok(PooledMembrane::new(self.clone(), entry)),
+                    Err(error) => {
+                        self.state.lock()?.size -= 1;
+                        Err(error)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline
+            {
+                return Err(format!("membrane pool exhausted: all {} membranes are checked out", self.config.max_size).into());
+            }
+            state = self.returned.wait_timeout(state, deadline - now)?.0;
+        }
+    }
+
+    /// drop idle membranes that have not been used within idle_timeout, never going below
+    /// min_size. returns how many were evicted
+    pub fn evict_idle(&self) -> Result<usize, Error>
+    {
+        let idle_timeout = match self.config.idle_timeout
+        {
+            Some(idle_timeout) => idle_timeout,
+            None => return Ok(0)
+        };
+
+        let mut state = self.state.lock()?;
+        let mut evicted = 0;
+        // the oldest returns are at the front of the idle list
+        while state.size > self.config.min_size && !state.idle.is_empty() && state.idle[0].1.elapsed() >= idle_timeout
+        {
+            state.idle.remove(0);
+            state.size -= 1;
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    fn instantiate(&self) -> Result<PoolEntry, Error>
+    {
+        let membrane = WasmMembrane::new_with_config(self.module.clone(), &self.config.engine_config)?;
+        membrane.init_with_config(self.config.init_config.as_deref())?;
+        let initialized = Arc::new(membrane.snapshot()?);
+        Ok(PoolEntry {
+            membrane,
+            initialized,
+        })
+    }
+
+    /// put the membrane back into the state it had right after init
+    fn reset(entry: &PoolEntry) -> Result<(), Error>
+    {
+        entry.membrane.clear_host_state()?;
+        entry.membrane.restore(&entry.initialized)
+    }
+
+    fn checkin(&self, entry: PoolEntry, discard: bool)
+    {
+        let discard = discard || entry.membrane.trapped() || match MembranePool::reset(&entry)
+        {
+            Ok(_) => false,
+            Err(error) => {
+                println!("WasmMembrane: could not reset pool membrane: {}", error.error);
+                true
+            }
+        };
+
+        let mut state = match self.state.lock()
+        {
+            Ok(state) => state,
+            Err(_) => return
+        };
+
+        if discard
+        {
+            state.size -= 1;
+            // keep the pool warm by replacing the discarded membrane if it dropped below min_size
+            if state.size < self.config.min_size
+            {
+                state.size += 1;
+                drop(state);
+                match self.instantiate()
+                {
+                    Ok(replacement) => {
+                        if let Ok(mut state) = self.state.lock()
+                        {
+                            state.idle.push((replacement, Instant::now()));
+                        }
+                    }
+                    Err(error) => {
+                        println!("WasmMembrane: could not replace discarded pool membrane: {}", error.error);
+                        if let Ok(mut state) = self.state.lock()
+                        {
+                            state.size -= 1;
+                        }
+                    }
+                }
+            }
+        } else {
+            state.idle.push((entry, Instant::now()));
+        }
+
+        self.returned.notify_one();
+    }
+}
+
+/// a membrane checked out of a MembranePool. it goes back to the pool when dropped
+pub struct PooledMembrane {
+    pool: Arc<MembranePool>,
+    entry: Option<PoolEntry>,
+    discard: bool,
+}
+
+impl PooledMembrane {
+    fn new(pool: Arc<MembranePool>, entry: PoolEntry) -> Self
+    {
+        PooledMembrane {
+            pool,
+            entry: Option::Some(entry),
+            discard: false,
+        }
+    }
+
+    /// do not return this membrane to the pool even if it did not trap
+    pub fn discard(&mut self)
+    {
+        self.discard = true;
+    }
+
+    pub fn membrane(&self) -> Arc<WasmMembrane>
+    {
+        self.entry.as_ref().unwrap().membrane.clone()
+    }
+}
+
+impl Deref for PooledMembrane {
+    type Target = WasmMembrane;
+
+    fn deref(&self) -> &WasmMembrane {
+        &self.entry.as_ref().unwrap().membrane
+    }
+}
+
+impl Drop for PooledMembrane {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take()
+        {
+            self.pool.checkin(entry, self.discard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::fs::File;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::error::Error;
+    use crate::kv::{KvStore, MemoryKvBackend};
+    use crate::membrane::{MembraneState, WasmMembrane};
+    use crate::pool::{MembranePool, MembranePoolConfig};
+
+    fn pool(config: MembranePoolConfig) -> Result<std::sync::Arc<MembranePool>, Error>
+    {
+        let path = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        MembranePool::new(WasmMembrane::compile(data.as_slice())?, config)
+    }
+
+    #[test]
+    pub fn test_pool() -> Result<(), Error>
+    {
+        let pool = pool(MembranePoolConfig {
+            min_size: 1,
+            max_size: 2,
+            idle_timeout: Option::Some(Duration::from_millis(0)),
+            checkout_timeout: Duration::from_millis(10),
+            init_config: Option::None,
+            ..Default::default()
+        })?;
+        assert_eq!(pool.size()?, 1);
+
+        {
+            let first = pool.checkout()?;
+            let mut second = pool.checkout()?;
+            first.test_log()?;
+            assert!(pool.checkout().is_err());
+            second.discard();
+        }
+
+        assert_eq!(pool.size()?, 1);
+        assert_eq!(pool.idle()?, 1);
+        assert_eq!(pool.evict_idle()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_pool_threads() -> Result<(), Error>
+    {
+        let pool = pool(MembranePoolConfig {
+            min_size: 1,
+            max_size: 2,
+            checkout_timeout: Duration::from_secs(5),
+            ..Default::default()
+        })?;
+
+        let workers: Vec<_> = (0..4).map(|_| {
+            let pool = pool.clone();
+            std::thread::spawn(move || -> Result<(), Error> {
+                for _ in 0..3
+                {
+                    pool.checkout()?.test_log()?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for worker in workers
+        {
+            worker.join().map_err(|_| "pool worker panicked")??;
+        }
+
+        assert!(pool.size()? <= 2);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_pool_resets_on_checkin() -> Result<(), Error>
+    {
+        let pool = pool(MembranePoolConfig {
+            min_size: 1,
+            max_size: 1,
+            ..Default::default()
+        })?;
+
+        let initialized = pool.checkout()?.snapshot()?;
+        {
+            let store = Arc::new(KvStore::new(Arc::new(MemoryKvBackend::new())));
+            let membrane = pool.checkout()?;
+            membrane.write_string("left behind by a request")?;
+            membrane.grant_kv(Option::Some(store.namespace("pool")))?;
+            membrane.test_log()?;
+            assert_eq!(membrane.state(), MembraneState::Running);
+        }
+
+        let membrane = pool.checkout()?;
+        assert_eq!(membrane.state(), MembraneState::Initialized);
+        assert_eq!(membrane.snapshot()?, initialized);
+        assert!(membrane.kv().is_err());
+        Ok(())
+    }
+
+    struct Tenant(&'static str);
+
+    #[test]
+    pub fn test_pool_drops_host_state_on_checkin() -> Result<(), Error>
+    {
+        let pool = pool(MembranePoolConfig {
+            min_size: 1,
+            max_size: 1,
+            ..Default::default()
+        })?;
+
+        {
+            let membrane = pool.checkout()?;
+            membrane.extensions().insert(Tenant("acme"));
+            membrane.register_host_function("pong", Arc::new(|_: &WasmMembrane, _: &[u8]| Ok(b"pong".to_vec())))?;
+            assert_eq!(membrane.extensions().get::<Tenant>().ok_or("extension was not inserted")?.0, "acme");
+        }
+
+        let membrane = pool.checkout()?;
+        assert!(!membrane.extensions().contains::<Tenant>());
+        assert!(membrane.call_host_function("pong", &[]).is_err());
+        assert!(membrane.log_sink().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::engine::MembraneEngineConfig;
+use crate::error::Error;
+use crate::membrane::WasmMembrane;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// increases by one for every successful reload
+    pub generation: u64,
+    /// true when state was handed from the old instance to the new one
+    pub state_migrated: bool,
+}
+
+/// a membrane whose module can be swapped while the host keeps running.
+///
+/// the new module is compiled and initialized next to the old one and only replaces it when
+/// every step passed. callers that still hold the previous Arc<WasmMembrane> keep using the
+/// old instance until they fetch the current one again
+pub struct ReloadableMembrane {
+    current: RwLock<(Arc<WasmMembrane>, u64)>,
+    config: MembraneEngineConfig,
+}
+
+impl ReloadableMembrane {
+    pub fn new(membrane: Arc<WasmMembrane>, config: MembraneEngineConfig) -> Self
+    {
+        ReloadableMembrane {
+            current: RwLock::new((membrane, 0)),
+            config,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], config: MembraneEngineConfig) -> Result<Self, Error>
+    {
+        let membrane = WasmMembrane::from_bytes_with_config(bytes, &config)?;
+        Ok(ReloadableMembrane::new(membrane, config))
+    }
+
+    /// the instance that is currently live
+    pub fn membrane(&self) -> Result<Arc<WasmMembrane>, Error>
+    {
+        Ok(self.current.read()?.0.clone())
+    }
+
+    pub fn generation(&self) -> Result<u64, Error>
+    {
+        Ok(self.current.read()?.1)
+    }
+
+    pub fn reload_file<P: AsRef<Path>>(&self, path: P, migrate_state: bool) -> Result<ReloadReport, Error>
+    {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        self.reload(data.as_slice(), migrate_state)
+    }
+
+    /// compile and init the new module and swap it in. the new instance gets the old one's init
+    /// config, log sink, extensions, host functions, filesystem and key-value grants and takes
+    /// over its name on the bus. when migrate_state is set the buffer
+    /// returned by the old instance's membrane_guest_export_state() is passed to the new
+    /// instance's membrane_guest_import_state(buffer). on any failure the old instance stays
+    /// live and the error says which step failed
+    pub fn reload(&self, bytes: &[u8], migrate_state: bool) -> Result<ReloadReport, Error>
+    {
+        let old = self.membrane()?;
+
+        let module = WasmMembrane::compile_with_config(bytes, &self.config)
+            .map_err(|e| reload_error("compile", e))?;
+        let new = WasmMembrane::new_with_config(module, &self.config).map_err(|e| reload_error("instantiate", e))?;
+        new.inherit_host_state(&old)?;
+        new.init_with_config(old.init_config().as_deref()).map_err(|e| reload_error("init", e))?;
+
+        // hold the write lock while state moves so nobody fetches the old instance
+        // after its state has been exported
+        let mut current = self.current.write()?;
+        if !Arc::ptr_eq(&current.0, &old)
+        {
+            return Err("reload failed: the membrane was reloaded concurrently".into());
+        }
+
+        let state_migrated = if migrate_state
+        {
+            ReloadableMembrane::migrate_state(&old, &new)?
+        } else {
+            false
+        };
+
+        if let Some((bus, name)) = old.bus_registration()?
+        {
+            bus.unregister(name.as_str())?;
+            bus.register(name.as_str(), &new).map_err(|e| reload_error("bus registration", e))?;
+        }
+
+        let generation = current.1 + 1;
+        *current = (new, generation);
+        old.log("wasm", format!("reloaded: generation {} (state migrated: {})", generation, state_migrated).as_str());
+
+        Ok(ReloadReport {
+            generation,
+            state_migrated,
+        })
+    }
+
+    /// returns false when neither side takes part in state handoff
+    fn migrate_state(old: &WasmMembrane, new: &WasmMembrane) -> Result<bool, Error>
+    {
+        let export = old.instance.exports.get_native_function::<(), i32>("membrane_guest_export_state");
+        let import = new.instance.exports.get_native_function::<i32, ()>("membrane_guest_import_state");
+
+        match (export, import)
+        {
+            (Err(_), Err(_)) => Ok(false),
+            (Ok(_), Err(_)) => Err("reload failed: the old module exports state but the new module has no membrane_guest_import_state( i32 )".into()),
+            (Err(_), Ok(_)) => Err("reload failed: the new module imports state but the old module has no membrane_guest_export_state( ) -> i32".into()),
+            (Ok(_), Ok(_)) => {
+                let buffer_id = old.call_export("membrane_guest_export_state", &[])
+                    .map_err(|e| reload_error("export state", e))?;
+                let buffer_id = buffer_id.first().and_then(|val| val.i32()).ok_or("reload failed: membrane_guest_export_state did not return a buffer id")?;
+                let state = old.read_buffer(buffer_id).map_err(|e| reload_error("export state", e))?;
+                old.dealloc_buffer(buffer_id).unwrap_or(());
+
+                let buffer_id = new.write_buffer(&state).map_err(|e| reload_error("import state", e))?;
+                new.call_export("membrane_guest_import_state", &[buffer_id.into()])
+                    .map_err(|e| reload_error("import state", e))?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+fn reload_error(step: &str, error: Error) -> Error
+{
+    format!("reload failed during {}: {}", step, error.error).into()
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::reload::ReloadableMembrane;
+
+    static WASM_PATH: &str = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+
+    struct Tenant(&'static str);
+
+    #[test]
+    pub fn test_reload() -> Result<(), Error>
+    {
+        let data = std::fs::read(WASM_PATH)?;
+        let config = MembraneEngineConfig::default();
+        let membrane = WasmMembrane::new_with_config(WasmMembrane::compile_with_config(data.as_slice(), &config)?, &config)?;
+
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink_lines = lines.clone();
+        membrane.set_log_sink(Option::Some(Arc::new(move |_: &WasmMembrane, log_type: &str, message: &str| {
+            sink_lines.lock().unwrap().push(format!("{}: {}", log_type, message));
+        })))?;
+        membrane.extensions().insert(Tenant("acme"));
+        membrane.register_host_function("pong", Arc::new(|_: &WasmMembrane, _: &[u8]| Ok(b"pong".to_vec())))?;
+        let mut init_config = HashMap::new();
+        init_config.insert("greeting", "hello again");
+        membrane.init_with_json(&init_config)?;
+        membrane.emit("remember", b"kept across reloads")?;
+
+        let reloadable = ReloadableMembrane::new(membrane, config);
+        let before = reloadable.membrane()?;
+
+        assert!(reloadable.reload(b"not wasm", true).is_err());
+        assert!(Arc::ptr_eq(&before, &reloadable.membrane()?));
+
+        let report = reloadable.reload_file(WASM_PATH, true)?;
+        assert_eq!(report.generation, 1);
+        assert!(report.state_migrated);
+
+        let after = reloadable.membrane()?;
+        after.emit("recall", &[])?;
+        let lines = lines.lock().unwrap();
+        // the new instance was initialized with the same config and logs through the same sink
+        assert_eq!(lines.iter().filter(|line| *line == "guest: hello again").count(), 2);
+        assert!(lines.contains(&"guest: remembered: kept across reloads".to_string()));
+        assert_eq!(after.extensions().get::<Tenant>().ok_or("extension was not carried over")?.0, "acme");
+        assert_eq!(after.call_host_function("pong", &[])?, b"pong".to_vec());
+
+        Ok(())
+    }
+}
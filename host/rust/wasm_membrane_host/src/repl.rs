@@ -0,0 +1,389 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use wasmer::{ExternType, Type, Val};
+
+use crate::error::Error;
+use crate::membrane::WasmMembrane;
+
+static HELP: &str = "commands:
+  exports                       list the exports of the module
+  call <export> [args..]        call an export. args may be numbers, \"strings\", 0xHEX or JSON ({..} or [..]);
+                                strings, hex and JSON are written to a new buffer and passed by buffer id
+  buffer <id> [string|hex|json] view the contents of a buffer (default: string)
+  alloc <len>                   allocate a buffer of len bytes
+  write <arg>                   write a \"string\", 0xHEX or JSON value to a new buffer
+  free <id>                     deallocate a buffer
+  logs [on|off|clear]           show collected logs, or toggle printing guest logs as they happen
+  reload                        reload the module from disk and run init again
+  help                          show this message
+  quit                          leave the repl";
+
+/// interactive shell for poking at a single membrane
+pub struct Repl {
+    path: PathBuf,
+    membrane: Arc<WasmMembrane>,
+    logs: Arc<Mutex<Vec<(String, String)>>>,
+    live: Arc<AtomicBool>,
+}
+
+impl Repl {
+    pub fn new(path: &Path) -> Result<Self, Error>
+    {
+        let logs = Arc::new(Mutex::new(vec![]));
+        let live = Arc::new(AtomicBool::new(true));
+        let membrane = Repl::load(path, logs.clone(), live.clone())?;
+
+        Ok(Repl {
+            path: path.to_path_buf(),
+            membrane,
+            logs,
+            live,
+        })
+    }
+
+    fn load(path: &Path, logs: Arc<Mutex<Vec<(String, String)>>>, live: Arc<AtomicBool>) -> Result<Arc<WasmMembrane>, Error>
+    {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let membrane = WasmMembrane::new(WasmMembrane::compile(data.as_slice())?)?;
+
+        membrane.set_log_sink(Option::Some(Arc::new(move |_: &WasmMembrane, log_type: &str, message: &str| {
+            if live.load(Ordering::Relaxed)
+            {
+                println!("{} : {}", log_type, message);
+            }
+            if let Ok(mut logs) = logs.lock()
+            {
+                logs.push((log_type.to_string(), message.to_string()));
+            }
+        })))?;
+
+        membrane.init()?;
+
+        Ok(membrane)
+    }
+
+    pub fn membrane(&self) -> Arc<WasmMembrane>
+    {
+        self.membrane.clone()
+    }
+
+    /// read commands from stdin until quit or end of input
+    pub fn run(&mut self) -> Result<(), Error>
+    {
+        println!("wasm-membrane repl: {:?} (type 'help' for commands)", self.path);
+        let stdin = io::stdin();
+        loop {
+            print!("membrane> ");
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0
+            {
+                return Ok(());
+            }
+
+            match self.execute(line.trim())
+            {
+                Ok(Option::Some(output)) => {
+                    if !output.is_empty()
+                    {
+                        println!("{}", output);
+                    }
+                }
+                Ok(Option::None) => return Ok(()),
+                Err(e) => println!("error: {}", e.error)
+            }
+        }
+    }
+
+    /// execute a single command line. returns None when the repl should exit
+    pub fn execute(&mut self, line: &str) -> Result<Option<String>, Error>
+    {
+        let tokens = tokenize(line)?;
+        if tokens.is_empty()
+        {
+            return Ok(Option::Some(String::new()));
+        }
+
+        let args = &tokens[1..];
+        let output = match tokens[0].as_str()
+        {
+            "help" => HELP.to_string(),
+            "quit" | "exit" => return Ok(Option::None),
+            "exports" => self.list_exports(),
+            "call" => self.call(args)?,
+            "buffer" => self.view_buffer(args)?,
+            "alloc" => {
+                let len = parse_i32(arg(args, 0, "len")?)?;
+                format!("buffer {}", self.membrane.alloc_buffer(len)?)
+            }
+            "write" => {
+                let bytes = parse_bytes(arg(args, 0, "value")?)?.ok_or("write expects a \"string\", 0xHEX or JSON value")?;
+                format!("buffer {}", self.membrane.write_buffer(&bytes)?)
+            }
+            "free" => {
+                let buffer_id = parse_i32(arg(args, 0, "id")?)?;
+                self.membrane.dealloc_buffer(buffer_id)?;
+                format!("freed buffer {}", buffer_id)
+            }
+            "logs" => self.logs(args)?,
+            "reload" => {
+                self.membrane = Repl::load(&self.path, self.logs.clone(), self.live.clone())?;
+                format!("reloaded {:?}", self.path)
+            }
+            command => return Err(format!("unknown command '{}' (type 'help' for commands)", command).into())
+        };
+
+        Ok(Option::Some(output))
+    }
+
+    fn list_exports(&self) -> String
+    {
+        let mut exports = self.membrane.exports();
+        exports.sort_by(|a, b| a.0.cmp(&b.0));
+        exports.iter().map(|(name, ty)| match ty {
+            ExternType::Function(func) => format!("fn     {}{:?} -> {:?}", name, func.params(), func.results()),
+            ExternType::Memory(memory) => format!("memory {} {:?}", name, memory),
+            ExternType::Global(global) => format!("global {} {:?}", name, global),
+            ExternType::Table(table) => format!("table  {} {:?}", name, table),
+        }).collect::<Vec<String>>().join("\n")
+    }
+
+    fn call(&self, args: &[String]) -> Result<String, Error>
+    {
+        let name = arg(args, 0, "export")?;
+        let func = self.membrane.instance.exports.get_function(name)?;
+        let params = func.ty().params().to_vec();
+        let args = &args[1..];
+        if params.len() != args.len()
+        {
+            return Err(format!("{} expects {} arguments but {} were given", name, params.len(), args.len()).into());
+        }
+
+        let mut values = vec![];
+        for (ty, arg) in params.iter().zip(args.iter())
+        {
+            values.push(self.to_val(ty, arg)?);
+        }
+
+        let results = self.membrane.call_export(name, values.as_slice())?;
+        if results.is_empty()
+        {
+            return Ok("ok".to_string());
+        }
+        Ok(results.iter().map(|result| format!("{:?}", result)).collect::<Vec<String>>().join(", "))
+    }
+
+    fn to_val(&self, ty: &Type, arg: &str) -> Result<Val, Error>
+    {
+        if let Some(bytes) = parse_bytes(arg)?
+        {
+            if *ty != Type::I32
+            {
+                return Err(format!("'{}' is passed as a buffer id which needs an i32 parameter, not {:?}", arg, ty).into());
+            }
+            return Ok(Val::I32(self.membrane.write_buffer(&bytes)?));
+        }
+
+        let val = match ty
+        {
+            Type::I32 => Val::I32(parse_i32(arg)?),
+            Type::I64 => Val::I64(arg.parse().map_err(|_| format!("'{}' is not an i64", arg))?),
+            Type::F32 => Val::F32(arg.parse().map_err(|_| format!("'{}' is not an f32", arg))?),
+            Type::F64 => Val::F64(arg.parse().map_err(|_| format!("'{}' is not an f64", arg))?),
+            ty => return Err(format!("cannot pass arguments of type {:?} from the repl", ty).into())
+        };
+        Ok(val)
+    }
+
+    fn view_buffer(&self, args: &[String]) -> Result<String, Error>
+    {
+        let buffer_id = parse_i32(arg(args, 0, "id")?)?;
+        let bytes = self.membrane.read_buffer(buffer_id)?;
+        let format = args.get(1).map(|format| format.as_str()).unwrap_or("string");
+        let output = match format
+        {
+            "string" => String::from_utf8_lossy(&bytes).to_string(),
+            "hex" => to_hex(&bytes),
+            "json" => {
+                let value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| format!("buffer {} is not json: {}", buffer_id, e))?;
+                serde_json::to_string_pretty(&value).map_err(|e| e.to_string())?
+            }
+            format => return Err(format!("unknown buffer format '{}' (expected string, hex or json)", format).into())
+        };
+        Ok(format!("buffer {} ({} bytes)\n{}", buffer_id, bytes.len(), output))
+    }
+
+    fn logs(&self, args: &[String]) -> Result<String, Error>
+    {
+        match args.first().map(|arg| arg.as_str())
+        {
+            Option::None => {
+                let logs = self.logs.lock()?;
+                Ok(logs.iter().map(|(log_type, message)| format!("{} : {}", log_type, message)).collect::<Vec<String>>().join("\n"))
+            }
+            Option::Some("on") => {
+                self.live.store(true, Ordering::Relaxed);
+                Ok("live logs on".to_string())
+            }
+            Option::Some("off") => {
+                self.live.store(false, Ordering::Relaxed);
+                Ok("live logs off".to_string())
+            }
+            Option::Some("clear") => {
+                self.logs.lock()?.clear();
+                Ok("logs cleared".to_string())
+            }
+            Option::Some(other) => Err(format!("unknown logs option '{}'", other).into())
+        }
+    }
+}
+
+fn arg<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str, Error>
+{
+    match args.get(index)
+    {
+        Some(arg) => Ok(arg.as_str()),
+        None => Err(format!("missing argument <{}>", name).into())
+    }
+}
+
+fn parse_i32(arg: &str) -> Result<i32, Error>
+{
+    arg.parse::<i32>().map_err(|_| format!("'{}' is not an i32", arg).into())
+}
+
+/// returns the bytes to write into a buffer for string, hex and json arguments
+/// or None if the argument should be treated as a number
+fn parse_bytes(arg: &str) -> Result<Option<Vec<u8>>, Error>
+{
+    if arg.starts_with('"')
+    {
+        let string: String = serde_json::from_str(arg).map_err(|e| format!("bad string {}: {}", arg, e))?;
+        return Ok(Option::Some(string.into_bytes()));
+    }
+
+    if let Some(hex) = arg.strip_prefix("0x")
+    {
+        return Ok(Option::Some(from_hex(hex)?));
+    }
+
+    if arg.starts_with('{') || arg.starts_with('[')
+    {
+        let value: serde_json::Value = serde_json::from_str(arg).map_err(|e| format!("bad json {}: {}", arg, e))?;
+        return Ok(Option::Some(value.to_string().into_bytes()));
+    }
+
+    Ok(Option::None)
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, Error>
+{
+    if !hex.len().is_multiple_of(2)
+    {
+        return Err(format!("hex value '{}' has an odd number of digits", hex).into());
+    }
+
+    let mut rtn = vec![];
+    for i in (0..hex.len()).step_by(2)
+    {
+        let byte = hex.get(i..i + 2).ok_or(format!("'{}' is not hex", hex))?;
+        rtn.push(u8::from_str_radix(byte, 16).map_err(|_| format!("'{}' is not hex", hex))?);
+    }
+    Ok(rtn)
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ")
+}
+
+/// split a line on whitespace while keeping "quoted strings" and {json} / [json] values together
+fn tokenize(line: &str) -> Result<Vec<String>, Error>
+{
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.peek().cloned()
+    {
+        if c.is_whitespace()
+        {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        while let Some(c) = chars.peek().cloned()
+        {
+            if !in_string && depth == 0 && c.is_whitespace()
+            {
+                break;
+            }
+            chars.next();
+            token.push(c);
+
+            if in_string
+            {
+                if escaped
+                {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = false;
+                }
+            } else {
+                match c
+                {
+                    '"' => in_string = true,
+                    '{' | '[' => depth += 1,
+                    '}' | ']' => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+
+        if in_string || depth != 0
+        {
+            return Err(format!("unterminated argument: {}", token).into());
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::repl::{from_hex, parse_bytes, tokenize};
+
+    #[test]
+    pub fn test_tokenize()
+    {
+        let tokens = tokenize(r#"call some_export 42 "hello world" {"a": [1, 2]} 0x0aff"#).unwrap();
+        assert_eq!(tokens, vec!["call", "some_export", "42", "\"hello world\"", "{\"a\": [1, 2]}", "0x0aff"]);
+        assert!(tokenize("call x \"unterminated").is_err());
+    }
+
+    #[test]
+    pub fn test_parse_bytes()
+    {
+        assert_eq!(parse_bytes("\"hi\"").unwrap(), Some(b"hi".to_vec()));
+        assert_eq!(parse_bytes("0x0aff").unwrap(), Some(vec![0x0a, 0xff]));
+        assert_eq!(parse_bytes("{\"a\": 1}").unwrap(), Some(b"{\"a\":1}".to_vec()));
+        assert_eq!(parse_bytes("12").unwrap(), None);
+        assert!(from_hex("abc").is_err());
+    }
+}
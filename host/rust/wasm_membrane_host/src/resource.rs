@@ -0,0 +1,168 @@
+use std::any::{type_name, Any};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+
+/// how many resources a membrane may hold unless ResourceTable::set_limit says otherwise
+pub static DEFAULT_RESOURCE_LIMIT: usize = 1024;
+
+struct Resource {
+    value: Arc<dyn Any + Send + Sync>,
+    type_name: &'static str,
+}
+
+struct Resources {
+    entries: HashMap<u32, Resource>,
+    next_handle: u32,
+}
+
+/// host objects a guest refers to by u32 handle, such as open files, db cursors or sockets.
+/// the guest only ever sees the handle. host functions resolve it with get, which checks the
+/// type. resources are dropped when the guest releases them through
+/// membrane_host_release_resource or when the membrane is dropped.
+/// wrap objects that need to change in a Mutex
+pub struct ResourceTable {
+    resources: Mutex<Resources>,
+    limit: AtomicUsize,
+}
+
+impl Default for ResourceTable {
+    fn default() -> Self {
+        ResourceTable {
+            resources: Mutex::new(Resources {
+                entries: HashMap::new(),
+                next_handle: 1,
+            }),
+            limit: AtomicUsize::new(DEFAULT_RESOURCE_LIMIT),
+        }
+    }
+}
+
+impl ResourceTable {
+    pub fn new() -> Self
+    {
+        ResourceTable::default()
+    }
+
+    /// resources beyond limit are refused. lowering the limit does not drop resources already held
+    pub fn set_limit(&self, limit: usize)
+    {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> usize
+    {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// store value and return the handle to give to the guest. handles are never 0
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Result<u32, Error>
+    {
+        let mut resources = self.resources.lock()?;
+        if resources.entries.len() >= self.limit()
+        {
+            return Err(format!("membrane holds the maximum of {} resources", self.limit()).into());
+        }
+
+        // handles are not reused until the counter wraps, so a stale handle rarely finds a new resource
+        let mut handle = resources.next_handle;
+        while handle == 0 || resources.entries.contains_key(&handle)
+        {
+            handle = handle.wrapping_add(1);
+        }
+        resources.next_handle = handle.wrapping_add(1);
+
+        resources.entries.insert(handle, Resource {
+            value: Arc::new(value),
+            type_name: type_name::<T>(),
+        });
+        Ok(handle)
+    }
+
+    /// the resource behind handle, failing when there is none or it is not a T
+    pub fn get<T: Any + Send + Sync>(&self, handle: u32) -> Result<Arc<T>, Error>
+    {
+        let resources = self.resources.lock()?;
+        let resource = resources.entries.get(&handle).ok_or_else(|| format!("there is no resource with handle {}", handle))?;
+        match resource.value.clone().downcast::<T>()
+        {
+            Ok(value) => Ok(value),
+            Err(_) => Err(format!("resource {} is a {} not a {}", handle, resource.type_name, type_name::<T>()).into())
+        }
+    }
+
+    /// take the resource out of the table. it is dropped once the last Arc to it is gone
+    pub fn remove<T: Any + Send + Sync>(&self, handle: u32) -> Result<Arc<T>, Error>
+    {
+        let value = self.get::<T>(handle)?;
+        self.release(handle)?;
+        Ok(value)
+    }
+
+    /// drop the resource whatever its type
+    pub fn release(&self, handle: u32) -> Result<(), Error>
+    {
+        let resource = self.resources.lock()?.entries.remove(&handle);
+        match resource
+        {
+            Some(_) => Ok(()),
+            None => Err(format!("there is no resource with handle {}", handle).into())
+        }
+    }
+
+    pub fn clear(&self) -> Result<(), Error>
+    {
+        let entries = std::mem::take(&mut self.resources.lock()?.entries);
+        // resources are dropped outside the lock in case their Drop uses the table
+        drop(entries);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.resources.lock().map(|resources| resources.entries.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::Mutex;
+
+    use crate::error::Error;
+    use crate::resource::ResourceTable;
+
+    struct Cursor {
+        position: usize,
+    }
+
+    #[test]
+    pub fn test_resources() -> Result<(), Error>
+    {
+        let table = ResourceTable::new();
+        table.set_limit(2);
+
+        let cursor = table.insert(Mutex::new(Cursor { position: 0 }))?;
+        let name = table.insert("file.txt".to_string())?;
+        assert!(table.insert(0u8).is_err());
+        assert_ne!(cursor, name);
+
+        table.get::<Mutex<Cursor>>(cursor)?.lock()?.position += 10;
+        assert_eq!(table.get::<Mutex<Cursor>>(cursor)?.lock()?.position, 10);
+        assert!(table.get::<String>(cursor).is_err());
+
+        assert_eq!(table.remove::<String>(name)?.as_str(), "file.txt");
+        assert!(table.get::<String>(name).is_err());
+        table.release(cursor)?;
+        assert!(table.release(cursor).is_err());
+        assert!(table.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use wasmer::Val;
+
+use crate::error::Error;
+use crate::membrane::MembraneState;
+
+static SNAPSHOT_MAGIC: &[u8] = b"WMSNAP\0\0";
+static SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+/// the value of a global captured by a snapshot. unlike wasmer::Val it holds no references,
+/// so a snapshot can be shared between threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    /// the bits of the float, so that NaNs compare equal
+    F32(u32),
+    F64(u64),
+    V128(u128),
+}
+
+impl GlobalValue {
+    /// fails for reference typed values, which cannot outlive the instance they belong to
+    pub fn from_val(val: &Val) -> Result<Self, Error>
+    {
+        match val
+        {
+            Val::I32(value) => Ok(GlobalValue::I32(*value)),
+            Val::I64(value) => Ok(GlobalValue::I64(*value)),
+            Val::F32(value) => Ok(GlobalValue::F32(value.to_bits())),
+            Val::F64(value) => Ok(GlobalValue::F64(value.to_bits())),
+            Val::V128(value) => Ok(GlobalValue::V128(*value)),
+            val => Err(format!("{:?} is a reference which cannot be snapshotted", val).into())
+        }
+    }
+
+    pub fn to_val(&self) -> Val
+    {
+        match self
+        {
+            GlobalValue::I32(value) => Val::I32(*value),
+            GlobalValue::I64(value) => Val::I64(*value),
+            GlobalValue::F32(bits) => Val::F32(f32::from_bits(*bits)),
+            GlobalValue::F64(bits) => Val::F64(f64::from_bits(*bits)),
+            GlobalValue::V128(value) => Val::V128(*value),
+        }
+    }
+}
+
+/// the state of a membrane between calls: its linear memory, its exported globals and the
+/// host side bookkeeping. taken with WasmMembrane::snapshot and put back with WasmMembrane::restore
+#[derive(Debug, Clone, PartialEq)]
+pub struct MembraneSnapshot {
+    /// identifies the module, a snapshot may only be restored into a membrane created
+    /// from the same module
+    pub fingerprint: [u8; 32],
+    pub memory: Vec<u8>,
+    pub globals: Vec<(String, GlobalValue)>,
+    pub state: MembraneState,
+}
+
+impl MembraneSnapshot {
+    pub fn serialize(&self) -> Result<Vec<u8>, Error>
+    {
+        let mut data = Vec::with_capacity(self.memory.len() + 128);
+        data.extend_from_slice(SNAPSHOT_MAGIC);
+        data.extend_from_slice(&SNAPSHOT_FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&self.fingerprint);
+        data.push(self.state.to_u8());
+
+        data.extend_from_slice(&(self.globals.len() as u32).to_le_bytes());
+        for (name, val) in &self.globals
+        {
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            match val
+            {
+                GlobalValue::I32(value) => {
+                    data.push(0);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                GlobalValue::I64(value) => {
+                    data.push(1);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                GlobalValue::F32(bits) => {
+                    data.push(2);
+                    data.extend_from_slice(&bits.to_le_bytes());
+                }
+                GlobalValue::F64(bits) => {
+                    data.push(3);
+                    data.extend_from_slice(&bits.to_le_bytes());
+                }
+                GlobalValue::V128(value) => {
+                    data.push(4);
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+
+        data.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        data.extend_from_slice(self.memory.as_slice());
+        Ok(data)
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, Error>
+    {
+        let mut reader = SnapshotReader { data, offset: 0 };
+        if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC
+        {
+            return Err("not a membrane snapshot".into());
+        }
+        let version = u32::from_le_bytes(reader.array()?);
+        if version != SNAPSHOT_FORMAT_VERSION
+        {
+            return Err(format!("snapshot has format version {} expected {}", version, SNAPSHOT_FORMAT_VERSION).into());
+        }
+        let fingerprint = reader.array()?;
+        let state = MembraneState::from_u8(reader.take(1)?[0])?;
+
+        let global_count = u32::from_le_bytes(reader.array()?);
+        let mut globals = vec![];
+        for _ in 0..global_count
+        {
+            let name_len = u32::from_le_bytes(reader.array()?) as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec())?;
+            let val = match reader.take(1)?[0]
+            {
+                0 => GlobalValue::I32(i32::from_le_bytes(reader.array()?)),
+                1 => GlobalValue::I64(i64::from_le_bytes(reader.array()?)),
+                2 => GlobalValue::F32(u32::from_le_bytes(reader.array()?)),
+                3 => GlobalValue::F64(u64::from_le_bytes(reader.array()?)),
+                4 => GlobalValue::V128(u128::from_le_bytes(reader.array()?)),
+                tag => return Err(format!("snapshot global {} has unknown type tag {}", name, tag).into())
+            };
+            globals.push((name, val));
+        }
+
+        let memory_len = u64::from_le_bytes(reader.array()?) as usize;
+        let memory = reader.take(memory_len)?.to_vec();
+        if reader.offset != data.len()
+        {
+            return Err("snapshot has trailing bytes".into());
+        }
+
+        Ok(MembraneSnapshot {
+            fingerprint,
+            memory,
+            globals,
+            state,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
+    {
+        let mut file = File::create(path)?;
+        file.write_all(self.serialize()?.as_slice())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error>
+    {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        MembraneSnapshot::deserialize(data.as_slice())
+    }
+}
+
+struct SnapshotReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error>
+    {
+        let bytes = self.offset.checked_add(len).and_then(|end| self.data.get(self.offset..end)).ok_or("snapshot is truncated")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<A>(&mut self) -> Result<A, Error>
+        where for<'b> &'b [u8]: TryInto<A>
+    {
+        let len = std::mem::size_of::<A>();
+        let bytes = self.take(len)?;
+        bytes.try_into().map_err(|_| "snapshot is truncated".into())
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::{WasmMembrane, MembraneState};
+    use crate::snapshot::{GlobalValue, MembraneSnapshot};
+
+    #[test]
+    pub fn test_serialize() -> Result<(), Error>
+    {
+        let snapshot = MembraneSnapshot {
+            fingerprint: [7; 32],
+            memory: vec![1, 2, 3, 0, 0, 9],
+            globals: vec![("counter".to_string(), GlobalValue::I32(-3)), ("ratio".to_string(), GlobalValue::F64(0.5f64.to_bits()))],
+            state: MembraneState::Running,
+        };
+        let restored = MembraneSnapshot::deserialize(snapshot.serialize()?.as_slice())?;
+        assert_eq!(snapshot, restored);
+
+        let mut truncated = snapshot.serialize()?;
+        truncated.pop();
+        assert!(MembraneSnapshot::deserialize(truncated.as_slice()).is_err());
+
+        // a memory length that would overflow the reader's offset
+        let mut oversized = snapshot.serialize()?;
+        let memory_len_at = oversized.len() - snapshot.memory.len() - 8;
+        oversized[memory_len_at..memory_len_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(MembraneSnapshot::deserialize(oversized.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_snapshot_restore() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        let buffer_id = membrane.write_string("kept by the snapshot")?;
+        let snapshot = MembraneSnapshot::deserialize(membrane.snapshot()?.serialize()?.as_slice())?;
+
+        membrane.dealloc_buffer(buffer_id)?;
+        membrane.restore(&snapshot)?;
+        assert_eq!(membrane.read_string(buffer_id)?, "kept by the snapshot");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fingerprint() -> Result<(), Error>
+    {
+        let data = std::fs::read("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        let module = WasmMembrane::compile(data.as_slice())?;
+        let first = WasmMembrane::new(module.clone())?;
+        let second = WasmMembrane::new(module)?;
+        assert_eq!(first.snapshot()?.fingerprint, second.snapshot()?.fingerprint);
+
+        let config = MembraneEngineConfig {
+            fuel: Option::Some(1_000_000),
+            ..Default::default()
+        };
+        let metered = WasmMembrane::new_with_config(WasmMembrane::compile_with_config(data.as_slice(), &config)?, &config)?;
+        assert!(metered.restore(&first.snapshot()?).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasmer::Module;
+
+use crate::engine::MembraneEngineConfig;
+use crate::error::Error;
+use crate::membrane::{MembraneState, WasmMembrane};
+
+/// how long the supervisor waits before recreating a failed membrane
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestartStrategy {
+    Immediate,
+    /// wait initial before the first restart in the window and multiply the wait by
+    /// factor for every further restart, never waiting longer than max
+    ExponentialBackoff {
+        initial: Duration,
+        max: Duration,
+        factor: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    pub strategy: RestartStrategy,
+    /// the supervisor gives up once this many restarts happened within restart_window
+    pub max_restarts: usize,
+    pub restart_window: Duration,
+    /// passed to WasmMembrane::init_with_config for every membrane the supervisor creates
+    pub init_config: Option<Vec<u8>>,
+    /// passed to WasmMembrane::new_with_config, should match the config the module was compiled with
+    pub engine_config: MembraneEngineConfig,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            strategy: RestartStrategy::ExponentialBackoff {
+                initial: Duration::from_millis(100),
+                max: Duration::from_secs(30),
+                factor: 2,
+            },
+            max_restarts: 5,
+            restart_window: Duration::from_secs(60),
+            init_config: Option::None,
+            engine_config: MembraneEngineConfig::default(),
+        }
+    }
+}
+
+/// what happened to the supervised membrane. generation counts the membranes the
+/// supervisor has created, starting at 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupervisorEvent {
+    Started { generation: u64 },
+    /// the membrane trapped, panicked, ran out of fuel or was otherwise poisoned
+    Failed { generation: u64, error: String },
+    Restarting { generation: u64, attempt: usize, delay: Duration },
+    /// creating or initializing the replacement failed, another attempt follows unless the limit is hit
+    RestartFailed { generation: u64, error: String },
+    /// max_restarts within restart_window was exceeded, calls fail until reset()
+    GaveUp { restarts: usize },
+}
+
+struct SupervisorState {
+    membrane: Option<Arc<WasmMembrane>>,
+    generation: u64,
+    /// when each restart within the window happened, oldest first
+    restarts: VecDeque<Instant>,
+    gave_up: bool,
+    /// a restart is sleeping or instantiating with the lock released
+    restarting: bool,
+}
+
+/// owns a module and keeps one initialized membrane of it alive. a membrane that fails
+/// fatally is replaced with a fresh one according to the configured RestartStrategy
+pub struct Supervisor {
+    module: Arc<Module>,
+    config: SupervisorConfig,
+    state: Mutex<SupervisorState>,
+    restarted: Condvar,
+    subscribers: RwLock<Vec<Sender<SupervisorEvent>>>,
+}
+
+impl Supervisor {
+    pub fn new(module: Arc<Module>, config: SupervisorConfig) -> Result<Arc<Self>, Error>
+    {
+        let supervisor = Arc::new(Supervisor {
+            module,
+            config,
+            state: Mutex::new(SupervisorState {
+                membrane: Option::None,
+                generation: 0,
+                restarts: VecDeque::new(),
+                gave_up: false,
+                restarting: false,
+            }),
+            restarted: Condvar::new(),
+            subscribers: RwLock::new(vec![]),
+        });
+
+        {
+            let mut state = supervisor.state.lock()?;
+            let membrane = supervisor.instantiate()?;
+            state.membrane = Option::Some(membrane);
+            state.generation = 1;
+        }
+        supervisor.emit(SupervisorEvent::Started { generation: 1 });
+
+        Ok(supervisor)
+    }
+
+    pub fn config(&self) -> &SupervisorConfig
+    {
+        &self.config
+    }
+
+    /// events are delivered to every receiver that is still alive
+    pub fn subscribe(&self) -> Result<Receiver<SupervisorEvent>, Error>
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.write()?.push(sender);
+        Ok(receiver)
+    }
+
+    pub fn generation(&self) -> Result<u64, Error>
+    {
+        Ok(self.state.lock()?.generation)
+    }
+
+    /// the current membrane, restarting it first if it failed since the last call
+    pub fn membrane(&self) -> Result<Arc<WasmMembrane>, Error>
+    {
+        let state = self.wait_for_restart()?;
+        if state.gave_up
+        {
+            return Err(format!("supervisor gave up after {} restarts within {:?}", self.config.max_restarts, self.config.restart_window).into());
+        }
+
+        if let Some(membrane) = &state.membrane
+        {
+            match membrane.state()
+            {
+                MembraneState::Poisoned | MembraneState::ShutDown => {}
+                _ => return Ok(membrane.clone())
+            }
+            self.emit(SupervisorEvent::Failed {
+                generation: state.generation,
+                error: format!("membrane is {:?}", membrane.state()),
+            });
+        }
+
+        self.restart(state)
+    }
+
+    /// run f against the current membrane. when f leaves the membrane poisoned it is
+    /// restarted right away so the next call finds a healthy one, f's error is still returned
+    pub fn call<F, T>(&self, f: F) -> Result<T, Error>
+        where F: FnOnce(&WasmMembrane) -> Result<T, Error>
+    {
+        let membrane = self.membrane()?;
+        let result = f(&membrane);
+        if let Err(error) = &result
+        {
+            if membrane.trapped()
+            {
+                let state = self.state.lock()?;
+                // another caller may have restarted it already or be restarting it
+                let current = state.membrane.as_ref().is_some_and(|current| Arc::ptr_eq(current, &membrane));
+                if current && !state.gave_up && !state.restarting
+                {
+                    self.emit(SupervisorEvent::Failed {
+                        generation: state.generation,
+                        error: error.error.clone(),
+                    });
+                    if let Err(restart_error) = self.restart(state)
+                    {
+                        println!("WasmMembrane: supervisor could not restart membrane: {}", restart_error.error);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// forget earlier restarts and start over with a fresh membrane, also after giving up
+    pub fn reset(&self) -> Result<Arc<WasmMembrane>, Error>
+    {
+        let mut state = self.wait_for_restart()?;
+        state.restarts.clear();
+        state.gave_up = false;
+        self.restart(state)
+    }
+
+    /// lock the state once no restart is in progress
+    fn wait_for_restart(&self) -> Result<MutexGuard<'_, SupervisorState>, Error>
+    {
+        let mut state = self.state.lock()?;
+        while state.restarting
+        {
+            state = self.restarted.wait(state)?;
+        }
+        Ok(state)
+    }
+
+    /// the lock is released while waiting out the delay and creating the replacement. other
+    /// callers wait for the replacement in wait_for_restart instead of getting the failed membrane
+    fn restart<'a>(&'a self, mut state: MutexGuard<'a, SupervisorState>) -> Result<Arc<WasmMembrane>, Error>
+    {
+        loop {
+            let now = Instant::now();
+            while state.restarts.front().is_some_and(|restart| now.duration_since(*restart) > self.config.restart_window)
+            {
+                state.restarts.pop_front();
+            }
+
+            if state.restarts.len() >= self.config.max_restarts
+            {
+                state.gave_up = true;
+                state.restarting = false;
+                self.restarted.notify_all();
+                self.emit(SupervisorEvent::GaveUp { restarts: state.restarts.len() });
+                return Err(format!("supervisor gave up after {} restarts within {:?}", state.restarts.len(), self.config.restart_window).into());
+            }
+
+            let attempt = state.restarts.len() + 1;
+            let delay = self.delay(attempt);
+            self.emit(SupervisorEvent::Restarting {
+                generation: state.generation,
+                attempt,
+                delay,
+            });
+            state.restarting = true;
+            drop(state);
+            thread::sleep(delay);
+            let result = self.instantiate();
+
+            state = self.state.lock()?;
+            state.restarts.push_back(Instant::now());
+            match result
+            {
+                Ok(membrane) => {
+                    state.membrane = Option::Some(membrane.clone());
+                    state.generation += 1;
+                    state.restarting = false;
+                    self.restarted.notify_all();
+                    self.emit(SupervisorEvent::Started { generation: state.generation });
+                    return Ok(membrane);
+                }
+                Err(error) => {
+                    self.emit(SupervisorEvent::RestartFailed {
+                        generation: state.generation,
+                        error: error.error,
+                    });
+                }
+            }
+        }
+    }
+
+    fn delay(&self, attempt: usize) -> Duration
+    {
+        match &self.config.strategy
+        {
+            RestartStrategy::Immediate => Duration::from_millis(0),
+            RestartStrategy::ExponentialBackoff { initial, max, factor } => {
+                let mut delay = *initial;
+                for _ in 1..attempt
+                {
+                    delay = delay.checked_mul(*factor).unwrap_or(*max);
+                    if delay >= *max
+                    {
+                        break;
+                    }
+                }
+                delay.min(*max)
+            }
+        }
+    }
+
+    fn instantiate(&self) -> Result<Arc<WasmMembrane>, Error>
+    {
+        let membrane = WasmMembrane::new_with_config(self.module.clone(), &self.config.engine_config)?;
+        membrane.init_with_config(self.config.init_config.as_deref())?;
+        Ok(membrane)
+    }
+
+    fn emit(&self, event: SupervisorEvent)
+    {
+        if let Ok(mut subscribers) = self.subscribers.write()
+        {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::fs::File;
+    use std::io::Read;
+    use std::time::Duration;
+
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::supervisor::{RestartStrategy, Supervisor, SupervisorConfig, SupervisorEvent};
+
+    #[test]
+    pub fn test_supervisor() -> Result<(), Error>
+    {
+        let path = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let supervisor = Supervisor::new(WasmMembrane::compile(data.as_slice())?, SupervisorConfig {
+            strategy: RestartStrategy::Immediate,
+            max_restarts: 1,
+            restart_window: Duration::from_secs(60),
+            init_config: Option::None,
+            ..Default::default()
+        })?;
+        let events = supervisor.subscribe()?;
+
+        assert!(supervisor.call(|membrane| membrane.test_panic()).is_err());
+        assert_eq!(supervisor.generation()?, 2);
+        supervisor.call(|membrane| membrane.test_log())?;
+
+        assert!(supervisor.call(|membrane| membrane.test_panic()).is_err());
+        assert!(supervisor.membrane().is_err());
+
+        let events: Vec<SupervisorEvent> = events.try_iter().collect();
+        assert!(matches!(events[0], SupervisorEvent::Failed { generation: 1, .. }));
+        assert_eq!(events[1], SupervisorEvent::Restarting { generation: 1, attempt: 1, delay: Duration::from_millis(0) });
+        assert_eq!(events[2], SupervisorEvent::Started { generation: 2 });
+        assert_eq!(events.last(), Some(&SupervisorEvent::GaveUp { restarts: 1 }));
+
+        supervisor.reset()?;
+        supervisor.call(|membrane| membrane.test_log())?;
+        // membranes are created with the configured engine settings
+        assert_eq!(supervisor.membrane()?.metadata().engine_settings, Option::Some(MembraneEngineConfig::default().cache_key()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::determinism::VirtualClock;
+use crate::error::Error;
+use crate::membrane::{Env, MAX_HOST_ALLOCATION};
+
+/// where a membrane's guest gets its time. swap it with WasmMembrane::set_clock, for instance
+/// for a ManualClock in tests
+pub trait Clock: Send + Sync {
+    /// time since a fixed point in the past, never going backwards
+    fn monotonic(&self) -> Duration;
+
+    /// time since the unix epoch
+    fn wall(&self) -> Duration;
+
+    /// block the calling guest for duration
+    fn sleep(&self, duration: Duration);
+}
+
+/// the clocks of the host. sleeps block the calling thread, for at most max_sleep
+pub struct SystemClock {
+    start: Instant,
+    max_sleep: Duration,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+            max_sleep: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> Self
+    {
+        SystemClock::default()
+    }
+
+    /// longer sleeps are cut short to max_sleep so that a guest cannot hold its thread
+    /// for as long as it likes. defaults to one second
+    pub fn with_max_sleep(mut self, max_sleep: Duration) -> Self
+    {
+        self.max_sleep = max_sleep;
+        self
+    }
+}
+
+impl Clock for SystemClock {
+    fn monotonic(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn wall(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration.min(self.max_sleep));
+    }
+}
+
+/// a clock that only moves when told to. sleeping advances it instead of blocking
+#[derive(Default)]
+pub struct ManualClock {
+    // (monotonic, wall)
+    times: Mutex<(Duration, Duration)>,
+}
+
+impl ManualClock {
+    pub fn new(wall: Duration) -> Self
+    {
+        ManualClock {
+            times: Mutex::new((Duration::default(), wall)),
+        }
+    }
+
+    /// move both clocks forward
+    pub fn advance(&self, duration: Duration)
+    {
+        if let Ok(mut times) = self.times.lock()
+        {
+            times.0 += duration;
+            times.1 += duration;
+        }
+    }
+
+    /// jump the wall clock, which unlike the monotonic clock may go backwards
+    pub fn set_wall(&self, wall: Duration)
+    {
+        if let Ok(mut times) = self.times.lock()
+        {
+            times.1 = wall;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn monotonic(&self) -> Duration {
+        self.times.lock().map(|times| times.0).unwrap_or_default()
+    }
+
+    fn wall(&self) -> Duration {
+        self.times.lock().map(|times| times.1).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// the clock of a deterministic membrane, both clocks read the same virtual time
+impl Clock for VirtualClock {
+    fn monotonic(&self) -> Duration {
+        Duration::from_nanos(self.now())
+    }
+
+    fn wall(&self) -> Duration {
+        Duration::from_nanos(self.now())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration.as_nanos() as u64);
+    }
+}
+
+/// a timer the guest started with membrane_host_timer_start
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Timer {
+    /// monotonic time it fires at
+    pub deadline: Duration,
+    /// None for a one-shot timer
+    pub interval: Option<Duration>,
+}
+
+fn nanos(duration: i64) -> Duration
+{
+    Duration::from_nanos(duration.max(0) as u64)
+}
+
+// host imports, see wasm_membrane_guest::time. times are in nanoseconds
+
+pub(crate) fn host_time_monotonic(env: &Env) -> i64
+{
+    match env.unwrap()
+    {
+        Ok(membrane) => membrane.clock().map(|clock| clock.monotonic().as_nanos() as i64).unwrap_or(-1),
+        Err(_) => -1
+    }
+}
+
+pub(crate) fn host_time_wall(env: &Env) -> i64
+{
+    match env.unwrap()
+    {
+        Ok(membrane) => membrane.clock().map(|clock| clock.wall().as_nanos() as i64).unwrap_or(-1),
+        Err(_) => -1
+    }
+}
+
+pub(crate) fn host_sleep(env: &Env, duration: i64)
+{
+    if let Ok(clock) = env.unwrap().and_then(|membrane| membrane.clock())
+    {
+        clock.sleep(nanos(duration));
+    }
+}
+
+/// responds with len random bytes, or an error when len is over MAX_HOST_ALLOCATION
+pub(crate) fn host_random(env: &Env, len: i32) -> i32
+{
+    env.respond(|membrane| {
+        let len = len.max(0) as usize;
+        if len > MAX_HOST_ALLOCATION
+        {
+            return Err(format!("cannot produce {} random bytes in one call, the limit is {}", len, MAX_HOST_ALLOCATION).into());
+        }
+        membrane.random_bytes(len)
+    })
+}
+
+/// start a timer firing after delay and then every interval, or once when interval is 0.
+/// returns the timer id or -1
+pub(crate) fn host_timer_start(env: &Env, delay: i64, interval: i64) -> i32
+{
+    let result = (|| -> Result<i32, Error> {
+        let membrane = env.unwrap()?;
+        let deadline = membrane.clock()?.monotonic() + nanos(delay);
+        let interval = if interval > 0 { Option::Some(nanos(interval)) } else { Option::None };
+        env.start_timer(Timer { deadline, interval })
+    })();
+    result.unwrap_or(-1)
+}
+
+/// returns 1 when the timer was running, 0 otherwise
+pub(crate) fn host_timer_cancel(env: &Env, id: i32) -> i32
+{
+    env.cancel_timer(id).unwrap_or(false) as i32
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::time::{Clock, ManualClock, SystemClock};
+
+    #[test]
+    pub fn test_manual_clock()
+    {
+        let clock = ManualClock::new(Duration::from_secs(1_000));
+        clock.sleep(Duration::from_millis(5));
+        assert_eq!(clock.monotonic(), Duration::from_millis(5));
+        clock.set_wall(Duration::from_secs(10));
+        assert_eq!(clock.wall(), Duration::from_secs(10));
+        assert_eq!(clock.monotonic(), Duration::from_millis(5));
+    }
+
+    #[test]
+    pub fn test_system_clock_max_sleep()
+    {
+        let clock = SystemClock::new().with_max_sleep(Duration::from_millis(10));
+        clock.sleep(Duration::from_secs(60));
+        assert!(clock.monotonic() < Duration::from_secs(5));
+    }
+
+    #[test]
+    pub fn test_guest_timers() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        let clock = Arc::new(ManualClock::new(Duration::from_secs(1_000)));
+        membrane.set_clock(clock.clone())?;
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink_lines = lines.clone();
+        membrane.set_log_sink(Option::Some(Arc::new(move |_: &WasmMembrane, _: &str, message: &str| {
+            sink_lines.lock().unwrap().push(message.to_string());
+        })))?;
+
+        // the guest starts a 10ms timeout and a 4ms interval that stops after three ticks
+        membrane.emit("timers", b"")?;
+        assert_eq!(membrane.next_timer(), Option::Some(Duration::from_millis(4)));
+        assert_eq!(membrane.run_timers()?, 0);
+
+        for _ in 0..5
+        {
+            clock.advance(Duration::from_millis(4));
+            membrane.run_timers()?;
+        }
+        assert_eq!(membrane.next_timer(), Option::None);
+        assert_eq!(lines.lock()?.iter().filter(|line| line.starts_with("tick")).count(), 3);
+        assert!(lines.lock()?.contains(&"timeout after 12ms".to_string()));
+        Ok(())
+    }
+}
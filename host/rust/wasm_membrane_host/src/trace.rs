@@ -0,0 +1,637 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use wasmer::Val;
+
+use crate::error::Error;
+use crate::membrane::{Env, MembraneMetadata, WasmMembrane};
+
+static TRACE_MAGIC: &[u8] = b"WMTRACE\0";
+static TRACE_FORMAT_VERSION: u32 = 1;
+
+/// an argument or result of a guest export
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceValue {
+    I32(i32),
+    I64(i64),
+    /// the bits of the float, so that NaNs compare equal
+    F32(u32),
+    F64(u64),
+}
+
+impl TraceValue {
+    pub fn from_val(val: &Val) -> Result<Self, Error>
+    {
+        match val
+        {
+            Val::I32(value) => Ok(TraceValue::I32(*value)),
+            Val::I64(value) => Ok(TraceValue::I64(*value)),
+            Val::F32(value) => Ok(TraceValue::F32(value.to_bits())),
+            Val::F64(value) => Ok(TraceValue::F64(value.to_bits())),
+            val => Err(format!("{:?} can not be traced", val).into())
+        }
+    }
+
+    pub fn to_val(&self) -> Val
+    {
+        match self
+        {
+            TraceValue::I32(value) => Val::I32(*value),
+            TraceValue::I64(value) => Val::I64(*value),
+            TraceValue::F32(bits) => Val::F32(f32::from_bits(*bits)),
+            TraceValue::F64(bits) => Val::F64(f64::from_bits(*bits)),
+        }
+    }
+}
+
+pub(crate) fn trace_values(values: &[Val]) -> Result<Vec<TraceValue>, Error>
+{
+    values.iter().map(TraceValue::from_val).collect()
+}
+
+/// one thing that crossed the boundary between host and guest. calls are bracketed by their
+/// return, with everything that happened during the call in between
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// the host called a guest export
+    ExportCall { name: String, args: Vec<TraceValue> },
+    /// the export returned results, or failed with a message
+    ExportReturn { result: Result<Vec<TraceValue>, String> },
+    /// the guest called a host import
+    ImportCall { name: String, args: Vec<i64> },
+    /// the import returned, None for imports without a result
+    ImportReturn { result: Option<i64> },
+    /// the host wrote bytes into a new guest buffer
+    BufferWrite { id: i32, bytes: Vec<u8> },
+    /// the host read a guest buffer
+    BufferRead { id: i32, bytes: Vec<u8> },
+    /// the host released a guest buffer
+    BufferFree { id: i32 },
+}
+
+/// everything a membrane did while it was recording, see WasmMembrane::start_recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub metadata: MembraneMetadata,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error>
+    {
+        fs::write(path, self.encode()?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error>
+    {
+        Trace::decode(fs::read(path)?.as_slice())
+    }
+
+    /// magic, format version, metadata as json, then the events. integers are varints so
+    /// that the many small buffer ids and lengths take a byte or two
+    pub fn encode(&self) -> Result<Vec<u8>, Error>
+    {
+        let mut data = vec![];
+        data.extend_from_slice(TRACE_MAGIC);
+        data.extend_from_slice(&TRACE_FORMAT_VERSION.to_le_bytes());
+        write_bytes(&mut data, serde_json::to_vec(&self.metadata).map_err(|e| format!("could not encode trace metadata: {}", e))?.as_slice());
+        for event in &self.events
+        {
+            encode_event(&mut data, event);
+        }
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Error>
+    {
+        if !data.starts_with(TRACE_MAGIC)
+        {
+            return Err("not a membrane trace".into());
+        }
+        let mut reader = Reader { data, offset: TRACE_MAGIC.len() };
+        let mut version = [0u8; 4];
+        version.copy_from_slice(reader.take(4)?);
+        if u32::from_le_bytes(version) != TRACE_FORMAT_VERSION
+        {
+            return Err(format!("unsupported trace format version {}", u32::from_le_bytes(version)).into());
+        }
+        let metadata = serde_json::from_slice(reader.bytes()?).map_err(|e| format!("could not decode trace metadata: {}", e))?;
+        let mut events = vec![];
+        while reader.offset < data.len()
+        {
+            events.push(decode_event(&mut reader)?);
+        }
+        Ok(Trace { metadata, events })
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64)
+{
+    while value >= 0x80
+    {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn write_signed(data: &mut Vec<u8>, value: i64)
+{
+    // zigzag so that -1, the most common id, stays one byte
+    write_varint(data, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+fn write_bytes(data: &mut Vec<u8>, bytes: &[u8])
+{
+    write_varint(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+fn write_values(data: &mut Vec<u8>, values: &[TraceValue])
+{
+    write_varint(data, values.len() as u64);
+    for value in values
+    {
+        match value
+        {
+            TraceValue::I32(value) => { data.push(0); write_signed(data, *value as i64); }
+            TraceValue::I64(value) => { data.push(1); write_signed(data, *value); }
+            TraceValue::F32(bits) => { data.push(2); write_varint(data, *bits as u64); }
+            TraceValue::F64(bits) => { data.push(3); write_varint(data, *bits); }
+        }
+    }
+}
+
+fn encode_event(data: &mut Vec<u8>, event: &TraceEvent)
+{
+    match event
+    {
+        TraceEvent::ExportCall { name, args } => {
+            data.push(1);
+            write_bytes(data, name.as_bytes());
+            write_values(data, args.as_slice());
+        }
+        TraceEvent::ExportReturn { result: Ok(results) } => {
+            data.push(2);
+            write_values(data, results.as_slice());
+        }
+        TraceEvent::ExportReturn { result: Err(message) } => {
+            data.push(3);
+            write_bytes(data, message.as_bytes());
+        }
+        TraceEvent::ImportCall { name, args } => {
+            data.push(4);
+            write_bytes(data, name.as_bytes());
+            write_varint(data, args.len() as u64);
+            for arg in args
+            {
+                write_signed(data, *arg);
+            }
+        }
+        TraceEvent::ImportReturn { result: Some(result) } => {
+            data.push(5);
+            write_signed(data, *result);
+        }
+        TraceEvent::ImportReturn { result: None } => {
+            data.push(6);
+        }
+        TraceEvent::BufferWrite { id, bytes } => {
+            data.push(7);
+            write_signed(data, *id as i64);
+            write_bytes(data, bytes.as_slice());
+        }
+        TraceEvent::BufferRead { id, bytes } => {
+            data.push(8);
+            write_signed(data, *id as i64);
+            write_bytes(data, bytes.as_slice());
+        }
+        TraceEvent::BufferFree { id } => {
+            data.push(9);
+            write_signed(data, *id as i64);
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error>
+    {
+        let bytes = self.offset.checked_add(len).and_then(|end| self.data.get(self.offset..end)).ok_or("trace is truncated")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error>
+    {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, Error>
+    {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift > 63
+            {
+                return Err("trace holds a malformed varint".into());
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0
+            {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn signed(&mut self) -> Result<i64, Error>
+    {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error>
+    {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Error>
+    {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    fn values(&mut self) -> Result<Vec<TraceValue>, Error>
+    {
+        let len = self.varint()?;
+        let mut values = vec![];
+        for _ in 0..len
+        {
+            values.push(match self.byte()?
+            {
+                0 => TraceValue::I32(self.signed()? as i32),
+                1 => TraceValue::I64(self.signed()?),
+                2 => TraceValue::F32(self.varint()? as u32),
+                3 => TraceValue::F64(self.varint()?),
+                tag => return Err(format!("unknown trace value type {}", tag).into())
+            });
+        }
+        Ok(values)
+    }
+}
+
+fn decode_event(reader: &mut Reader) -> Result<TraceEvent, Error>
+{
+    Ok(match reader.byte()?
+    {
+        1 => TraceEvent::ExportCall { name: reader.string()?, args: reader.values()? },
+        2 => TraceEvent::ExportReturn { result: Ok(reader.values()?) },
+        3 => TraceEvent::ExportReturn { result: Err(reader.string()?) },
+        4 => {
+            let name = reader.string()?;
+            let len = reader.varint()?;
+            let mut args = vec![];
+            for _ in 0..len
+            {
+                args.push(reader.signed()?);
+            }
+            TraceEvent::ImportCall { name, args }
+        }
+        5 => TraceEvent::ImportReturn { result: Some(reader.signed()?) },
+        6 => TraceEvent::ImportReturn { result: None },
+        7 => TraceEvent::BufferWrite { id: reader.signed()? as i32, bytes: reader.bytes()?.to_vec() },
+        8 => TraceEvent::BufferRead { id: reader.signed()? as i32, bytes: reader.bytes()?.to_vec() },
+        9 => TraceEvent::BufferFree { id: reader.signed()? as i32 },
+        tag => return Err(format!("unknown trace event {}", tag).into())
+    })
+}
+
+/// what a membrane does with the traffic crossing its boundary
+#[derive(Clone)]
+pub(crate) enum TraceMode {
+    Record(Arc<Recorder>),
+    Replay(Arc<Replayer>),
+}
+
+#[derive(Default)]
+pub(crate) struct Recorder {
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+impl Recorder {
+    pub fn record(&self, event: TraceEvent)
+    {
+        if let Ok(mut events) = self.events.lock()
+        {
+            events.push(event);
+        }
+    }
+
+    pub fn take(&self) -> Result<Vec<TraceEvent>, Error>
+    {
+        Ok(std::mem::take(&mut *self.events.lock()?))
+    }
+}
+
+/// feeds a trace back into a membrane. the events the host caused are performed again,
+/// the calls the guest makes are checked against the trace and answered from it
+pub(crate) struct Replayer {
+    events: Vec<TraceEvent>,
+    position: Mutex<usize>,
+    // an import stub can not fail the guest, it leaves the divergence here for run to report
+    divergence: Mutex<Option<Error>>,
+}
+
+impl Replayer {
+    pub fn new(trace: &Trace) -> Self
+    {
+        Replayer {
+            events: trace.events.clone(),
+            position: Mutex::new(0),
+            divergence: Mutex::new(Option::None),
+        }
+    }
+
+    /// replay every event, returns how many there were
+    pub fn run(&self, membrane: &WasmMembrane) -> Result<usize, Error>
+    {
+        while let Some((index, event)) = self.next()?
+        {
+            self.step(membrane, index, event)?;
+        }
+        Ok(self.events.len())
+    }
+
+    fn next(&self) -> Result<Option<(usize, TraceEvent)>, Error>
+    {
+        let mut position = self.position.lock()?;
+        let index = *position;
+        match self.events.get(index)
+        {
+            Some(event) => {
+                *position += 1;
+                Ok(Option::Some((index, event.clone())))
+            }
+            None => Ok(Option::None)
+        }
+    }
+
+    fn expect_next(&self, expected: &str) -> Result<(usize, TraceEvent), Error>
+    {
+        self.next()?.ok_or_else(|| format!("replay diverged: expected {} but the trace ended", expected).into())
+    }
+
+    fn check_divergence(&self) -> Result<(), Error>
+    {
+        match self.divergence.lock()?.take()
+        {
+            Some(error) => Err(error),
+            None => Ok(())
+        }
+    }
+
+    /// perform an event the host caused
+    fn step(&self, membrane: &WasmMembrane, index: usize, event: TraceEvent) -> Result<(), Error>
+    {
+        match event
+        {
+            TraceEvent::ExportCall { name, args } => self.export(membrane, index, name.as_str(), args.as_slice()),
+            TraceEvent::BufferWrite { id, bytes } => {
+                let written = membrane.write_buffer(&bytes)?;
+                if written != id
+                {
+                    return Err(diverged(index, format!("buffer written as {}", id), format!("buffer {}", written)));
+                }
+                Ok(())
+            }
+            TraceEvent::BufferRead { id, bytes } => {
+                let read = membrane.read_buffer(id)?;
+                if read != bytes
+                {
+                    return Err(diverged(index, format!("buffer {} holding {:?}", id, bytes), format!("{:?}", read)));
+                }
+                Ok(())
+            }
+            TraceEvent::BufferFree { id } => membrane.dealloc_buffer(id),
+            event => Err(diverged(index, "a host action".to_string(), format!("{:?}", event)))
+        }
+    }
+
+    fn export(&self, membrane: &WasmMembrane, index: usize, name: &str, args: &[TraceValue]) -> Result<(), Error>
+    {
+        let args: Vec<Val> = args.iter().map(|arg| arg.to_val()).collect();
+        let result = membrane.call_guest(name, args.as_slice());
+        self.check_divergence()?;
+        let result = match result
+        {
+            Ok(results) => Ok(trace_values(&results)?),
+            Err(error) => Err(error.error)
+        };
+
+        let (return_index, event) = self.expect_next(format!("the return of {}", name).as_str())?;
+        match event
+        {
+            TraceEvent::ExportReturn { result: expected } => {
+                // traps only have to agree on failing, their messages hold addresses
+                if expected.is_ok() != result.is_ok() || (expected.is_ok() && expected != result)
+                {
+                    return Err(diverged(return_index, format!("{} to return {:?}", name, expected), format!("{:?}", result)));
+                }
+                Ok(())
+            }
+            event => Err(diverged(index, format!("{} to return", name), format!("{:?}", event)))
+        }
+    }
+
+    /// answer a call the guest made to an import from the trace
+    pub fn import(&self, membrane: &WasmMembrane, name: &str, args: &[i64]) -> Result<Option<i64>, Error>
+    {
+        let (index, event) = self.expect_next(format!("a call to {}", name).as_str())?;
+        match event
+        {
+            TraceEvent::ImportCall { name: expected_name, args: expected_args } if expected_name == name && expected_args.as_slice() == args => {}
+            event => return Err(diverged(index, format!("{:?}", event), format!("a call to {} with {:?}", name, args)))
+        }
+
+        loop {
+            let (index, event) = self.expect_next(format!("the return of {}", name).as_str())?;
+            match event
+            {
+                TraceEvent::ImportReturn { result } => return Ok(result),
+                event => self.step(membrane, index, event)?
+            }
+        }
+    }
+
+    pub fn diverge(&self, error: Error)
+    {
+        if let Ok(mut divergence) = self.divergence.lock()
+        {
+            if divergence.is_none()
+            {
+                *divergence = Option::Some(error);
+            }
+        }
+    }
+}
+
+fn diverged(index: usize, expected: String, actual: String) -> Error
+{
+    format!("replay diverged at event {}: expected {} but got {}", index, expected, actual).into()
+}
+
+/// what a host import returns, as it is kept in a trace
+pub(crate) trait TraceResult {
+    fn to_trace(&self) -> Option<i64>;
+
+    /// the recorded result, or the import's failure value when there is none
+    fn from_trace(value: Option<i64>) -> Self;
+}
+
+impl TraceResult for () {
+    fn to_trace(&self) -> Option<i64> {
+        Option::None
+    }
+
+    fn from_trace(_value: Option<i64>) -> Self {}
+}
+
+impl TraceResult for i32 {
+    fn to_trace(&self) -> Option<i64> {
+        Option::Some(*self as i64)
+    }
+
+    fn from_trace(value: Option<i64>) -> Self {
+        value.map(|value| value as i32).unwrap_or(-1)
+    }
+}
+
+impl TraceResult for i64 {
+    fn to_trace(&self) -> Option<i64> {
+        Option::Some(*self)
+    }
+
+    fn from_trace(value: Option<i64>) -> Self {
+        value.unwrap_or(-1)
+    }
+}
+
+/// run a host import, recording it or answering it from the trace when the membrane is
+/// recording or replaying
+pub(crate) fn traced<R: TraceResult, F: FnOnce() -> R>(env: &Env, name: &str, args: &[i64], import: F) -> R
+{
+    let membrane = match env.unwrap()
+    {
+        Ok(membrane) => membrane,
+        Err(_) => return import()
+    };
+    match membrane.trace_mode()
+    {
+        Some(TraceMode::Record(recorder)) => {
+            recorder.record(TraceEvent::ImportCall { name: name.to_string(), args: args.to_vec() });
+            let result = import();
+            recorder.record(TraceEvent::ImportReturn { result: result.to_trace() });
+            result
+        }
+        Some(TraceMode::Replay(replayer)) => match replayer.import(&membrane, name, args)
+        {
+            Ok(result) => R::from_trace(result),
+            Err(error) => {
+                replayer.diverge(error);
+                R::from_trace(Option::None)
+            }
+        },
+        None => import()
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::Arc;
+
+    use crate::error::Error;
+    use crate::kv::{KvStore, MemoryKvBackend};
+    use crate::membrane::WasmMembrane;
+    use crate::trace::{write_varint, Trace, TraceEvent, TraceValue, TRACE_FORMAT_VERSION};
+
+    static WASM_PATH: &str = "../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm";
+
+    #[test]
+    pub fn test_trace_encoding() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file(WASM_PATH)?;
+        let trace = Trace {
+            metadata: membrane.metadata().clone(),
+            events: vec![
+                TraceEvent::BufferWrite { id: 3, bytes: b"topic".to_vec() },
+                TraceEvent::ExportCall { name: "membrane_guest_on_event".to_string(), args: vec![TraceValue::I32(3), TraceValue::F64(f64::NAN.to_bits())] },
+                TraceEvent::ImportCall { name: "membrane_host_kv_get".to_string(), args: vec![-1, i64::MAX] },
+                TraceEvent::ImportReturn { result: Option::None },
+                TraceEvent::BufferRead { id: 4, bytes: vec![] },
+                TraceEvent::BufferFree { id: 4 },
+                TraceEvent::ExportReturn { result: Err("trap".to_string()) },
+            ],
+        };
+        assert_eq!(Trace::decode(trace.encode()?.as_slice())?, trace);
+        assert!(Trace::decode(b"WMTRACE\0").is_err());
+
+        // a metadata length that would overflow the reader's offset
+        let mut oversized = b"WMTRACE\0".to_vec();
+        oversized.extend_from_slice(&TRACE_FORMAT_VERSION.to_le_bytes());
+        write_varint(&mut oversized, u64::MAX);
+        assert!(Trace::decode(oversized.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_record_replay() -> Result<(), Error>
+    {
+        let store = Arc::new(KvStore::new(Arc::new(MemoryKvBackend::new())));
+        let membrane = WasmMembrane::new(WasmMembrane::compile(std::fs::read(WASM_PATH)?.as_slice())?)?;
+        membrane.grant_kv(Option::Some(store.namespace("recorded")))?;
+        membrane.start_recording()?;
+        membrane.init()?;
+        membrane.emit("greeting", b"hello")?;
+        membrane.emit("count", b"")?;
+        membrane.emit("count", b"")?;
+        let trace = membrane.stop_recording()?;
+        assert!(trace.events.iter().any(|event| match event
+        {
+            TraceEvent::ImportCall { name, .. } => name == "membrane_host_kv_cas",
+            _ => false
+        }));
+
+        // the replay never touches a kv store, the guest sees the recorded answers
+        let path = std::env::temp_dir().join(format!("wasm_membrane_trace_test_{}", std::process::id()));
+        trace.save(&path)?;
+        let replayed = WasmMembrane::new(WasmMembrane::compile(std::fs::read(WASM_PATH)?.as_slice())?)?;
+        assert_eq!(replayed.replay(&Trace::load(&path)?)?, trace.events.len());
+        std::fs::remove_file(&path)?;
+        // a membrane that already ran cannot be replayed onto
+        assert!(replayed.replay(&trace).is_err());
+
+        // a guest that asks for something else than it did in the trace diverges
+        let mut tampered = trace.clone();
+        for event in tampered.events.iter_mut()
+        {
+            if let TraceEvent::BufferWrite { bytes, .. } = event
+            {
+                if bytes.as_slice() == b"count"
+                {
+                    *bytes = b"greeting".to_vec();
+                }
+            }
+        }
+        let diverging = WasmMembrane::new(WasmMembrane::compile(std::fs::read(WASM_PATH)?.as_slice())?)?;
+        assert!(diverging.replay(&tampered).is_err());
+        Ok(())
+    }
+}
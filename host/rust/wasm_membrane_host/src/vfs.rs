@@ -0,0 +1,475 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::membrane::{Env, MAX_HOST_ALLOCATION};
+
+/// flags of membrane_host_fs_open, or'ed together
+pub static OPEN_READ: i32 = 1;
+pub static OPEN_WRITE: i32 = 2;
+pub static OPEN_CREATE: i32 = 4;
+pub static OPEN_TRUNCATE: i32 = 8;
+pub static OPEN_APPEND: i32 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+impl OpenOptions {
+    pub fn from_flags(flags: i32) -> Self
+    {
+        OpenOptions {
+            read: flags & OPEN_READ != 0,
+            write: flags & OPEN_WRITE != 0,
+            create: flags & OPEN_CREATE != 0,
+            truncate: flags & OPEN_TRUNCATE != 0,
+            append: flags & OPEN_APPEND != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// an open file of a Vfs
+pub trait VfsFile: Read + Write + Send {}
+
+impl<T: Read + Write + Send> VfsFile for T {}
+
+/// the filesystem a membrane sees when it is granted the filesystem capability.
+/// paths are relative to the root of the Vfs, use '/' as separator and may not leave the root
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &str, options: OpenOptions) -> Result<Box<dyn VfsFile>, Error>;
+    fn list(&self, path: &str) -> Result<Vec<DirEntry>, Error>;
+    fn stat(&self, path: &str) -> Result<Metadata, Error>;
+    fn remove(&self, path: &str) -> Result<(), Error>;
+}
+
+/// resolve '.' and '..' without touching the real filesystem. fails when the path would leave
+/// the root. the result has no leading or trailing '/' and is empty for the root itself
+pub fn normalize(path: &str) -> Result<String, Error>
+{
+    let mut components: Vec<&str> = vec![];
+    for component in path.split(['/', '\\'])
+    {
+        match component
+        {
+            "" | "." => {}
+            ".." => {
+                if components.pop().is_none()
+                {
+                    return Err(format!("path {} leaves the filesystem root", path).into());
+                }
+            }
+            component => components.push(component)
+        }
+    }
+    Ok(components.join("/"))
+}
+
+/// a filesystem that only lives in memory, handy for tests and for plugins that produce
+/// output the host collects afterwards. directories exist implicitly while they hold files
+#[derive(Default)]
+pub struct MemoryVfs {
+    files: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self
+    {
+        MemoryVfs::default()
+    }
+
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<(), Error>
+    {
+        self.files.lock()?.insert(normalize(path)?, data.to_vec());
+        Ok(())
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, Error>
+    {
+        let path = normalize(path)?;
+        self.files.lock()?.get(&path).cloned().ok_or_else(|| format!("no such file: {}", path).into())
+    }
+
+    fn is_dir(files: &BTreeMap<String, Vec<u8>>, path: &str) -> bool
+    {
+        path.is_empty() || files.keys().any(|file| file.starts_with(path) && file[path.len()..].starts_with('/'))
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str, options: OpenOptions) -> Result<Box<dyn VfsFile>, Error>
+    {
+        let path = normalize(path)?;
+        let mut files = self.files.lock()?;
+        if MemoryVfs::is_dir(&files, path.as_str())
+        {
+            return Err(format!("{} is a directory", path).into());
+        }
+
+        let data = match files.get(&path)
+        {
+            Some(_) if options.truncate => vec![],
+            Some(data) => data.clone(),
+            None if options.create => vec![],
+            None => return Err(format!("no such file: {}", path).into())
+        };
+        if options.write || options.append
+        {
+            files.insert(path.clone(), data.clone());
+        }
+
+        let position = if options.append { data.len() as u64 } else { 0 };
+        let mut cursor = io::Cursor::new(data);
+        cursor.seek(SeekFrom::Start(position))?;
+        Ok(Box::new(MemoryFile {
+            files: self.files.clone(),
+            path,
+            writable: options.write || options.append,
+            cursor,
+        }))
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<DirEntry>, Error>
+    {
+        let path = normalize(path)?;
+        let files = self.files.lock()?;
+        if !MemoryVfs::is_dir(&files, path.as_str())
+        {
+            return Err(format!("no such directory: {}", path).into());
+        }
+
+        let prefix = if path.is_empty() { String::new() } else { format!("{}/", path) };
+        let mut entries: BTreeMap<String, bool> = BTreeMap::new();
+        for file in files.keys().filter(|file| file.starts_with(prefix.as_str()))
+        {
+            let rest = &file[prefix.len()..];
+            match rest.find('/')
+            {
+                Some(end) => entries.insert(rest[..end].to_string(), true),
+                None => entries.insert(rest.to_string(), false)
+            };
+        }
+        Ok(entries.into_iter().map(|(name, is_dir)| DirEntry { name, is_dir }).collect())
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error>
+    {
+        let path = normalize(path)?;
+        let files = self.files.lock()?;
+        match files.get(&path)
+        {
+            Some(data) => Ok(Metadata { is_dir: false, len: data.len() as u64 }),
+            None if MemoryVfs::is_dir(&files, path.as_str()) => Ok(Metadata { is_dir: true, len: 0 }),
+            None => Err(format!("no such file or directory: {}", path).into())
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error>
+    {
+        let path = normalize(path)?;
+        match self.files.lock()?.remove(&path)
+        {
+            Some(_) => Ok(()),
+            None => Err(format!("no such file: {}", path).into())
+        }
+    }
+}
+
+/// writes go through to the MemoryVfs right away so other handles see them
+struct MemoryFile {
+    files: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    path: String,
+    writable: bool,
+    cursor: io::Cursor<Vec<u8>>,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cursor.read(buf)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable
+        {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file was not opened for writing"));
+        }
+        let written = self.cursor.write(buf)?;
+        self.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.writable
+        {
+            return Ok(());
+        }
+        let mut files = self.files.lock().map_err(|_| io::Error::other("memory filesystem lock is poisoned"))?;
+        files.insert(self.path.clone(), self.cursor.get_ref().clone());
+        Ok(())
+    }
+}
+
+/// a directory of the real filesystem. guests cannot reach anything outside of it, neither
+/// through '..' nor through symlinks pointing elsewhere
+pub struct DirectoryVfs {
+    root: PathBuf,
+}
+
+impl DirectoryVfs {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, Error>
+    {
+        Ok(DirectoryVfs {
+            root: root.as_ref().canonicalize()?,
+        })
+    }
+
+    /// walk the path a component at a time without following symlinks, replacing each symlink
+    /// by its target. a symlink pointing outside the root is refused, and so is a dangling one
+    /// since opening it with create would make a file wherever it points
+    fn resolve(&self, path: &str) -> Result<PathBuf, Error>
+    {
+        let mut resolved = self.root.clone();
+        for component in Path::new(normalize(path)?.as_str()).components()
+        {
+            resolved.push(component);
+            // a component that does not exist yet cannot be a symlink, nor can anything below it
+            let is_symlink = fs::symlink_metadata(&resolved).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false);
+            if is_symlink
+            {
+                resolved = resolved.canonicalize().map_err(|_| format!("path {} goes through a dangling symlink", path))?;
+                if !resolved.starts_with(&self.root)
+                {
+                    return Err(format!("path {} leaves the filesystem root", path).into());
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+impl Vfs for DirectoryVfs {
+    fn open(&self, path: &str, options: OpenOptions) -> Result<Box<dyn VfsFile>, Error>
+    {
+        let file = fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .create(options.create)
+            .truncate(options.truncate)
+            .append(options.append)
+            .open(self.resolve(path)?)?;
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<DirEntry>, Error>
+    {
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.resolve(path)?)?
+        {
+            let entry = entry?;
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                is_dir: entry.file_type()?.is_dir(),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &str) -> Result<Metadata, Error>
+    {
+        let metadata = fs::metadata(self.resolve(path)?)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+        })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), Error>
+    {
+        let path = self.resolve(path)?;
+        if path == self.root
+        {
+            return Err("cannot remove the filesystem root".into());
+        }
+        match fs::metadata(&path)?.is_dir()
+        {
+            true => fs::remove_dir(path)?,
+            false => fs::remove_file(path)?
+        }
+        Ok(())
+    }
+}
+
+// host imports, answered with WasmMembrane::write_response
+
+pub(crate) fn host_fs_open(env: &Env, path_buffer: i32, flags: i32) -> i32
+{
+    env.respond(|membrane| {
+        let path = membrane.consume_string(path_buffer)?;
+        let file = membrane.filesystem()?.open(path.as_str(), OpenOptions::from_flags(flags))?;
+        let handle = membrane.resources().insert(Mutex::new(file))?;
+        Ok(handle.to_le_bytes().to_vec())
+    })
+}
+
+/// reads at most MAX_HOST_ALLOCATION bytes, like any read it may return fewer than len
+pub(crate) fn host_fs_read(env: &Env, handle: i32, len: i32) -> i32
+{
+    env.respond(|membrane| {
+        let file = membrane.resources().get::<Mutex<Box<dyn VfsFile>>>(handle as u32)?;
+        let mut data = vec![0u8; (len.max(0) as usize).min(MAX_HOST_ALLOCATION)];
+        let read = file.lock()?.read(data.as_mut_slice())?;
+        data.truncate(read);
+        Ok(data)
+    })
+}
+
+pub(crate) fn host_fs_write(env: &Env, handle: i32, data_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let data = membrane.consume_buffer(data_buffer)?;
+        let file = membrane.resources().get::<Mutex<Box<dyn VfsFile>>>(handle as u32)?;
+        let written = file.lock()?.write(data.as_slice())?;
+        Ok((written as u32).to_le_bytes().to_vec())
+    })
+}
+
+pub(crate) fn host_fs_list(env: &Env, path_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let path = membrane.consume_string(path_buffer)?;
+        let entries = membrane.filesystem()?.list(path.as_str())?;
+        Ok(serde_json::to_vec(&entries).map_err(|e| format!("{}", e))?)
+    })
+}
+
+pub(crate) fn host_fs_stat(env: &Env, path_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let path = membrane.consume_string(path_buffer)?;
+        let metadata = membrane.filesystem()?.stat(path.as_str())?;
+        Ok(serde_json::to_vec(&metadata).map_err(|e| format!("{}", e))?)
+    })
+}
+
+pub(crate) fn host_fs_remove(env: &Env, path_buffer: i32) -> i32
+{
+    env.respond(|membrane| {
+        let path = membrane.consume_string(path_buffer)?;
+        membrane.filesystem()?.remove(path.as_str())?;
+        Ok(vec![])
+    })
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::vfs::{normalize, DirEntry, DirectoryVfs, MemoryVfs, OpenOptions, Vfs};
+
+    #[test]
+    pub fn test_normalize() -> Result<(), Error>
+    {
+        assert_eq!(normalize("/templates/./page.html")?, "templates/page.html");
+        assert_eq!(normalize("a/b/../c")?, "a/c");
+        assert_eq!(normalize("/")?, "");
+        assert!(normalize("../etc/passwd").is_err());
+        assert!(normalize("a/../../b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_memory_vfs() -> Result<(), Error>
+    {
+        let vfs = MemoryVfs::new();
+        vfs.write_file("templates/page.html", b"<html>")?;
+
+        let mut file = vfs.open("out/page.html", OpenOptions { write: true, create: true, ..Default::default() })?;
+        file.write_all(b"rendered")?;
+        assert_eq!(vfs.read_file("out/page.html")?, b"rendered".to_vec());
+
+        let mut contents = String::new();
+        vfs.open("templates/page.html", OpenOptions { read: true, ..Default::default() })?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "<html>");
+
+        assert_eq!(vfs.list("/")?, vec![
+            DirEntry { name: "out".to_string(), is_dir: true },
+            DirEntry { name: "templates".to_string(), is_dir: true },
+        ]);
+        assert!(vfs.stat("templates")?.is_dir);
+        assert_eq!(vfs.stat("out/page.html")?.len, 8);
+
+        vfs.remove("out/page.html")?;
+        assert!(vfs.stat("out").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_guest_filesystem() -> Result<(), Error>
+    {
+        let membrane = WasmMembrane::from_file("../../../guest/rust/wasm_membrane_guest_example/pkg/wasm_membrane_bg.wasm")?;
+        assert!(membrane.emit("render", b"").is_err());
+
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.write_file("templates/page.html", b"<h1>hello</h1>")?;
+        membrane.grant_filesystem(Option::Some(vfs.clone()))?;
+        membrane.emit("render", b"")?;
+        assert_eq!(vfs.read_file("out/page.html")?, b"<H1>HELLO</H1>".to_vec());
+        assert!(membrane.resources().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_directory_vfs() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(format!("wasm_membrane_vfs_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root"))?;
+        std::fs::write(dir.join("secret.txt"), b"outside")?;
+
+        let vfs = DirectoryVfs::new(dir.join("root"))?;
+        vfs.open("hello.txt", OpenOptions { write: true, create: true, ..Default::default() })?.write_all(b"hi")?;
+        assert_eq!(vfs.stat("hello.txt")?.len, 2);
+        assert!(vfs.open("../secret.txt", OpenOptions { read: true, ..Default::default() }).is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("root/link.txt"))?;
+            assert!(vfs.open("link.txt", OpenOptions { read: true, ..Default::default() }).is_err());
+
+            // a dangling symlink would otherwise let create make a file outside the root
+            std::os::unix::fs::symlink(dir.join("planted.txt"), dir.join("root/dangling.txt"))?;
+            assert!(vfs.open("dangling.txt", OpenOptions { write: true, create: true, ..Default::default() }).is_err());
+            assert!(!dir.join("planted.txt").exists());
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use wasmer::{imports, Array, Function, ImportObject, Module, WasmPtr};
+use wasmer_wasi::{generate_import_object_from_env, get_wasi_version, WasiFile, WasiFsError, WasiState, WasiVersion};
+
+use crate::error::Error;
+use crate::membrane::{Env, MAX_HOST_ALLOCATION};
+
+/// a host directory the guest sees at guest_path
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreopenedDir {
+    pub host_path: PathBuf,
+    pub guest_path: String,
+    /// when false the guest may only read
+    pub writable: bool,
+}
+
+/// what a guest created with WasmMembrane::new_with_wasi sees of the process it runs in.
+/// a guest gets no directories, env vars or args beyond the ones listed here
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasiConfig {
+    /// args[0] of the guest
+    pub program_name: String,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+    pub preopened_dirs: Vec<PreopenedDir>,
+    /// send each line the guest writes to stdout to the membrane's log sink as "stdout"
+    /// instead of the host process's stdout
+    pub capture_stdout: bool,
+    /// the same for stderr, logged as "stderr"
+    pub capture_stderr: bool,
+}
+
+impl Default for WasiConfig {
+    fn default() -> Self {
+        WasiConfig {
+            program_name: "membrane".to_string(),
+            args: vec![],
+            envs: vec![],
+            preopened_dirs: vec![],
+            capture_stdout: true,
+            capture_stderr: true,
+        }
+    }
+}
+
+impl WasiConfig {
+    pub fn new(program_name: &str) -> Self
+    {
+        WasiConfig {
+            program_name: program_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self
+    {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self
+    {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn preopen_dir<P: Into<PathBuf>>(mut self, host_path: P, guest_path: &str, writable: bool) -> Self
+    {
+        self.preopened_dirs.push(PreopenedDir {
+            host_path: host_path.into(),
+            guest_path: guest_path.to_string(),
+            writable,
+        });
+        self
+    }
+}
+
+pub(crate) fn import_object(module: &Module, config: &WasiConfig, env: Env) -> Result<ImportObject, Error>
+{
+    let mut builder = WasiState::new(config.program_name.as_str());
+    builder.args(config.args.iter()).envs(config.envs.iter().cloned());
+    for dir in &config.preopened_dirs
+    {
+        builder.preopen(|preopen| {
+            preopen.directory(&dir.host_path).alias(dir.guest_path.as_str()).read(true).write(dir.writable).create(dir.writable)
+        }).map_err(|e| format!("could not preopen {}: {}", dir.host_path.display(), e))?;
+    }
+    if config.capture_stdout
+    {
+        builder.stdout(Box::new(LogPipe::new(env.clone(), "stdout")));
+    }
+    if config.capture_stderr
+    {
+        builder.stderr(Box::new(LogPipe::new(env, "stderr")));
+    }
+    let wasi_env = builder.finalize().map_err(|e| format!("could not set up wasi: {}", e))?;
+
+    let version = get_wasi_version(module, false).unwrap_or(WasiVersion::Latest);
+    Ok(generate_import_object_from_env(module.store(), wasi_env, version))
+}
+
+static WASI_ESUCCESS: i32 = 0;
+static WASI_EFAULT: i32 = 21;
+
+/// clock_time_get and random_get backed by the membrane's deterministic sources, to be
+/// resolved before the regular WASI imports. every clock reads the virtual clock
+pub(crate) fn deterministic_import_object(module: &Module, env: Env) -> ImportObject
+{
+    let store = module.store();
+    imports! {
+        "wasi_snapshot_preview1" => {
+            "clock_time_get" => Function::new_native_with_env(store, env.clone(), deterministic_clock_time_get),
+            "random_get" => Function::new_native_with_env(store, env.clone(), deterministic_random_get),
+        },
+        "wasi_unstable" => {
+            "clock_time_get" => Function::new_native_with_env(store, env.clone(), deterministic_clock_time_get),
+            "random_get" => Function::new_native_with_env(store, env, deterministic_random_get),
+        },
+    }
+}
+
+fn deterministic_clock_time_get(env: &Env, _clock_id: i32, _precision: i64, time: WasmPtr<u64>) -> i32
+{
+    let result = (|| -> Result<(), Error> {
+        let membrane = env.unwrap()?;
+        let sources = membrane.deterministic_sources().ok_or("membrane is not deterministic")?;
+        let memory = membrane.instance.exports.get_memory("memory")?;
+        time.deref(memory).ok_or("time pointer is out of bounds")?.set(sources.clock.now());
+        Ok(())
+    })();
+    match result
+    {
+        Ok(_) => WASI_ESUCCESS,
+        Err(_) => WASI_EFAULT
+    }
+}
+
+/// the buffer is bounds checked up front and filled MAX_HOST_ALLOCATION bytes at a time
+fn deterministic_random_get(env: &Env, buf: WasmPtr<u8, Array>, len: u32) -> i32
+{
+    let result = (|| -> Result<(), Error> {
+        let membrane = env.unwrap()?;
+        let sources = membrane.deterministic_sources().ok_or("membrane is not deterministic")?;
+        let memory = membrane.instance.exports.get_memory("memory")?;
+        let cells = buf.deref(memory, 0, len).ok_or("random buffer is out of bounds")?;
+        let mut random = sources.random.lock()?;
+        for chunk in cells.chunks(MAX_HOST_ALLOCATION)
+        {
+            let mut bytes = vec![0u8; chunk.len()];
+            random.fill(bytes.as_mut_slice());
+            for (cell, byte) in chunk.iter().zip(bytes)
+            {
+                cell.set(byte);
+            }
+        }
+        Ok(())
+    })();
+    match result
+    {
+        Ok(_) => WASI_ESUCCESS,
+        Err(_) => WASI_EFAULT
+    }
+}
+
+/// a WASI stdout or stderr that hands every complete line to the membrane's log sink
+struct LogPipe {
+    env: Env,
+    log_type: &'static str,
+    line: Vec<u8>,
+}
+
+impl LogPipe {
+    fn new(env: Env, log_type: &'static str) -> Self
+    {
+        LogPipe {
+            env,
+            log_type,
+            line: vec![],
+        }
+    }
+
+    fn log(&mut self, line: &[u8])
+    {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if let Ok(membrane) = self.env.unwrap()
+        {
+            membrane.log(self.log_type, line);
+        }
+    }
+}
+
+impl fmt::Debug for LogPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LogPipe({})", self.log_type)
+    }
+}
+
+impl Write for LogPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|byte| *byte == b'\n')
+        {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.log(&line[..line.len() - 1]);
+        }
+        Ok(buf.len())
+    }
+
+    /// a partial line is only logged when the guest flushes
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty()
+        {
+            let line = std::mem::replace(&mut self.line, vec![]);
+            self.log(line.as_slice());
+        }
+        Ok(())
+    }
+}
+
+impl Read for LogPipe {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "can not read from a log pipe"))
+    }
+}
+
+impl Seek for LogPipe {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(io::ErrorKind::Other, "can not seek in a log pipe"))
+    }
+}
+
+impl WasiFile for LogPipe {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> Result<(), WasiFsError> {
+        Err(WasiFsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> Result<(), WasiFsError> {
+        Ok(())
+    }
+
+    fn bytes_available(&self) -> Result<usize, WasiFsError> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::sync::{Arc, Mutex};
+
+    use crate::engine::MembraneEngineConfig;
+    use crate::error::Error;
+    use crate::membrane::WasmMembrane;
+    use crate::wasi::WasiConfig;
+
+    #[test]
+    pub fn test_wasi() -> Result<(), Error>
+    {
+        let bytes = std::fs::read("../../../guest/rust/wasm_membrane_guest_example/target/wasm32-wasi/release/wasm_membrane.wasm")?;
+        let config = WasiConfig::new("example").arg("--verbose").env("GREETING", "hello");
+        let membrane = WasmMembrane::new_with_wasi(WasmMembrane::compile(bytes.as_slice())?, &MembraneEngineConfig::default(), &config)?;
+
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink_lines = lines.clone();
+        membrane.set_log_sink(Option::Some(Arc::new(move |_: &WasmMembrane, log_type: &str, message: &str| {
+            sink_lines.lock().unwrap().push(format!("{}: {}", log_type, message));
+        })))?;
+        membrane.init()?;
+
+        membrane.emit("print_env", b"GREETING")?;
+        membrane.emit("print_args", b"")?;
+        let lines = lines.lock()?;
+        assert!(lines.contains(&"stdout: hello".to_string()));
+        assert!(lines.contains(&"stdout: example --verbose".to_string()));
+        Ok(())
+    }
+}